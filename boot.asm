@@ -2,7 +2,7 @@ STACK_SZ equ 0x4000
 MAX_CPUS equ 16
 
 	;       Multiboot2 header
-	section .multiboot
+	section .multiboot progbits alloc noexec nowrite align=8
 
 mb_start:
 	dd 0xe85250d6; magic (multiboot2)
@@ -19,7 +19,10 @@ mb_start:
 
 mb_end:
 
-	section .data
+	; Everything in here runs (or is used) before paging is enabled, so it lives in the
+	; .boot.* sections, which the linker script keeps at their physical addresses. The rest of
+	; the kernel is linked into the higher half.
+	section .boot.data progbits alloc noexec write align=4
 
 no_multiboot:
 	db "The kernel was not booted by a multiboot-compatible loader.", 0
@@ -33,7 +36,7 @@ no_long_mode_err:
 failed_to_enter_long_mode:
 	db "Failed to enter long mode.", 0
 
-	section .rodata
+	section .boot.rodata progbits alloc noexec nowrite align=8
 
 gdt:
 	; temporary gdt for entering long mode
@@ -48,7 +51,7 @@ gdt:
 	dq gdt.data
 
 	[BITS   32]
-	section .text
+	section .boot.text progbits alloc exec nowrite align=16
 	;       The linker script specifies _start as the entry point to the kernel and the
 	;       bootloader will jump to this position once the kernel has been loaded. It
 	;       doesn't make sense to return from this function as the bootloader is gone.
@@ -188,6 +191,24 @@ setup_page_tables:
 	or  eax, 0b11; present, writable
 	mov [p4_table], eax
 
+	;   map the same p3 table at p4 entry 256 (0xffff800000000000), so that the
+	;   first GiB of physical memory can already be reached through the kernel's
+	;   direct map before Rust sets up its own page tables
+	mov eax, p3_table
+	or  eax, 0b11; present, writable
+	mov [p4_table + 256 * 8], eax
+
+	;   map the last p4 entry to the higher half p3 table, and its second to last
+	;   entry to the same p2 table, so that the first GiB of physical memory also
+	;   shows up at 0xffffffff80000000, which is where the kernel is linked
+	mov eax, p3_high_table
+	or  eax, 0b11; present, writable
+	mov [p4_table + 511 * 8], eax
+
+	mov eax, p2_table
+	or  eax, 0b11; present, writable
+	mov [p3_high_table + 510 * 8], eax
+
 	;   map first p3 entry to p2 table
 	mov eax, p2_table
 	or  eax, 0b11; present, writable
@@ -208,22 +229,14 @@ setup_page_tables:
 	ret
 
 enable_paging:
-	;   Zero out some memory
-	;   TODO: why did course say to do this?
-	;   This may be redundant bc I have already setup the
-	;   stack.
-	;   This has to do with PWT I think
-	mov edi, 0x80000
-	xor eax, eax
-	mov ecx, 0x4000                                       // 16384
-	rep stosd
-
-	;   TODO: note why we are doing this. I do not know yet.
-	;   Course said to do it and that it will be explained later.
-	mov dword [0x80000], 0x81007
-	mov dword [0x81000], 0b10000111
-
 	;   Load P4 table into CR3
+	;
+	;   CR3 holds the physical address of the top level page table (bits 12-51).
+	;   Bits 3 and 4 are flags:
+	;   3: Page-level write-through (PWT)
+	;   4: Page-level cache disable (PCD)
+	;   PWT and PCD are not used if bit 17 of cr4 (PCIDE) is set. (TODO: what is PCIDE?)
+	;   We want normal write-back caching for the page tables, so both stay clear.
 	mov eax, p4_table
 	mov cr3, eax
 
@@ -232,19 +245,6 @@ enable_paging:
 	or  eax, (1 << 5); Enable PAE
 	mov cr4, eax
 
-	; Enable page-lebel writethrough (PWT)
-
-	; CR3:
-	; The CR3 register is used for holding the base address of the page directory (thanks copilot?)
-
-	;   Bits 3 and 4 are flags:
-	;   3: Page-level write-through (PWT)
-	;   4: Page-level cache disable (PCD)
-	;   PWT and PCD are not used if bit 17 of cr4 (PCIDE) is set. (TODO: what is PCIDE?)
-	;   Bits 12-31 (or 63 in long mode) are the page directory base address (PDBR)
-	mov eax, 0x80000; 0x80000 = 1 << 19
-	mov cr3, eax
-
 	;   set the long mode bit in EFER MSR (model-specific register)
 	mov ecx, 0xC0000080
 	rdmsr
@@ -284,8 +284,7 @@ error:
 	hlt
 	jmp .err_end
 
-section .bss
-align   4096
+section .boot.bss nobits alloc noexec write align=4096
 
 p4_table:
 	resb 4096
@@ -293,6 +292,9 @@ p4_table:
 p3_table:
 	resb 4096
 
+p3_high_table:
+	resb 4096
+
 p2_table:
 	resb 4096
 
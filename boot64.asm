@@ -2,8 +2,12 @@
 
 	extern kernel_main
 	extern stack_top
+	extern KERNEL_STACK
 
-	section .text
+	; Must match KERNEL_STACK_SIZE in kernel/src/main.rs
+	KERNEL_STACK_SIZE equ 64 * 1024
+
+	section .boot.text progbits alloc exec nowrite align=16
 	global  long_mode_entry
 
 long_mode_entry:
@@ -17,8 +21,15 @@ long_mode_entry:
 
 	;    mov edi, ebx
 	mov  edi, [stack_top - 4]
-	;    mov rsp, 0x200000
-	call kernel_main
+
+	;   Switch to the kernel stack and jump to the kernel. Both live in the higher
+	;   half, out of reach of a relative call from down here, so go through rax.
+	mov rax, KERNEL_STACK + KERNEL_STACK_SIZE
+	and rax, -16
+	mov rsp, rax
+
+	mov  rax, kernel_main
+	call rax
 	hlt
 
 end:
@@ -9,6 +9,9 @@ const ASM: &[&str] = &[
     "boot.asm",
     "boot64.asm",
     "kernel/src/mem.asm",
+    "kernel/src/exception.asm",
+    "kernel/src/trampoline.asm",
+    "kernel/src/switch.asm",
 ];
 
 fn build_assembly_files(files: &[&str], root: &Path, out_dir: &Path) -> Vec<PathBuf> {
@@ -68,16 +71,20 @@ fn build_boot_dir(root: &Path, iso_dir: &Path) -> PathBuf {
     let grub_dir = boot_dir.as_path().join("grub");
     fs::create_dir_all(&grub_dir).expect("to create grub dir");
 
-    let grub_cfg = root.join("grub.cfg");
+    install_grub_cfg(root, &boot_dir, "grub.cfg");
+
+    boot_dir
+}
+
+fn install_grub_cfg(root: &Path, boot_dir: &Path, name: &str) {
+    let grub_cfg = root.join(name);
     println!("cargo:rerun-if-changed={}", grub_cfg.display());
-    let grub_cfg_target = grub_dir.join("grub.cfg");
+    let grub_cfg_target = boot_dir.join("grub").join("grub.cfg");
 
     if !grub_cfg.exists() {
-        panic!("grub.cfg not found");
+        panic!("{} not found", name);
     }
     fs::copy(grub_cfg, grub_cfg_target).expect("to copy grub.cfg");
-
-    boot_dir
 }
 
 fn build_kernel_elf(root: &Path, boot_dir: &Path, objects: Vec<PathBuf>) {
@@ -121,8 +128,12 @@ fn main() {
     let root = PathBuf::from(manifest);
 
     let final_iso = root.join("bruh_os.iso");
-    if final_iso.exists() {
-        fs::remove_file(&final_iso).expect("to remove old iso");
+    // The same kernel booted with nohz=on, for the tests.
+    let nohz_iso = root.join("bruh_os_nohz.iso");
+    for iso in [&final_iso, &nohz_iso] {
+        if iso.exists() {
+            fs::remove_file(iso).expect("to remove old iso");
+        }
     }
 
     let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
@@ -132,6 +143,9 @@ fn main() {
     let boot_dir = build_boot_dir(&root, &iso_dir);
     build_kernel_elf(&root, &boot_dir, objects);
     build_kernel_iso(&final_iso, &iso_dir);
+    install_grub_cfg(&root, &boot_dir, "grub-nohz.cfg");
+    build_kernel_iso(&nohz_iso, &iso_dir);
 
     println!("cargo:rerun-if-changed={}", final_iso.display());
+    println!("cargo:rerun-if-changed={}", nohz_iso.display());
 }
@@ -1,3 +1,6 @@
 fn main() {
     println!("cargo:rerun-if-changed=src/mem.asm");
+    println!("cargo:rerun-if-changed=src/exception.asm");
+    println!("cargo:rerun-if-changed=src/trampoline.asm");
+    println!("cargo:rerun-if-changed=src/switch.asm");
 }
@@ -0,0 +1,730 @@
+//! ACPI table discovery.
+//!
+//! The RSDP is taken from the copy the bootloader put in the multiboot info (the new, ACPI 2.0+
+//! tag if there is one), or else found by scanning the first KiB of the EBDA and the BIOS area
+//! at 0xe0000-0xfffff, the way real mode firmware leaves it. From there the XSDT (or the RSDT on
+//! ACPI 1.0 machines) lists every other table. Tables are only read, never written, through the
+//! direct map; every table's checksum is verified before it is used.
+//!
+//! The tables the rest of the kernel cares about are parsed into [`Madt`], [`Fadt`], [`Hpet`]
+//! and [`Mcfg`]. The interrupt controllers and NMI sources found in the MADT are handed to
+//! [`ioapic`] right away, and the local APICs look up their NMI pins in it as they are enabled,
+//! so [`init`] must run before [`apic::init`](crate::apic::init).
+
+use alloc::vec::Vec;
+use core::{fmt, mem::size_of};
+
+use spin::Once;
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
+
+use crate::{
+    boot_info,
+    ioapic::{self, IsaOverride, NmiSource, Polarity, TriggerMode},
+    paging, print, println,
+};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Size of the ACPI 1.0 part of the RSDP, which the first checksum covers.
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+const SDT_HEADER_SIZE: usize = 36;
+/// Far more than any real table, DSDTs included, so that a bad length is caught.
+const MAX_SDT_SIZE: usize = 4 << 20;
+
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+
+/// A table that passed its checksum, somewhere in physical memory.
+#[derive(Clone, Copy)]
+pub struct Sdt {
+    pub address: PhysAddr,
+    bytes: &'static [u8],
+}
+
+impl Sdt {
+    /// # Safety
+    ///
+    /// `address` must point to an ACPI table, or else to memory that is safe to read.
+    unsafe fn load(address: PhysAddr) -> Option<Self> {
+        if !paging::is_direct_mapped(address, SDT_HEADER_SIZE as u64) {
+            return None;
+        }
+        let header = paging::phys_to_virt(address).as_ptr::<u8>();
+        let length = (header.add(4) as *const u32).read_unaligned() as usize;
+        if !(SDT_HEADER_SIZE..=MAX_SDT_SIZE).contains(&length)
+            || !paging::is_direct_mapped(address, length as u64)
+        {
+            return None;
+        }
+        let bytes = core::slice::from_raw_parts(header, length);
+        checksum_ok(bytes).then_some(Self { address, bytes })
+    }
+
+    pub fn signature(&self) -> [u8; 4] {
+        self.bytes[..4].try_into().unwrap()
+    }
+
+    /// The table's contents after the header.
+    pub fn data(&self) -> &'static [u8] {
+        &self.bytes[SDT_HEADER_SIZE..]
+    }
+
+    fn u8(&self, offset: usize) -> Option<u8> {
+        self.bytes.get(offset).copied()
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        read_le(self.bytes, offset).map(u16::from_le_bytes)
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        read_le(self.bytes, offset).map(u32::from_le_bytes)
+    }
+
+    fn u64(&self, offset: usize) -> Option<u64> {
+        read_le(self.bytes, offset).map(u64::from_le_bytes)
+    }
+}
+
+impl fmt::Debug for Sdt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sdt")
+            .field("signature", &Signature(self.signature()))
+            .field("address", &self.address)
+            .field("length", &self.bytes.len())
+            .finish()
+    }
+}
+
+struct Signature([u8; 4]);
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", core::str::from_utf8(&self.0).unwrap_or("????"))
+    }
+}
+
+fn read_le<const N: usize>(bytes: &[u8], offset: usize) -> Option<[u8; N]> {
+    bytes.get(offset..offset + N)?.try_into().ok()
+}
+
+pub fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// ACPI generic address structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+    /// Where a [`AddressSpace::SystemMemory`] register is mapped, once [`Self::mapped`] has
+    /// been called.
+    virt: Option<VirtAddr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+impl GenericAddress {
+    fn parse(table: &Sdt, offset: usize) -> Option<Self> {
+        let address = table.u64(offset + 4)?;
+        if address == 0 {
+            return None;
+        }
+        Some(Self {
+            space: match table.u8(offset)? {
+                0 => AddressSpace::SystemMemory,
+                1 => AddressSpace::SystemIo,
+                2 => AddressSpace::PciConfig,
+                other => AddressSpace::Other(other),
+            },
+            bit_width: table.u8(offset + 1)?,
+            bit_offset: table.u8(offset + 2)?,
+            access_size: table.u8(offset + 3)?,
+            address,
+            virt: None,
+        })
+    }
+
+    /// Maps the register if it is in memory, so that [`Self::read`] and [`Self::write`] can
+    /// reach it.
+    fn mapped(self) -> Self {
+        let virt = (self.space == AddressSpace::SystemMemory)
+            .then(|| paging::map_mmio(PhysAddr::new(self.address), self.width() as u64 / 8));
+        Self { virt, ..self }
+    }
+
+    fn io(port: u32, bit_width: u8) -> Option<Self> {
+        (port != 0).then_some(Self {
+            space: AddressSpace::SystemIo,
+            bit_width,
+            bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+            virt: None,
+        })
+    }
+
+    /// Width of an access in bits. Tables from before ACPI 3.0 leave `access_size` zero.
+    fn width(&self) -> u8 {
+        match (self.access_size, self.bit_width) {
+            (1..=4, _) => 8 << (self.access_size - 1),
+            (_, 16 | 32 | 64) => self.bit_width,
+            _ => 8,
+        }
+    }
+
+    /// Reads the register, if it is one we can access.
+    ///
+    /// # Safety
+    ///
+    /// Reading hardware registers can have side effects.
+    pub unsafe fn read(&self) -> Option<u64> {
+        let width = self.width();
+        match self.space {
+            AddressSpace::SystemIo => {
+                let port = self.address as u16;
+                Some(match width {
+                    8 => Port::<u8>::new(port).read() as u64,
+                    16 => Port::<u16>::new(port).read() as u64,
+                    _ => Port::<u32>::new(port).read() as u64,
+                })
+            }
+            AddressSpace::SystemMemory => {
+                let virt = self.virt?;
+                Some(match width {
+                    8 => virt.as_ptr::<u8>().read_volatile() as u64,
+                    16 => virt.as_ptr::<u16>().read_volatile() as u64,
+                    32 => virt.as_ptr::<u32>().read_volatile() as u64,
+                    _ => virt.as_ptr::<u64>().read_volatile(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes the register. Returns false for address spaces we can't access.
+    ///
+    /// # Safety
+    ///
+    /// Writing hardware registers can have arbitrary side effects.
+    pub unsafe fn write(&self, value: u64) -> bool {
+        let width = self.width();
+        match self.space {
+            AddressSpace::SystemIo => {
+                let port = self.address as u16;
+                match width {
+                    8 => Port::<u8>::new(port).write(value as u8),
+                    16 => Port::<u16>::new(port).write(value as u16),
+                    _ => Port::<u32>::new(port).write(value as u32),
+                }
+            }
+            AddressSpace::SystemMemory => {
+                let Some(virt) = self.virt else {
+                    return false;
+                };
+                match width {
+                    8 => virt.as_mut_ptr::<u8>().write_volatile(value as u8),
+                    16 => virt.as_mut_ptr::<u16>().write_volatile(value as u16),
+                    32 => virt.as_mut_ptr::<u32>().write_volatile(value as u32),
+                    _ => virt.as_mut_ptr::<u64>().write_volatile(value),
+                }
+            }
+            AddressSpace::PciConfig => {
+                // Device on bus 0: device in bits 32-47, function in 16-31, offset in 0-15.
+                let device = (self.address >> 32) as u32 & 0x1f;
+                let function = (self.address >> 16) as u32 & 0x7;
+                let offset = self.address as u32 & 0xff;
+                let config = 1 << 31 | device << 11 | function << 8 | (offset & !3);
+                Port::<u32>::new(PCI_CONFIG_ADDRESS).write(config);
+                // Only byte accesses; this is used for the reset register, which is 8 bits.
+                Port::<u8>::new(PCI_CONFIG_DATA + (offset & 3) as u16).write(value as u8);
+            }
+            AddressSpace::Other(_) => return false,
+        }
+        true
+    }
+}
+
+/// A processor's local APIC, from the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    /// ACPI processor UID.
+    pub uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    /// Disabled, but can be brought online later.
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// A local APIC LINT pin wired to NMI. The local APIC always takes NMIs as edge triggered, so
+/// the trigger mode in the MADT entry is left out.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// ACPI processor UID, `None` for all processors.
+    pub uid: Option<u32>,
+    pub lint: u8,
+    pub polarity: Polarity,
+}
+
+/// Multiple APIC description table.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub processors: Vec<Processor>,
+    pub ioapics: Vec<IoApicEntry>,
+    pub isa_overrides: Vec<IsaOverride>,
+    pub nmi_sources: Vec<NmiSource>,
+    pub local_nmis: Vec<LocalApicNmi>,
+}
+
+/// Decodes MPS INTI flags, where "conforms to the bus" means ISA: active high, edge triggered.
+fn inti_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::Edge,
+    };
+    (polarity, trigger)
+}
+
+impl Madt {
+    fn parse(table: &Sdt) -> Option<Self> {
+        let mut madt = Self {
+            local_apic_address: PhysAddr::new(table.u32(36)? as u64),
+            processors: Vec::new(),
+            ioapics: Vec::new(),
+            isa_overrides: Vec::new(),
+            nmi_sources: Vec::new(),
+            local_nmis: Vec::new(),
+        };
+
+        let mut offset = 44;
+        while offset + 2 <= table.bytes.len() {
+            let kind = table.u8(offset)?;
+            let length = table.u8(offset + 1)? as usize;
+            if length < 2 {
+                break;
+            }
+            let entry = offset;
+            offset += length;
+
+            match kind {
+                0 => {
+                    let flags = table.u32(entry + 4)?;
+                    madt.processors.push(Processor {
+                        uid: table.u8(entry + 2)? as u32,
+                        apic_id: table.u8(entry + 3)? as u32,
+                        enabled: flags & 1 != 0,
+                        online_capable: flags & 2 != 0,
+                    });
+                }
+                1 => madt.ioapics.push(IoApicEntry {
+                    id: table.u8(entry + 2)?,
+                    address: PhysAddr::new(table.u32(entry + 4)? as u64),
+                    gsi_base: table.u32(entry + 8)?,
+                }),
+                2 => {
+                    let (polarity, trigger) = inti_flags(table.u16(entry + 8)?);
+                    madt.isa_overrides.push(IsaOverride {
+                        irq: table.u8(entry + 3)?,
+                        gsi: table.u32(entry + 4)?,
+                        polarity,
+                        trigger,
+                    });
+                }
+                3 => {
+                    let (polarity, _) = inti_flags(table.u16(entry + 2)?);
+                    madt.nmi_sources.push(NmiSource {
+                        gsi: table.u32(entry + 4)?,
+                        polarity,
+                    });
+                }
+                4 => {
+                    let uid = table.u8(entry + 2)?;
+                    let (polarity, _) = inti_flags(table.u16(entry + 3)?);
+                    madt.local_nmis.push(LocalApicNmi {
+                        uid: (uid != 0xff).then_some(uid as u32),
+                        lint: table.u8(entry + 5)?,
+                        polarity,
+                    });
+                }
+                5 => madt.local_apic_address = PhysAddr::new(table.u64(entry + 4)?),
+                9 => {
+                    let flags = table.u32(entry + 8)?;
+                    madt.processors.push(Processor {
+                        uid: table.u32(entry + 12)?,
+                        apic_id: table.u32(entry + 4)?,
+                        enabled: flags & 1 != 0,
+                        online_capable: flags & 2 != 0,
+                    });
+                }
+                10 => {
+                    let uid = table.u32(entry + 4)?;
+                    let (polarity, _) = inti_flags(table.u16(entry + 2)?);
+                    madt.local_nmis.push(LocalApicNmi {
+                        uid: (uid != u32::MAX).then_some(uid),
+                        lint: table.u8(entry + 8)?,
+                        polarity,
+                    });
+                }
+                _ => {}
+            }
+        }
+        Some(madt)
+    }
+
+    /// Processors that are (or can be brought) online.
+    pub fn usable_processors(&self) -> impl Iterator<Item = &Processor> {
+        self.processors
+            .iter()
+            .filter(|cpu| cpu.enabled || cpu.online_capable)
+    }
+
+    /// The LINT pins wired to NMI on the processor with local APIC ID `apic_id`.
+    pub fn local_nmis(&self, apic_id: u32) -> impl Iterator<Item = &LocalApicNmi> {
+        let uid = self
+            .processors
+            .iter()
+            .find(|cpu| cpu.apic_id == apic_id)
+            .map(|cpu| cpu.uid);
+        self.local_nmis
+            .iter()
+            .filter(move |nmi| nmi.uid.is_none() || nmi.uid == uid)
+    }
+}
+
+/// Fixed ACPI description table. Only the fields we have a use for.
+#[derive(Debug, Clone)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub pm1a_control: Option<GenericAddress>,
+    pub pm1b_control: Option<GenericAddress>,
+    /// RTC CMOS index of the century, if there is one.
+    pub century: u8,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// `flags`: the reset register is supported.
+    pub const RESET_REG_SUP: u32 = 1 << 10;
+
+    fn parse(table: &Sdt) -> Option<Self> {
+        // The 64-bit X_ fields take precedence over the old 32-bit ones when they are there,
+        // which they aren't in ACPI 1.0 tables.
+        let dsdt = table
+            .u64(140)
+            .filter(|&addr| addr != 0)
+            .unwrap_or(table.u32(40)? as u64);
+        let pm1_control_len = table.u8(89)? * 8;
+        let x_or = |offset: usize, legacy: Option<GenericAddress>| {
+            GenericAddress::parse(table, offset)
+                .or(legacy)
+                .map(GenericAddress::mapped)
+        };
+
+        Some(Self {
+            dsdt: PhysAddr::new(dsdt),
+            smi_command_port: table.u32(48)?,
+            acpi_enable: table.u8(52)?,
+            pm1a_control: x_or(172, GenericAddress::io(table.u32(64)?, pm1_control_len)),
+            pm1b_control: x_or(184, GenericAddress::io(table.u32(68)?, pm1_control_len)),
+            century: table.u8(108)?,
+            flags: table.u32(112).unwrap_or(0),
+            reset_register: GenericAddress::parse(table, 116).map(GenericAddress::mapped),
+            reset_value: table.u8(128).unwrap_or(0),
+        })
+    }
+}
+
+/// HPET description table.
+#[derive(Debug, Clone)]
+pub struct Hpet {
+    /// The register block, which the hpet module maps itself.
+    pub base: GenericAddress,
+}
+
+impl Hpet {
+    fn parse(table: &Sdt) -> Option<Self> {
+        Some(Self {
+            base: GenericAddress::parse(table, 40)?,
+        })
+    }
+}
+
+/// A PCI Express enhanced configuration space, from the MCFG.
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+impl Mcfg {
+    fn parse(table: &Sdt) -> Option<Self> {
+        const ENTRY_SIZE: usize = 16;
+        let mut entries = Vec::new();
+        let mut offset = 44;
+        while offset + ENTRY_SIZE <= table.bytes.len() {
+            entries.push(McfgEntry {
+                base: PhysAddr::new(table.u64(offset)?),
+                segment: table.u16(offset + 8)?,
+                start_bus: table.u8(offset + 10)?,
+                end_bus: table.u8(offset + 11)?,
+            });
+            offset += ENTRY_SIZE;
+        }
+        Some(Self { entries })
+    }
+}
+
+/// Everything [`init`] found.
+#[derive(Debug)]
+pub struct Acpi {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub tables: Vec<Sdt>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+}
+
+impl Acpi {
+    /// The DSDT, which isn't listed in the RSDT/XSDT but pointed to by the FADT.
+    pub fn dsdt(&self) -> Option<Sdt> {
+        let dsdt = unsafe { Sdt::load(self.fadt.as_ref()?.dsdt)? };
+        (&dsdt.signature() == b"DSDT").then_some(dsdt)
+    }
+}
+
+/// The parts of the RSDP we need.
+struct Rsdp {
+    revision: u8,
+    oem_id: [u8; 6],
+    rsdt: u32,
+    xsdt: Option<u64>,
+}
+
+/// Parses the RSDP at `ptr`, checking its signature and checksums.
+///
+/// # Safety
+///
+/// `ptr` must be readable for [`RSDP_V2_SIZE`] bytes, or [`RSDP_V1_SIZE`] if it is a 1.0 RSDP.
+unsafe fn parse_rsdp(ptr: *const u8) -> Option<Rsdp> {
+    let v1 = core::slice::from_raw_parts(ptr, RSDP_V1_SIZE);
+    if &v1[..8] != RSDP_SIGNATURE || !checksum_ok(v1) {
+        return None;
+    }
+    let revision = v1[15];
+    let rsdt = u32::from_le_bytes(read_le(v1, 16)?);
+    let mut xsdt = None;
+    if revision >= 2 {
+        let v2 = core::slice::from_raw_parts(ptr, RSDP_V2_SIZE);
+        let length = u32::from_le_bytes(read_le(v2, 20)?) as usize;
+        let extended = core::slice::from_raw_parts(ptr, length.max(RSDP_V2_SIZE));
+        if checksum_ok(extended) {
+            xsdt = Some(u64::from_le_bytes(read_le(v2, 24)?)).filter(|&addr| addr != 0);
+        }
+    }
+    Some(Rsdp {
+        revision,
+        oem_id: v1[9..15].try_into().unwrap(),
+        rsdt,
+        xsdt,
+    })
+}
+
+/// The RSDP copy in the multiboot info. The RSDP itself starts right after the tag header.
+fn rsdp_from_multiboot() -> Option<Rsdp> {
+    let info = boot_info::MULTIBOOT_INFO.get()?;
+    const TAG_HEADER_SIZE: usize = 8;
+    let tag = info
+        .rsdp_v2_tag()
+        .map(|tag| tag as *const _ as *const u8)
+        .or_else(|| info.rsdp_v1_tag().map(|tag| tag as *const _ as *const u8))?;
+    unsafe { parse_rsdp(tag.add(TAG_HEADER_SIZE)) }
+}
+
+/// Looks for the RSDP on a 16 byte boundary in `range`.
+fn scan_for_rsdp(range: core::ops::Range<u64>) -> Option<Rsdp> {
+    range
+        .step_by(16)
+        .find_map(|addr| unsafe { parse_rsdp(paging::phys_to_virt(PhysAddr::new(addr)).as_ptr()) })
+}
+
+fn find_rsdp() -> Option<Rsdp> {
+    rsdp_from_multiboot().or_else(|| {
+        // The real mode segment of the EBDA is kept in the BIOS data area.
+        let ebda_segment =
+            unsafe { *paging::phys_to_virt(PhysAddr::new(0x40e)).as_ptr::<u16>() } as u64;
+        let ebda = ebda_segment << 4;
+        let in_ebda = (0x80000..0xa0000)
+            .contains(&ebda)
+            .then(|| scan_for_rsdp(ebda..ebda + 1024))
+            .flatten();
+        in_ebda.or_else(|| scan_for_rsdp(0xe0000..0x100000))
+    })
+}
+
+/// Loads the tables listed in the RSDT (32-bit entries) or XSDT (64-bit entries) at `address`.
+fn load_tables(address: PhysAddr, entry_size: usize) -> Vec<Sdt> {
+    let Some(root) = (unsafe { Sdt::load(address) }) else {
+        println!("ACPI: root table at {:#x} is invalid.", address);
+        return Vec::new();
+    };
+    root.data()
+        .chunks_exact(entry_size)
+        .filter_map(|entry| {
+            let addr = match entry_size {
+                4 => u32::from_le_bytes(entry.try_into().unwrap()) as u64,
+                _ => u64::from_le_bytes(entry.try_into().unwrap()),
+            };
+            let table = unsafe { Sdt::load(PhysAddr::new(addr)) };
+            if table.is_none() {
+                println!("ACPI: skipping invalid table at {:#x}.", addr);
+            }
+            table
+        })
+        .collect()
+}
+
+static ACPI: Once<Acpi> = Once::new();
+
+/// The ACPI tables, if [`init`] found any.
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.get()
+}
+
+/// Finds and parses the ACPI tables. Must run after [`heap::init`](crate::heap::init).
+pub fn init() {
+    let Some(rsdp) = find_rsdp() else {
+        println!("ACPI: no RSDP found.");
+        return;
+    };
+    let tables = match rsdp.xsdt {
+        Some(xsdt) => load_tables(PhysAddr::new(xsdt), size_of::<u64>()),
+        None => load_tables(PhysAddr::new(rsdp.rsdt as u64), size_of::<u32>()),
+    };
+
+    let find = |signature: &[u8; 4]| tables.iter().find(|table| &table.signature() == signature);
+    let acpi = Acpi {
+        revision: rsdp.revision,
+        oem_id: rsdp.oem_id,
+        madt: find(b"APIC").and_then(Madt::parse),
+        fadt: find(b"FACP").and_then(Fadt::parse),
+        hpet: find(b"HPET").and_then(Hpet::parse),
+        mcfg: find(b"MCFG").and_then(Mcfg::parse),
+        tables,
+    };
+
+    print!(
+        "ACPI {} ({}):",
+        if acpi.revision >= 2 { "2.0+" } else { "1.0" },
+        core::str::from_utf8(&acpi.oem_id).unwrap_or("?").trim_end()
+    );
+    for table in &acpi.tables {
+        print!(" {:?}", Signature(table.signature()));
+    }
+    println!();
+
+    if let Some(madt) = &acpi.madt {
+        for entry in &madt.ioapics {
+            ioapic::add(entry.id, entry.address, entry.gsi_base);
+        }
+        for isa in &madt.isa_overrides {
+            ioapic::add_isa_override(*isa);
+        }
+        for nmi in &madt.nmi_sources {
+            ioapic::add_nmi_source(*nmi);
+        }
+        println!(
+            "ACPI: {} usable CPUs, {} I/O APICs.",
+            madt.usable_processors().count(),
+            madt.ioapics.len()
+        );
+    }
+    for entry in acpi.mcfg.iter().flat_map(|mcfg| &mcfg.entries) {
+        println!(
+            "ACPI: PCI Express configuration space for segment {}, buses {}-{}, at {:#x}.",
+            entry.segment, entry.start_bus, entry.end_bus, entry.base
+        );
+    }
+
+    ACPI.call_once(|| acpi);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apic;
+
+    #[test_case]
+    fn checksums() {
+        assert!(checksum_ok(&[0x10, 0xf0]));
+        assert!(checksum_ok(&[]));
+        assert!(!checksum_ok(&[0x10, 0xef]));
+    }
+
+    #[test_case]
+    fn madt_lists_the_boot_cpu() {
+        let Some(madt) = get().and_then(|acpi| acpi.madt.as_ref()) else {
+            return;
+        };
+        let id = apic::id();
+        assert!(madt
+            .usable_processors()
+            .any(|cpu| cpu.apic_id == id && cpu.enabled));
+    }
+
+    #[test_case]
+    fn madt_wires_lint1_to_nmi() {
+        let Some(madt) = get().and_then(|acpi| acpi.madt.as_ref()) else {
+            return;
+        };
+        assert!(madt.local_nmis(apic::id()).any(|nmi| nmi.lint == 1));
+    }
+
+    #[test_case]
+    fn tables_outside_the_direct_map_are_refused() {
+        assert!(unsafe { Sdt::load(PhysAddr::new(1 << 50)) }.is_none());
+    }
+
+    #[test_case]
+    fn fadt_points_to_dsdt() {
+        let Some(acpi) = get() else {
+            return;
+        };
+        if acpi.fadt.is_some() {
+            assert!(acpi.dsdt().is_some());
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! Local APIC.
+//!
+//! Every CPU has a local APIC that receives interrupts (from the I/O APIC, other CPUs and its
+//! own timer) and delivers them to the CPU. It is programmed either through a page of MMIO
+//! registers (xAPIC) or, if the CPU supports it, through MSRs (x2APIC), where register `offset`
+//! of the MMIO page is MSR `0x800 + offset / 16`.
+//!
+//! When there is a local APIC, [`init`] also brings up the I/O APIC and moves IRQ delivery over
+//! to it, disabling the 8259 PICs. Without one (QEMU's `-machine isapc`, for example), the
+//! PICs stay in charge.
+//!
+//! Each local APIC's timer is driven by [`timer`].
+
+pub mod timer;
+
+use core::{arch::x86_64::__cpuid, fmt};
+
+use spin::Once;
+use x86_64::{
+    registers::model_specific::Msr, structures::idt::InterruptStackFrame, PhysAddr, VirtAddr,
+};
+
+use crate::{
+    acpi, idt,
+    ioapic::{self, Polarity},
+    irq, paging, println,
+};
+
+/// Vector the local APIC uses for spurious interrupts. The low four bits must be set on older
+/// APICs.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// Vector for errors detected by the local APIC, see [`ERROR_STATUS`].
+pub const ERROR_VECTOR: u8 = 0xfe;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Register offsets in the xAPIC MMIO page.
+pub const ID: u32 = 0x20;
+pub const VERSION: u32 = 0x30;
+pub const TASK_PRIORITY: u32 = 0x80;
+pub const EOI: u32 = 0xb0;
+pub const SPURIOUS: u32 = 0xf0;
+pub const ERROR_STATUS: u32 = 0x280;
+pub const ICR_LOW: u32 = 0x300;
+pub const ICR_HIGH: u32 = 0x310;
+pub const LVT_TIMER: u32 = 0x320;
+pub const LVT_LINT0: u32 = 0x350;
+pub const LVT_LINT1: u32 = 0x360;
+pub const LVT_ERROR: u32 = 0x370;
+pub const TIMER_INITIAL_COUNT: u32 = 0x380;
+pub const TIMER_CURRENT_COUNT: u32 = 0x390;
+pub const TIMER_DIVIDE: u32 = 0x3e0;
+
+/// Divide configuration value for dividing the bus clock by 16, which is what
+/// [`timer`] is calibrated for.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+pub const LVT_MASKED: u32 = 1 << 16;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_DELIVERY_EXTINT: u32 = 0b111 << 8;
+
+// Interrupt command register bits.
+pub const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+pub const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+pub const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+pub const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+pub const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+pub const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Registers are memory mapped at the given address.
+    XApic(VirtAddr),
+    /// Registers are MSRs.
+    X2Apic,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::XApic(_) => write!(f, "xAPIC"),
+            Mode::X2Apic => write!(f, "x2APIC"),
+        }
+    }
+}
+
+pub struct LocalApic {
+    mode: Mode,
+}
+
+impl LocalApic {
+    #[cfg(test)]
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn read(&self, reg: u32) -> u32 {
+        match self.mode {
+            Mode::XApic(base) => unsafe { (base + reg as u64).as_ptr::<u32>().read_volatile() },
+            Mode::X2Apic => unsafe { Msr::new(0x800 + (reg >> 4)).read() as u32 },
+        }
+    }
+
+    pub fn write(&self, reg: u32, value: u32) {
+        match self.mode {
+            Mode::XApic(base) => unsafe {
+                (base + reg as u64)
+                    .as_mut_ptr::<u32>()
+                    .write_volatile(value)
+            },
+            Mode::X2Apic => unsafe { Msr::new(0x800 + (reg >> 4)).write(value as u64) },
+        }
+    }
+
+    /// APIC ID of the current CPU.
+    pub fn id(&self) -> u32 {
+        match self.mode {
+            Mode::XApic(_) => self.read(ID) >> 24,
+            Mode::X2Apic => self.read(ID),
+        }
+    }
+
+    pub fn eoi(&self) {
+        self.write(EOI, 0);
+    }
+
+    /// Writes the interrupt command register, which sends an IPI, and waits until the IPI has
+    /// been accepted. `destination` is an APIC ID.
+    pub fn write_icr(&self, destination: u32, command: u32) {
+        match self.mode {
+            Mode::XApic(_) => {
+                // Writing the low half is what sends the IPI.
+                self.write(ICR_HIGH, destination << 24);
+                self.write(ICR_LOW, command);
+                while self.read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                    core::hint::spin_loop();
+                }
+            }
+            Mode::X2Apic => unsafe {
+                Msr::new(0x830).write((destination as u64) << 32 | command as u64);
+            },
+        }
+    }
+
+    /// Sends interrupt `vector` to the CPU with APIC ID `destination`.
+    pub fn send_ipi(&self, destination: u32, vector: u8) {
+        self.write_icr(
+            destination,
+            ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | vector as u32,
+        );
+    }
+
+    /// Sends interrupt `vector` to every CPU but this one.
+    pub fn broadcast_ipi(&self, vector: u8) {
+        self.write_icr(
+            0,
+            ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | ICR_ALL_EXCLUDING_SELF | vector as u32,
+        );
+    }
+
+    /// Sends an NMI to the CPU with APIC ID `destination`.
+    pub fn send_nmi(&self, destination: u32) {
+        self.write_icr(destination, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT);
+    }
+
+    /// Reads and clears the error status register.
+    pub fn error_status(&self) -> u32 {
+        // The register only latches new errors on a write.
+        self.write(ERROR_STATUS, 0);
+        self.read(ERROR_STATUS)
+    }
+
+    /// Sets up the local APIC of the current CPU. Every CPU has to call this for its own APIC.
+    pub fn enable(&self) {
+        let mut base = Msr::new(IA32_APIC_BASE);
+        unsafe {
+            let mut value = base.read() | APIC_BASE_GLOBAL_ENABLE;
+            if self.mode == Mode::X2Apic {
+                value |= APIC_BASE_X2APIC_ENABLE;
+            }
+            base.write(value);
+        }
+
+        // Accept all interrupts.
+        self.write(TASK_PRIORITY, 0);
+        // LINT0 is where the PIC is wired in virtual wire mode, LINT1 is the NMI line unless the
+        // MADT says otherwise.
+        self.write(LVT_LINT0, LVT_MASKED);
+        self.write(LVT_LINT1, LVT_DELIVERY_NMI);
+        let madt = acpi::get().and_then(|acpi| acpi.madt.as_ref());
+        let mut nmis = madt
+            .into_iter()
+            .flat_map(|madt| madt.local_nmis(self.id()))
+            .peekable();
+        if nmis.peek().is_some() {
+            self.write(LVT_LINT1, LVT_MASKED);
+        }
+        for nmi in nmis {
+            let lvt = match nmi.lint {
+                0 => LVT_LINT0,
+                1 => LVT_LINT1,
+                _ => continue,
+            };
+            let polarity = match nmi.polarity {
+                Polarity::ActiveHigh => 0,
+                Polarity::ActiveLow => LVT_ACTIVE_LOW,
+            };
+            self.write(lvt, LVT_DELIVERY_NMI | polarity);
+        }
+        self.write(LVT_TIMER, LVT_MASKED);
+        // It resets to dividing by 2, and every CPU's timer must run at the calibrated rate.
+        self.write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(LVT_ERROR, ERROR_VECTOR as u32);
+        self.error_status();
+        self.write(SPURIOUS, SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+        // In case something was left in service.
+        self.eoi();
+    }
+}
+
+static LAPIC: Once<LocalApic> = Once::new();
+
+/// The local APIC, once [`init`] found one.
+pub fn local() -> Option<&'static LocalApic> {
+    LAPIC.get()
+}
+
+/// Sends an EOI to the local APIC of the current CPU.
+pub fn eoi() {
+    if let Some(lapic) = local() {
+        lapic.eoi();
+    }
+}
+
+/// APIC ID of the current CPU, or 0 without an APIC.
+pub fn id() -> u32 {
+    local().map_or(0, LocalApic::id)
+}
+
+fn detect() -> Option<Mode> {
+    let leaf = __cpuid(1);
+    if leaf.edx & (1 << 9) == 0 {
+        return None;
+    }
+    if leaf.ecx & (1 << 21) != 0 {
+        return Some(Mode::X2Apic);
+    }
+
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() } & APIC_BASE_ADDR_MASK;
+    Some(Mode::XApic(paging::map_mmio(PhysAddr::new(base), 4096)))
+}
+
+extern "x86-interrupt" fn spurious_interrupt(_stack_frame: InterruptStackFrame) {
+    // Not a real interrupt, so it must not get an EOI.
+}
+
+extern "x86-interrupt" fn error_interrupt(_stack_frame: InterruptStackFrame) {
+    if let Some(lapic) = local() {
+        println!("Local APIC error: {:#x}", lapic.error_status());
+        lapic.eoi();
+    }
+}
+
+/// Brings up the local APIC of the bootstrap CPU and moves IRQs over to the I/O APIC. Falls
+/// back to the 8259 PIC (which must already be initialized) if either is missing.
+pub fn init() {
+    let Some(mode) = detect() else {
+        println!("No local APIC, IRQs stay on the 8259 PIC.");
+        return;
+    };
+    let lapic = LAPIC.call_once(|| LocalApic { mode });
+    lapic.enable();
+
+    idt::register(SPURIOUS_VECTOR, spurious_interrupt)
+        .expect("APIC spurious vector already in use");
+    idt::register(ERROR_VECTOR, error_interrupt).expect("APIC error vector already in use");
+
+    let id = lapic.id();
+    let version = lapic.read(VERSION) & 0xff;
+    if !ioapic::init(id) {
+        // Let the PIC's interrupts through, as they were before the APIC was enabled.
+        lapic.write(LVT_LINT0, LVT_DELIVERY_EXTINT);
+        println!(
+            "Local APIC {} ({}, version {:#x}), but no I/O APIC; IRQs stay on the 8259 PIC.",
+            id, mode, version
+        );
+        return;
+    }
+    irq::use_ioapic();
+
+    println!(
+        "Local APIC {} ({}, version {:#x}), IRQs routed through the I/O APIC.",
+        id, mode, version
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::instructions::hlt;
+
+    use super::*;
+    use crate::time;
+
+    #[test_case]
+    fn id_matches_cpuid() {
+        let Some(lapic) = local() else {
+            return;
+        };
+        let expected = match lapic.mode() {
+            Mode::XApic(_) => __cpuid(1).ebx >> 24,
+            Mode::X2Apic => __cpuid(0xb).edx,
+        };
+        assert_eq!(lapic.id(), expected);
+    }
+
+    #[test_case]
+    fn timer_interrupts_arrive() {
+        // Whichever controller ended up delivering IRQs, the PIT has to get through.
+        let start = time::ticks();
+        while time::ticks() < start + 2 {
+            hlt();
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! Local APIC timer.
+//!
+//! Each local APIC has a timer of its own, which only interrupts its own CPU. It counts down
+//! from an initial count at the bus clock rate (divided by 16 here), once or periodically, or
+//! on CPUs that support TSC-deadline mode, fires when the TSC reaches a value written to an MSR.
+//! The bus clock rate isn't reported, so [`init`] measures it against the TSC if it has been
+//! calibrated, or else PIT channel 2. Every CPU's timer runs at the same rate, as long as each
+//! CPU sets the same divider, which [`LocalApic::enable`] does.
+//!
+//! The functions here program the timer of the CPU they are called on, whose interrupt is the
+//! CPU's own tick (see [`time::start_local_tick`]).
+
+use core::{
+    arch::x86_64::__cpuid,
+    hint::spin_loop,
+    sync::atomic::{fence, AtomicBool, AtomicU64, Ordering},
+};
+
+use x86_64::{
+    instructions::interrupts::without_interrupts, registers::model_specific::Msr,
+    structures::idt::InterruptStackFrame,
+};
+
+use super::{LocalApic, LVT_MASKED, LVT_TIMER, TIMER_CURRENT_COUNT, TIMER_INITIAL_COUNT};
+use crate::{
+    apic, idt, pit, println, sched,
+    time::{self, Duration, Hz, Instant, NANOS_PER_SEC},
+    tsc,
+};
+
+/// Vector of the timer interrupt.
+pub const VECTOR: u8 = 0xfc;
+
+const LVT_ONE_SHOT: u32 = 0b00 << 17;
+const LVT_PERIODIC: u32 = 0b01 << 17;
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+#[cfg(test)]
+const LVT_MODE_MASK: u32 = 0b11 << 17;
+
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+const CALIBRATION_MS: u32 = 10;
+
+/// Counts per second, 0 until calibrated.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    OneShot,
+    Periodic,
+    TscDeadline,
+}
+
+/// Counts per second, 0 if there is no timer or it hasn't been calibrated.
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Whether [`set_deadline`] uses TSC-deadline mode.
+pub fn has_tsc_deadline() -> bool {
+    TSC_DEADLINE.load(Ordering::Relaxed)
+}
+
+/// Timer counts in `duration`, as many as fit in the count register, and at least 1 so that
+/// the timer doesn't stop.
+fn counts(duration: Duration) -> u32 {
+    let counts = duration.as_nanos() * frequency() as u128 / NANOS_PER_SEC as u128;
+    counts.clamp(1, u32::MAX as u128) as u32
+}
+
+fn set_mode(lapic: &LocalApic, mode: u32) {
+    let lvt = VECTOR as u32 | mode;
+    if lapic.read(LVT_TIMER) != lvt {
+        lapic.write(LVT_TIMER, lvt);
+        // The LVT write must be done before a TSC-deadline MSR write, which isn't ordered
+        // with MMIO writes.
+        fence(Ordering::SeqCst);
+    }
+}
+
+/// Interrupts this CPU every `period`, up to about 68 seconds per GHz of bus clock.
+pub fn start_periodic(period: Duration) {
+    if let Some(lapic) = apic::local() {
+        set_mode(lapic, LVT_PERIODIC);
+        lapic.write(TIMER_INITIAL_COUNT, counts(period));
+    }
+}
+
+/// Interrupts this CPU once, `delay` from now.
+pub fn start_one_shot(delay: Duration) {
+    if let Some(lapic) = apic::local() {
+        set_mode(lapic, LVT_ONE_SHOT);
+        lapic.write(TIMER_INITIAL_COUNT, counts(delay));
+    }
+}
+
+/// Interrupts this CPU once at `deadline`, in TSC-deadline mode if it can, which doesn't
+/// lose the time spent programming it. Replaces whatever the timer was set to.
+pub fn set_deadline(deadline: Instant) {
+    let delay = deadline.duration_since(Instant::now());
+    if !has_tsc_deadline() {
+        start_one_shot(delay);
+        return;
+    }
+    if let Some(lapic) = apic::local() {
+        set_mode(lapic, LVT_TSC_DEADLINE);
+        let cycles = delay.as_nanos() * tsc::frequency() as u128 / NANOS_PER_SEC as u128;
+        // Zero would disarm the timer.
+        let tsc = tsc::read().saturating_add(cycles as u64).max(1);
+        unsafe { Msr::new(IA32_TSC_DEADLINE).write(tsc) };
+    }
+}
+
+/// Stops this CPU's timer.
+pub fn stop() {
+    if let Some(lapic) = apic::local() {
+        // Leaving TSC-deadline mode disarms the deadline as well.
+        lapic.write(LVT_TIMER, LVT_MASKED | LVT_ONE_SHOT | VECTOR as u32);
+        lapic.write(TIMER_INITIAL_COUNT, 0);
+    }
+}
+
+/// The mode this CPU's timer is in, `None` if it is stopped.
+#[cfg(test)]
+pub fn mode() -> Option<Mode> {
+    let lvt = apic::local()?.read(LVT_TIMER);
+    if lvt & LVT_MASKED != 0 {
+        return None;
+    }
+    match lvt & LVT_MODE_MASK {
+        LVT_ONE_SHOT => Some(Mode::OneShot),
+        LVT_PERIODIC => Some(Mode::Periodic),
+        LVT_TSC_DEADLINE => Some(Mode::TscDeadline),
+        _ => None,
+    }
+}
+
+extern "x86-interrupt" fn timer_interrupt(_stack_frame: InterruptStackFrame) {
+    time::local_tick();
+    apic::eoi();
+    sched::preempt();
+}
+
+/// Counts down from the top for [`CALIBRATION_MS`], against the TSC or the PIT, with the
+/// timer masked. The divider was set by [`LocalApic::enable`].
+fn calibrate(lapic: &LocalApic, against_tsc: bool) -> u64 {
+    lapic.write(LVT_TIMER, LVT_MASKED | LVT_ONE_SHOT | VECTOR as u32);
+    let counted = without_interrupts(|| {
+        lapic.write(TIMER_INITIAL_COUNT, u32::MAX);
+        if against_tsc {
+            let end = tsc::read() + tsc::frequency() * CALIBRATION_MS as u64 / 1000;
+            while tsc::read() < end {
+                spin_loop();
+            }
+        } else {
+            pit::wait((pit::FREQUENCY * CALIBRATION_MS / 1000) as u16);
+        }
+        u32::MAX - lapic.read(TIMER_CURRENT_COUNT)
+    });
+    lapic.write(TIMER_INITIAL_COUNT, 0);
+    counted as u64 * 1000 / CALIBRATION_MS as u64
+}
+
+/// Measures the timer's rate, which is the same on every CPU. Must run after
+/// [`apic::init`](super::init) and [`tsc::init`], and the timer is only started by
+/// [`time::start_local_tick`].
+pub fn init() {
+    let Some(lapic) = apic::local() else {
+        return;
+    };
+    let against_tsc = tsc::frequency() > 0;
+    let frequency = calibrate(lapic, against_tsc);
+    if frequency == 0 {
+        println!("Local APIC timer: not counting");
+        return;
+    }
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    let tsc_deadline = against_tsc && __cpuid(1).ecx & (1 << 24) != 0;
+    TSC_DEADLINE.store(tsc_deadline, Ordering::Relaxed);
+    idt::register(VECTOR, timer_interrupt).expect("Local APIC timer vector already in use");
+    println!(
+        "Local APIC timer: {}, against the {}{}",
+        Hz(frequency),
+        if against_tsc { "TSC" } else { "PIT" },
+        if tsc_deadline { ", TSC-deadline" } else { "" }
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use spin::Mutex;
+
+    use super::*;
+    use crate::smp::{self, CpuMask};
+
+    #[test_case]
+    fn calibration_agrees_with_the_busy_wait() {
+        let Some(lapic) = apic::local().filter(|_| frequency() > 0) else {
+            return;
+        };
+        let counted = without_interrupts(|| {
+            stop();
+            lapic.write(TIMER_INITIAL_COUNT, u32::MAX);
+            // It counts down.
+            let start = lapic.read(TIMER_CURRENT_COUNT);
+            time::udelay(10_000);
+            let counted = (start - lapic.read(TIMER_CURRENT_COUNT)) as u64 * 100;
+            time::start_local_tick();
+            counted
+        });
+        assert!(counted >= frequency() * 95 / 100, "{} Hz", counted);
+        assert!(counted <= frequency() * 11 / 10, "{} Hz", counted);
+    }
+
+    #[test_case]
+    fn every_cpu_ticks_on_its_own() {
+        if frequency() == 0 || time::is_tickless() {
+            return;
+        }
+        let cpus = CpuMask::online();
+        let ticks = |cpu| {
+            let stats = sched::cpu_stats(cpu);
+            stats.busy_ticks + stats.idle_ticks
+        };
+        let start = Instant::now();
+        let before: alloc::vec::Vec<_> = cpus.iter().map(ticks).collect();
+        time::sleep(time::tick_period() * 10);
+        let after: alloc::vec::Vec<_> = cpus.iter().map(ticks).collect();
+        let expected = start.elapsed().as_nanos() / time::tick_period().as_nanos();
+        // Loose enough for ticks that land just outside the window, but a timer dividing by
+        // anything other than what it was calibrated for is off by a factor of two or more.
+        for ((cpu, before), after) in cpus.iter().zip(before).zip(after) {
+            let ticked = (after - before) as u128;
+            assert!(
+                ticked >= expected / 2 && ticked <= expected * 3 / 2 + 1,
+                "CPU {} ticked {} times, expected {}",
+                cpu,
+                ticked,
+                expected
+            );
+        }
+        assert_eq!(mode(), Some(Mode::Periodic));
+    }
+
+    #[test_case]
+    fn idle_cpus_stop_their_timers() {
+        if frequency() == 0 || !time::is_tickless() || smp::cpu_count() == 1 {
+            return;
+        }
+        let expected = if has_tsc_deadline() {
+            Mode::TscDeadline
+        } else {
+            Mode::OneShot
+        };
+        let timers = Mutex::new(Vec::new());
+        smp::call_on(CpuMask::others(), &|| {
+            let busy = sched::slice_end().is_some();
+            timers.lock().push((smp::current_cpu(), busy, mode()));
+        });
+        let timers = timers.into_inner();
+        for &(cpu, busy, mode) in &timers {
+            // A CPU that has to switch threads at some point has its timer set for then.
+            if busy {
+                assert_eq!(mode, Some(expected), "CPU {}", cpu);
+            } else {
+                assert_ne!(mode, Some(Mode::Periodic), "CPU {}", cpu);
+            }
+        }
+        // Nothing runs on most of them, so their timers wait for something to do.
+        assert!(
+            timers.iter().any(|&(_, _, mode)| mode.is_none()),
+            "{:?}",
+            timers
+        );
+    }
+}
@@ -2,6 +2,9 @@ use core::ops::Range;
 
 use multiboot2::{BasicMemoryInfoTag, BootInformation, BootInformationHeader};
 use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::paging;
 
 pub static MULTIBOOT_INFO: Once<BootInformation<'static>> = Once::new();
 pub static BOOT_INFO: Once<BootInfo> = Once::new();
@@ -9,7 +12,9 @@ pub static BOOT_INFO: Once<BootInfo> = Once::new();
 #[allow(unused)]
 pub struct BootInfo {
     pub info: &'static multiboot2::BootInformation<'static>,
+    /// Physical address of the multiboot info.
     pub start_addr: usize,
+    /// Physical address of the end of the multiboot info.
     pub end_addr: usize,
     pub total_size: usize,
     pub loader: &'static str,
@@ -18,8 +23,10 @@ pub struct BootInfo {
     pub mem_map: &'static [multiboot2::MemoryArea],
 }
 
+/// Parses the multiboot info at physical address `mboot_ptr`, through the direct map.
 pub fn init(mboot_ptr: usize) -> Result<(), ()> {
-    let boot_info = unsafe { BootInformation::load(mboot_ptr as *const BootInformationHeader) };
+    let ptr = paging::phys_to_virt(PhysAddr::new(mboot_ptr as u64));
+    let boot_info = unsafe { BootInformation::load(ptr.as_ptr::<BootInformationHeader>()) };
     let Ok(boot_info) = boot_info else {
         return Err(());
     };
@@ -33,9 +40,9 @@ pub fn init(mboot_ptr: usize) -> Result<(), ()> {
         let basic_map = unsafe { basic_map.as_ref().unwrap() };
         let bounds = basic_map.memory_lower()..basic_map.memory_upper();
 
-        let start_addr = boot_info.start_address();
-        let end_addr = boot_info.end_address();
         let total_size = boot_info.total_size();
+        let start_addr = mboot_ptr;
+        let end_addr = mboot_ptr + total_size;
 
         let loader = unsafe {
             boot_info
@@ -66,6 +73,15 @@ pub fn init(mboot_ptr: usize) -> Result<(), ()> {
     Ok(())
 }
 
+impl BootInfo {
+    /// Value of a `name=value` option on the command line.
+    pub fn option(&self, name: &str) -> Option<&'static str> {
+        self.cmdline?
+            .split_whitespace()
+            .find_map(|arg| arg.strip_prefix(name)?.strip_prefix('='))
+    }
+}
+
 pub fn boot_info() -> &'static BootInfo {
     BOOT_INFO.get().expect("Boot information not initialized")
 }
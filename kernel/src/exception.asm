@@ -0,0 +1,252 @@
+	[BITS 64]
+
+	; Entry points for CPU exceptions (vectors 0-31).
+	;
+	; Each stub makes the stack look the same whether or not the CPU pushed an error code,
+	; pushes its vector number, and jumps to exception_common, which saves every general
+	; purpose register and hands a pointer to all of it (an ExceptionFrame, see
+	; kernel/src/exception.rs) to exception_dispatch.
+	;
+	; Stack after exception_common is done pushing, from the top down:
+	;   ss, rsp, rflags, cs, rip      (pushed by the CPU)
+	;   error code                    (pushed by the CPU, or 0 from the stub)
+	;   vector                        (pushed by the stub)
+	;   rax ... r15                   (pushed by exception_common)
+
+	extern exception_dispatch
+
+	section .text
+
+exception_common:
+	push rax
+	push rbx
+	push rcx
+	push rdx
+	push rsi
+	push rdi
+	push rbp
+	push r8
+	push r9
+	push r10
+	push r11
+	push r12
+	push r13
+	push r14
+	push r15
+
+	;   The CPU aligns the stack to 16 bytes before pushing its frame, and the 22
+	;   quadwords pushed since then (176 bytes) keep it aligned for the call.
+	mov  rdi, rsp
+	cld
+	call exception_dispatch
+
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop r11
+	pop r10
+	pop r9
+	pop r8
+	pop rbp
+	pop rdi
+	pop rsi
+	pop rdx
+	pop rcx
+	pop rbx
+	pop rax
+
+	;   drop the vector and error code
+	add rsp, 16
+	iretq
+
+	; Vectors 8, 10-14, 17, 21, 29 and 30 come with an error code, the rest push a 0.
+
+exception_stub_0:
+	push 0
+	push 0
+	jmp  exception_common
+
+exception_stub_1:
+	push 0
+	push 1
+	jmp  exception_common
+
+exception_stub_2:
+	push 0
+	push 2
+	jmp  exception_common
+
+exception_stub_3:
+	push 0
+	push 3
+	jmp  exception_common
+
+exception_stub_4:
+	push 0
+	push 4
+	jmp  exception_common
+
+exception_stub_5:
+	push 0
+	push 5
+	jmp  exception_common
+
+exception_stub_6:
+	push 0
+	push 6
+	jmp  exception_common
+
+exception_stub_7:
+	push 0
+	push 7
+	jmp  exception_common
+
+exception_stub_8:
+	push 8
+	jmp  exception_common
+
+exception_stub_9:
+	push 0
+	push 9
+	jmp  exception_common
+
+exception_stub_10:
+	push 10
+	jmp  exception_common
+
+exception_stub_11:
+	push 11
+	jmp  exception_common
+
+exception_stub_12:
+	push 12
+	jmp  exception_common
+
+exception_stub_13:
+	push 13
+	jmp  exception_common
+
+exception_stub_14:
+	push 14
+	jmp  exception_common
+
+exception_stub_15:
+	push 0
+	push 15
+	jmp  exception_common
+
+exception_stub_16:
+	push 0
+	push 16
+	jmp  exception_common
+
+exception_stub_17:
+	push 17
+	jmp  exception_common
+
+exception_stub_18:
+	push 0
+	push 18
+	jmp  exception_common
+
+exception_stub_19:
+	push 0
+	push 19
+	jmp  exception_common
+
+exception_stub_20:
+	push 0
+	push 20
+	jmp  exception_common
+
+exception_stub_21:
+	push 21
+	jmp  exception_common
+
+exception_stub_22:
+	push 0
+	push 22
+	jmp  exception_common
+
+exception_stub_23:
+	push 0
+	push 23
+	jmp  exception_common
+
+exception_stub_24:
+	push 0
+	push 24
+	jmp  exception_common
+
+exception_stub_25:
+	push 0
+	push 25
+	jmp  exception_common
+
+exception_stub_26:
+	push 0
+	push 26
+	jmp  exception_common
+
+exception_stub_27:
+	push 0
+	push 27
+	jmp  exception_common
+
+exception_stub_28:
+	push 0
+	push 28
+	jmp  exception_common
+
+exception_stub_29:
+	push 29
+	jmp  exception_common
+
+exception_stub_30:
+	push 30
+	jmp  exception_common
+
+exception_stub_31:
+	push 0
+	push 31
+	jmp  exception_common
+
+	section .rodata
+
+	global exception_stubs
+
+	; Table of the stub addresses, indexed by vector.
+exception_stubs:
+	dq exception_stub_0
+	dq exception_stub_1
+	dq exception_stub_2
+	dq exception_stub_3
+	dq exception_stub_4
+	dq exception_stub_5
+	dq exception_stub_6
+	dq exception_stub_7
+	dq exception_stub_8
+	dq exception_stub_9
+	dq exception_stub_10
+	dq exception_stub_11
+	dq exception_stub_12
+	dq exception_stub_13
+	dq exception_stub_14
+	dq exception_stub_15
+	dq exception_stub_16
+	dq exception_stub_17
+	dq exception_stub_18
+	dq exception_stub_19
+	dq exception_stub_20
+	dq exception_stub_21
+	dq exception_stub_22
+	dq exception_stub_23
+	dq exception_stub_24
+	dq exception_stub_25
+	dq exception_stub_26
+	dq exception_stub_27
+	dq exception_stub_28
+	dq exception_stub_29
+	dq exception_stub_30
+	dq exception_stub_31
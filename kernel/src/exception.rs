@@ -0,0 +1,327 @@
+//! CPU exceptions.
+//!
+//! Every exception vector (0-31) enters through a stub in exception.asm, which saves all general
+//! purpose registers and calls [`exception_dispatch`] with them laid out as an
+//! [`ExceptionFrame`]. Exceptions we know how to deal with (demand paging, breakpoints, NMIs)
+//! are handled there and return to the interrupted code with the registers restored from the
+//! frame. Everything else ends up on the fatal error screen, together with a full register dump.
+
+use core::{
+    fmt::{self, Write},
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    instructions::{hlt, interrupts},
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::idt::{PageFaultErrorCode, SelectorErrorCode},
+    VirtAddr,
+};
+
+use crate::{
+    paging, println, serial, smp,
+    vga::{Color, WRITER},
+};
+
+pub const DEBUG: u64 = 1;
+pub const NON_MASKABLE: u64 = 2;
+pub const BREAKPOINT: u64 = 3;
+pub const PAGE_FAULT: u64 = 14;
+
+const NAMES: [&str; 32] = [
+    "Divide error",
+    "Debug",
+    "Non-maskable interrupt",
+    "Breakpoint",
+    "Overflow",
+    "Bound range exceeded",
+    "Invalid opcode",
+    "Device not available",
+    "Double fault",
+    "Coprocessor segment overrun",
+    "Invalid TSS",
+    "Segment not present",
+    "Stack-segment fault",
+    "General protection fault",
+    "Page fault",
+    "Reserved (15)",
+    "x87 floating point exception",
+    "Alignment check",
+    "Machine check",
+    "SIMD floating point exception",
+    "Virtualization exception",
+    "Control protection exception",
+    "Reserved (22)",
+    "Reserved (23)",
+    "Reserved (24)",
+    "Reserved (25)",
+    "Reserved (26)",
+    "Reserved (27)",
+    "Hypervisor injection exception",
+    "VMM communication exception",
+    "Security exception",
+    "Reserved (31)",
+];
+
+extern "C" {
+    // Defined in exception.asm, one entry point per vector.
+    static exception_stubs: [u64; 32];
+}
+
+/// Entry point for exception `vector`, to be installed in the IDT.
+pub fn stub(vector: u8) -> VirtAddr {
+    VirtAddr::new(unsafe { exception_stubs[vector as usize] })
+}
+
+/// Everything saved on the stack on the way into [`exception_dispatch`], lowest address first.
+/// Must match the push order in exception.asm.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for vectors where the CPU does not push an error code.
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionFrame {
+    pub fn name(&self) -> &'static str {
+        NAMES[self.vector as usize % NAMES.len()]
+    }
+
+    /// Whether the exception was raised while running in ring 3.
+    pub fn is_user(&self) -> bool {
+        self.cs & 0b11 == 0b11
+    }
+}
+
+/// The control registers at the time an exception was reported.
+#[derive(Debug, Clone, Copy)]
+pub struct ControlRegisters {
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl ControlRegisters {
+    pub fn read() -> Self {
+        let (pml4, pcid) = Cr3::read_raw();
+        Self {
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: pml4.start_address().as_u64() | pcid as u64,
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+/// Decodes the error code pushed for `vector`, if it has a meaning beyond "zero".
+struct ErrorCode {
+    vector: u64,
+    code: u64,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.code)?;
+        match self.vector {
+            PAGE_FAULT => {
+                let error = PageFaultErrorCode::from_bits_truncate(self.code);
+                write!(f, " ({})", paging::FaultCause(error))
+            }
+            // Invalid TSS, segment not present, stack-segment fault, general protection.
+            10..=13 if self.code != 0 => {
+                let selector = SelectorErrorCode::new_truncate(self.code);
+                write!(
+                    f,
+                    " ({:?} index {}{})",
+                    selector.descriptor_table(),
+                    selector.index(),
+                    if selector.external() {
+                        ", external"
+                    } else {
+                        ""
+                    }
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What ends up on the fatal error screen when an exception can't be handled.
+struct ExceptionReport<'a> {
+    frame: &'a ExceptionFrame,
+    control: ControlRegisters,
+    detail: Option<&'a dyn fmt::Display>,
+}
+
+impl fmt::Display for ExceptionReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = self.frame;
+        writeln!(
+            f,
+            "EXCEPTION {}: {} in {} mode",
+            frame.vector,
+            frame.name(),
+            if frame.is_user() { "user" } else { "kernel" }
+        )?;
+        writeln!(
+            f,
+            "Error code: {}",
+            ErrorCode {
+                vector: frame.vector,
+                code: frame.error_code
+            }
+        )?;
+        if let Some(detail) = self.detail {
+            write!(f, "{}", detail)?;
+        }
+
+        let registers = [
+            ("rax", frame.rax),
+            ("rbx", frame.rbx),
+            ("rcx", frame.rcx),
+            ("rdx", frame.rdx),
+            ("rsi", frame.rsi),
+            ("rdi", frame.rdi),
+            ("rbp", frame.rbp),
+            ("rsp", frame.rsp),
+            ("r8", frame.r8),
+            ("r9", frame.r9),
+            ("r10", frame.r10),
+            ("r11", frame.r11),
+            ("r12", frame.r12),
+            ("r13", frame.r13),
+            ("r14", frame.r14),
+            ("r15", frame.r15),
+            ("rip", frame.rip),
+            ("rfl", frame.rflags),
+            ("cs", frame.cs),
+            ("ss", frame.ss),
+            ("cr0", self.control.cr0),
+            ("cr2", self.control.cr2),
+            ("cr3", self.control.cr3),
+            ("cr4", self.control.cr4),
+        ];
+        for line in registers.chunks(3) {
+            for (name, value) in line {
+                write!(f, "{:>3}={:#018x}  ", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Extra lines for a page fault that [`paging::handle_page_fault`] could not resolve.
+struct PageFaultDetail {
+    addr: VirtAddr,
+    reason: paging::FaultError,
+}
+
+impl fmt::Display for PageFaultDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Address: {:#x}", self.addr)?;
+        writeln!(f, "Unresolved: {}", self.reason)?;
+        write!(f, "{}", paging::walk(self.addr))
+    }
+}
+
+/// Called by exception_common in exception.asm with interrupts disabled. Returning resumes the
+/// interrupted code with the (possibly modified) registers in `frame`.
+#[no_mangle]
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    match frame.vector {
+        PAGE_FAULT => {
+            // Faults in demand paged regions get a fresh page, and the access is retried on
+            // return.
+            let error = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            let Err(reason) = paging::handle_page_fault(error) else {
+                return;
+            };
+            let detail = PageFaultDetail {
+                addr: VirtAddr::new_truncate(Cr2::read_raw()),
+                reason,
+            };
+            report_fatal(frame, Some(&detail));
+        }
+        BREAKPOINT | DEBUG => {
+            println!("{} at {:#x}", frame.name(), frame.rip);
+        }
+        NON_MASKABLE => {
+            if smp::stopping() {
+                // Another CPU hit a fatal error and is stopping the rest of us.
+                halt();
+            }
+            // Otherwise this is most likely a hardware error.
+            println!("Non-maskable interrupt at {:#x}, ignoring", frame.rip);
+        }
+        _ => report_fatal(frame, None),
+    }
+}
+
+fn report_fatal(frame: &ExceptionFrame, detail: Option<&dyn fmt::Display>) -> ! {
+    fatal_error(&ExceptionReport {
+        frame,
+        control: ControlRegisters::read(),
+        detail,
+    })
+}
+
+static IN_FATAL: AtomicBool = AtomicBool::new(false);
+
+/// Clears the screen, prints `report` in white on red and stops the machine for good.
+///
+/// Used for unrecoverable exceptions and by the panic handler, so it must not rely on anything
+/// that could be in a broken state: interrupts are disabled, the other CPUs are stopped with an
+/// NMI and the VGA writer is taken by force.
+pub fn fatal_error(report: &dyn fmt::Display) -> ! {
+    interrupts::disable();
+    smp::stop_others();
+
+    // A fault while reporting a fault; the screen is already as good as it will get.
+    if !IN_FATAL.swap(true, Ordering::SeqCst) {
+        unsafe {
+            let writer = &*addr_of!(WRITER);
+            // Whoever was holding the writer is never going to release it.
+            writer.force_unlock();
+            let mut writer = writer.lock();
+            writer.set_color(Color::White, Color::Red);
+            writer.clear_screen();
+            write!(writer, "{}", report).ok();
+            serial::write_fatal(report);
+        }
+    }
+
+    halt();
+}
+
+/// Halts the current CPU for good.
+fn halt() -> ! {
+    loop {
+        interrupts::disable();
+        hlt();
+    }
+}
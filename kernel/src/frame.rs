@@ -0,0 +1,346 @@
+//! Physical frame allocator.
+//!
+//! Physical memory is tracked with a bitmap holding one bit per 4 KiB frame, where a set bit
+//! means the frame is free. Everything starts out as used; the `Available` regions found by
+//! [`mem::find_available_regions`] are then released, and the ranges that are already in use
+//! (the kernel image, the multiboot info, the legacy BIOS/VGA area, ...) are claimed again so
+//! that nothing we hand out can ever overlap them.
+//!
+//! Only the first [`MAX_PHYS_MEM`] bytes of physical memory are managed for now.
+
+use core::ops::Range;
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+use crate::{boot_info, mem, paging::KERNEL_OFFSET, println, smp};
+
+pub const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+/// Amount of physical memory covered by the bitmap (4 GiB).
+pub const MAX_PHYS_MEM: u64 = 4 * 1024 * 1024 * 1024;
+
+const MAX_FRAMES: usize = (MAX_PHYS_MEM / FRAME_SIZE) as usize;
+const BITMAP_WORDS: usize = MAX_FRAMES / 64;
+const MAX_RESERVED: usize = 8;
+
+extern "C" {
+    // Defined in linker.ld
+    static __kernel_start: u8;
+    static __kernel_end: u8;
+}
+
+/// A range of physical memory that must never be handed out.
+#[derive(Debug, Clone)]
+pub struct ReservedRange {
+    pub name: &'static str,
+    pub range: Range<u64>,
+}
+
+pub struct FrameAllocator {
+    /// One bit per frame, set if the frame is free.
+    bitmap: [u64; BITMAP_WORDS],
+    /// One past the highest frame number covered by an available region.
+    frame_limit: usize,
+    /// Frame number to resume searching from.
+    next: usize,
+    free: usize,
+    total: usize,
+    reserved: [Option<ReservedRange>; MAX_RESERVED],
+    reserved_count: usize,
+}
+
+const NO_RESERVED: Option<ReservedRange> = None;
+
+impl FrameAllocator {
+    pub const fn new() -> Self {
+        Self {
+            bitmap: [0; BITMAP_WORDS],
+            frame_limit: 0,
+            next: 0,
+            free: 0,
+            total: 0,
+            reserved: [NO_RESERVED; MAX_RESERVED],
+            reserved_count: 0,
+        }
+    }
+
+    #[inline(always)]
+    fn is_free(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    #[inline(always)]
+    fn mark_free(&mut self, frame: usize) {
+        self.bitmap[frame / 64] |= 1 << (frame % 64);
+    }
+
+    #[inline(always)]
+    fn mark_used(&mut self, frame: usize) {
+        self.bitmap[frame / 64] &= !(1 << (frame % 64));
+    }
+
+    /// Adds every frame that lies entirely within `range` to the free pool.
+    pub fn release(&mut self, range: Range<u64>) {
+        let start = (range.start.div_ceil(FRAME_SIZE) as usize).min(MAX_FRAMES);
+        let end = ((range.end / FRAME_SIZE) as usize).min(MAX_FRAMES);
+        for frame in start..end {
+            if !self.is_free(frame) {
+                self.mark_free(frame);
+                self.free += 1;
+                self.total += 1;
+            }
+        }
+        self.frame_limit = self.frame_limit.max(end);
+    }
+
+    /// Removes every frame that touches `range` from the free pool, permanently.
+    pub fn reserve(&mut self, name: &'static str, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        let start = ((range.start / FRAME_SIZE) as usize).min(MAX_FRAMES);
+        let end = (range.end.div_ceil(FRAME_SIZE) as usize).min(MAX_FRAMES);
+        for frame in start..end {
+            if self.is_free(frame) {
+                self.mark_used(frame);
+                self.free -= 1;
+                self.total -= 1;
+            }
+        }
+        assert!(
+            self.reserved_count < MAX_RESERVED,
+            "Too many reserved physical ranges"
+        );
+        self.reserved[self.reserved_count] = Some(ReservedRange { name, range });
+        self.reserved_count += 1;
+    }
+
+    pub fn reserved(&self) -> impl Iterator<Item = &ReservedRange> {
+        self.reserved[..self.reserved_count].iter().flatten()
+    }
+
+    /// Allocates a single frame, returning its frame number.
+    pub fn alloc(&mut self) -> Option<usize> {
+        if self.free == 0 {
+            return None;
+        }
+        let words = self.frame_limit.div_ceil(64);
+        let first = self.next / 64;
+        for i in 0..words {
+            let word = (first + i) % words;
+            let bits = self.bitmap[word];
+            if bits == 0 {
+                continue;
+            }
+            let frame = word * 64 + bits.trailing_zeros() as usize;
+            self.mark_used(frame);
+            self.free -= 1;
+            self.next = frame + 1;
+            return Some(frame);
+        }
+        None
+    }
+
+    /// Allocates `count` physically contiguous frames whose first frame number is a multiple
+    /// of `align` (in frames).
+    pub fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 || count > self.free {
+            return None;
+        }
+        let mut start = 0;
+        while start + count <= self.frame_limit {
+            match (start..start + count).find(|&frame| !self.is_free(frame)) {
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    for frame in start..start + count {
+                        self.mark_used(frame);
+                    }
+                    self.free -= count;
+                    return Some(start);
+                }
+            }
+        }
+        None
+    }
+
+    pub fn free(&mut self, frame: usize) {
+        assert!(
+            frame < self.frame_limit,
+            "Freeing unmanaged frame {:#x}",
+            frame
+        );
+        assert!(!self.is_free(frame), "Double free of frame {:#x}", frame);
+        let addr = frame as u64 * FRAME_SIZE;
+        if let Some(reserved) = self.reserved().find(|r| r.range.contains(&addr)) {
+            panic!(
+                "Freeing frame {:#x} in reserved range {}",
+                addr, reserved.name
+            );
+        }
+        self.mark_free(frame);
+        self.free += 1;
+    }
+}
+
+static ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::new());
+
+fn kernel_image() -> Range<u64> {
+    let start = unsafe { &__kernel_start as *const u8 } as u64;
+    let end = unsafe { &__kernel_end as *const u8 } as u64;
+    start - KERNEL_OFFSET..end - KERNEL_OFFSET
+}
+
+fn frame_at(frame: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE))
+}
+
+fn frame_number(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+/// Seeds the allocator from the memory map. Must run after [`mem::find_available_regions`].
+pub fn init() {
+    let boot_info = boot_info::boot_info();
+    let mut allocator = ALLOCATOR.lock();
+
+    for region in mem::available_regions() {
+        allocator.release(region.start_address()..region.end_address());
+    }
+
+    allocator.reserve("real mode IVT and BIOS data area", 0..0x1000);
+    allocator.reserve("EBDA, VGA memory and BIOS ROM", 0x9f000..0x100000);
+    allocator.reserve(
+        "AP trampoline",
+        smp::TRAMPOLINE_BASE..smp::TRAMPOLINE_BASE + FRAME_SIZE,
+    );
+    allocator.reserve("kernel image", kernel_image());
+    allocator.reserve(
+        "multiboot info",
+        boot_info.start_addr as u64..boot_info.end_addr as u64,
+    );
+
+    println!(
+        "Frame allocator: {} of {} frames free.",
+        allocator.free, allocator.total
+    );
+}
+
+/// Allocates a single 4 KiB frame.
+pub fn alloc_frame() -> Option<PhysFrame> {
+    without_interrupts(|| ALLOCATOR.lock().alloc()).map(frame_at)
+}
+
+/// Returns a frame obtained from [`alloc_frame`] or [`alloc_contiguous`] to the free pool.
+pub fn free_frame(frame: PhysFrame) {
+    without_interrupts(|| ALLOCATOR.lock().free(frame_number(frame)));
+}
+
+/// Allocates `count` physically contiguous frames, the first of which is aligned to `align`
+/// bytes. `align` must be a power of two; anything below the frame size is rounded up to it.
+pub fn alloc_contiguous(count: usize, align: u64) -> Option<PhysFrame> {
+    assert!(align.is_power_of_two(), "Alignment must be a power of two");
+    let align = (align.max(FRAME_SIZE) / FRAME_SIZE) as usize;
+    without_interrupts(|| ALLOCATOR.lock().alloc_contiguous(count, align)).map(frame_at)
+}
+
+/// Frees `count` frames starting at `start`, as returned by [`alloc_contiguous`].
+pub fn free_contiguous(start: PhysFrame, count: usize) {
+    let first = frame_number(start);
+    without_interrupts(|| {
+        let mut allocator = ALLOCATOR.lock();
+        for frame in first..first + count {
+            allocator.free(frame);
+        }
+    });
+}
+
+/// Returns the number of free frames and the total number of managed frames.
+pub fn stats() -> (usize, usize) {
+    without_interrupts(|| {
+        let allocator = ALLOCATOR.lock();
+        (allocator.free, allocator.total)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlaps_reserved(start: PhysFrame, count: usize) -> Option<&'static str> {
+        let range = start.start_address().as_u64()
+            ..start.start_address().as_u64() + count as u64 * FRAME_SIZE;
+        let allocator = ALLOCATOR.lock();
+        let overlap = allocator
+            .reserved()
+            .find(|r| r.range.start < range.end && range.start < r.range.end)
+            .map(|r| r.name);
+        overlap
+    }
+
+    fn is_available(frame: PhysFrame) -> bool {
+        let addr = frame.start_address().as_u64();
+        mem::available_regions()
+            .iter()
+            .any(|r| r.start_address() <= addr && addr + FRAME_SIZE <= r.end_address())
+    }
+
+    #[test_case]
+    fn frames_never_overlap_reserved_ranges() {
+        const COUNT: usize = 512;
+        let (free_before, _) = stats();
+
+        let mut frames = [None; COUNT];
+        for slot in frames.iter_mut() {
+            let frame = alloc_frame().expect("out of frames");
+            assert_eq!(overlaps_reserved(frame, 1), None);
+            assert!(is_available(frame));
+            *slot = Some(frame);
+        }
+        for (i, frame) in frames.iter().enumerate() {
+            assert!(!frames[i + 1..].contains(frame), "frame handed out twice");
+        }
+        assert_eq!(stats().0, free_before - COUNT);
+
+        for frame in frames.into_iter().flatten() {
+            free_frame(frame);
+        }
+        assert_eq!(stats().0, free_before);
+    }
+
+    #[test_case]
+    fn kernel_image_and_boot_info_are_reserved() {
+        let allocator = ALLOCATOR.lock();
+        let kernel = kernel_image();
+        let info = boot_info::boot_info();
+        for addr in (kernel.start..kernel.end).step_by(FRAME_SIZE as usize) {
+            assert!(!allocator.is_free((addr / FRAME_SIZE) as usize));
+        }
+        for addr in (info.start_addr as u64..info.end_addr as u64).step_by(FRAME_SIZE as usize) {
+            assert!(!allocator.is_free((addr / FRAME_SIZE) as usize));
+        }
+        for addr in (0xa0000..0x100000).step_by(FRAME_SIZE as usize) {
+            assert!(!allocator.is_free(addr / FRAME_SIZE as usize));
+        }
+    }
+
+    #[test_case]
+    fn contiguous_frames_are_aligned_and_unreserved() {
+        const COUNT: usize = 16;
+        const ALIGN: u64 = 64 * 1024;
+
+        let start = alloc_contiguous(COUNT, ALIGN).expect("no contiguous range");
+        assert!(start.start_address().is_aligned(ALIGN));
+        assert_eq!(overlaps_reserved(start, COUNT), None);
+        {
+            let allocator = ALLOCATOR.lock();
+            let first = frame_number(start);
+            assert!((first..first + COUNT).all(|frame| !allocator.is_free(frame)));
+        }
+        free_contiguous(start, COUNT);
+    }
+}
@@ -84,18 +84,25 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-static mut TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
-    let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// Size of the stack double faults are handled on.
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
 
-        let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(STACK) });
-        let stack_end = stack_start + STACK_SIZE;
-        stack_end
-    };
-    tss.privilege_stack_table[0] = VirtAddr::new(0x150000); // TODO: figure out what this number is
+/// A TSS that switches to `rsp0` on entry from ring 3 and handles double faults on the stack
+/// ending at `double_fault_stack_end`.
+pub fn new_tss(rsp0: VirtAddr, double_fault_stack_end: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_end;
+    tss.privilege_stack_table[0] = rsp0;
     tss
+}
+
+/// The boot CPU's TSS, used until `percpu::init` gives it one of its own.
+static mut TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
+    static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+    let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
+    // Nothing runs in ring 3 this early.
+    new_tss(VirtAddr::zero(), stack_start + DOUBLE_FAULT_STACK_SIZE)
 });
 
 #[derive(Debug, Clone)]
@@ -109,17 +116,18 @@ pub struct Selectors {
 }
 
 #[allow(dead_code)]
-struct Gdt {
+pub struct Gdt {
     selectors: Selectors,
     gdt: GlobalDescriptorTable,
 }
 
-static mut GDT: Lazy<Gdt> = Lazy::new(|| {
+/// Builds a GDT using `tss`. Every CPU gets one of these, all with the same selectors.
+pub fn build(tss: &'static TaskStateSegment) -> Gdt {
     let mut gdt = GlobalDescriptorTable::new();
     let ring0_code = gdt.add_entry(Descriptor::kernel_code_segment());
     let ring3_code = gdt.add_entry(Descriptor::user_code_segment());
     let ring3_data = gdt.add_entry(Descriptor::user_data_segment());
-    let tss = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
     Gdt {
         selectors: Selectors {
             // I am not sure if this aligns exactly with x86_64's GDT,
@@ -151,18 +159,26 @@ static mut GDT: Lazy<Gdt> = Lazy::new(|| {
         },
         gdt,
     }
-});
+}
 
-pub fn init() {
+static mut GDT: Lazy<Gdt> = Lazy::new(|| build(unsafe { &*addr_of!(TSS) }));
+
+/// Loads `gdt` and its TSS on the current CPU.
+pub fn load(gdt: &'static Gdt) {
     use x86_64::instructions::segmentation::{Segment, CS};
     use x86_64::instructions::tables::load_tss;
+    gdt.gdt.load();
     unsafe {
-        GDT.gdt.load();
-        CS::set_reg(GDT.selectors.ring0_code);
-        load_tss(GDT.selectors.tss);
+        CS::set_reg(gdt.selectors.ring0_code);
+        load_tss(gdt.selectors.tss);
     }
 }
 
+/// Loads the boot CPU's first GDT, which is all there is until the heap is up.
+pub fn init() {
+    load(unsafe { &*addr_of!(GDT) });
+}
+
 pub fn selectors<'a>() -> &'a Selectors {
     unsafe { &GDT.selectors }
 }
@@ -0,0 +1,240 @@
+//! Kernel heap.
+//!
+//! The heap lives at a fixed virtual range ([`HEAP_START`], [`HEAP_SIZE`] bytes long) that is
+//! backed by frames from the physical frame allocator when the kernel starts up.
+//!
+//! Allocation uses an address-ordered free list. Every block handed out is rounded up to a
+//! multiple of the size of a free-list node and aligned to at least that, so whatever is left
+//! over when a hole is split is always big enough to become a hole itself. Neighbouring holes
+//! are merged on free.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::{align_of, size_of},
+    ptr::{self, null_mut},
+};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{frame, paging, println};
+
+pub const HEAP_START: u64 = 0xffff_9000_0000_0000;
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: KernelHeap = KernelHeap(Mutex::new(LinkedListAllocator::empty()));
+
+struct Hole {
+    size: usize,
+    next: *mut Hole,
+}
+
+pub struct LinkedListAllocator {
+    /// Dummy node; `head.next` is the lowest hole.
+    head: Hole,
+    free: usize,
+}
+
+// The raw pointers only ever point into the heap, which is owned by the allocator.
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator {
+    const MIN_ALIGN: usize = align_of::<Hole>();
+    const MIN_SIZE: usize = size_of::<Hole>();
+
+    pub const fn empty() -> Self {
+        Self {
+            head: Hole {
+                size: 0,
+                next: null_mut(),
+            },
+            free: 0,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The memory range must be mapped, unused, and must only be given to the allocator once.
+    pub unsafe fn init(&mut self, start: usize, size: usize) {
+        let aligned = start.next_multiple_of(Self::MIN_ALIGN);
+        let size = (size - (aligned - start)) / Self::MIN_SIZE * Self::MIN_SIZE;
+        self.add_hole(aligned, size);
+    }
+
+    /// Bytes currently free, ignoring fragmentation.
+    pub fn free_bytes(&self) -> usize {
+        self.free
+    }
+
+    fn adjust(layout: Layout) -> (usize, usize) {
+        let size = layout
+            .size()
+            .max(Self::MIN_SIZE)
+            .next_multiple_of(Self::MIN_SIZE);
+        let align = layout.align().max(Self::MIN_ALIGN);
+        (size, align)
+    }
+
+    /// Inserts a hole in address order, merging it with its neighbours where they touch.
+    unsafe fn add_hole(&mut self, addr: usize, size: usize) {
+        debug_assert!(addr.is_multiple_of(Self::MIN_ALIGN) && size.is_multiple_of(Self::MIN_SIZE));
+        self.free += size;
+
+        let mut prev: *mut Hole = &mut self.head;
+        while !(*prev).next.is_null() && ((*prev).next as usize) < addr {
+            prev = (*prev).next;
+        }
+        let next = (*prev).next;
+
+        let hole = addr as *mut Hole;
+        hole.write(Hole { size, next });
+
+        // Merge with the following hole.
+        if !next.is_null() && addr + size == next as usize {
+            (*hole).size += (*next).size;
+            (*hole).next = (*next).next;
+        }
+
+        // Merge with the preceding hole, unless it is the dummy head.
+        if !ptr::eq(prev, &self.head) && prev as usize + (*prev).size == addr {
+            (*prev).size += (*hole).size;
+            (*prev).next = (*hole).next;
+        } else {
+            (*prev).next = hole;
+        }
+    }
+
+    pub unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::adjust(layout);
+
+        let mut prev: *mut Hole = &mut self.head;
+        while !(*prev).next.is_null() {
+            let hole = (*prev).next;
+            let hole_start = hole as usize;
+            let hole_end = hole_start + (*hole).size;
+
+            let start = hole_start.next_multiple_of(align);
+            let end = start.saturating_add(size);
+            if end > hole_end {
+                prev = hole;
+                continue;
+            }
+
+            // Take the hole off the list and give back whatever we don't use of it.
+            (*prev).next = (*hole).next;
+            self.free -= hole_end - hole_start;
+            if start > hole_start {
+                self.add_hole(hole_start, start - hole_start);
+            }
+            if hole_end > end {
+                self.add_hole(end, hole_end - end);
+            }
+            return start as *mut u8;
+        }
+
+        null_mut()
+    }
+
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::adjust(layout);
+        self.add_hole(ptr as usize, size);
+    }
+}
+
+pub struct KernelHeap(Mutex<LinkedListAllocator>);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| self.0.lock().alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.0.lock().dealloc(ptr, layout))
+    }
+}
+
+/// Maps the heap range and hands it to the global allocator.
+pub fn init() {
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::new(HEAP_START));
+    let end = Page::containing_address(VirtAddr::new(HEAP_START + HEAP_SIZE as u64 - 1));
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for page in Page::range_inclusive(start, end) {
+        let frame = frame::alloc_frame().expect("Out of memory while mapping the kernel heap");
+        paging::map(page, frame, flags).expect("Failed to map the kernel heap");
+    }
+
+    without_interrupts(|| unsafe {
+        ALLOCATOR.0.lock().init(HEAP_START as usize, HEAP_SIZE);
+    });
+
+    println!("Kernel heap: {} KB at {:#x}.", HEAP_SIZE / 1024, HEAP_START);
+}
+
+/// Bytes currently free on the kernel heap, ignoring fragmentation.
+pub fn free_bytes() -> usize {
+    without_interrupts(|| ALLOCATOR.0.lock().free_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, collections::BTreeMap, string::String, vec, vec::Vec};
+
+    use super::*;
+
+    #[test_case]
+    fn box_and_string() {
+        let value = Box::new(41);
+        assert_eq!(*value + 1, 42);
+
+        let mut s = String::from("goose");
+        s.push_str(" kernel");
+        assert_eq!(s.as_str(), "goose kernel");
+    }
+
+    #[test_case]
+    fn large_vec() {
+        let n = 1000u64;
+        let v: Vec<u64> = (0..n).collect();
+        assert_eq!(v.iter().sum::<u64>(), (n - 1) * n / 2);
+    }
+
+    #[test_case]
+    fn btree_map() {
+        let mut map = BTreeMap::new();
+        for i in 0..100 {
+            map.insert(i, i * 2);
+        }
+        assert_eq!(map.get(&50), Some(&100));
+        assert_eq!(map.len(), 100);
+    }
+
+    #[test_case]
+    fn memory_is_reused() {
+        const SIZE: usize = HEAP_SIZE / 128;
+        let before = free_bytes();
+        // Twice the heap in all, which only fits if every block is given back.
+        for i in 0..256 {
+            let x = vec![i as u8; SIZE];
+            assert_eq!(x[SIZE - 1], i as u8);
+        }
+        assert_eq!(free_bytes(), before);
+    }
+
+    #[test_case]
+    fn alignment_is_respected() {
+        let layout = Layout::from_size_align(24, 4096).unwrap();
+        let before = free_bytes();
+        unsafe {
+            let ptr = ALLOCATOR.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 4096, 0);
+            ALLOCATOR.dealloc(ptr, layout);
+        }
+        assert_eq!(free_bytes(), before);
+    }
+}
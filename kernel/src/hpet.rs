@@ -0,0 +1,448 @@
+//! High Precision Event Timer.
+//!
+//! The HPET, found through the ACPI HPET table, is a page of MMIO registers with a main counter
+//! that counts up at a fixed rate of at least 10 MHz, and up to 32 comparators that each raise
+//! an interrupt when the counter reaches their value, once or (on those that can) periodically.
+//! A 64-bit counter makes a clock source, finer than the tick but slower to read than the TSC.
+//!
+//! Comparators interrupt through the I/O APIC, on one of the GSIs each says it can be routed to
+//! (16 to 23 on QEMU's q35; the legacy replacement routing to IRQ 0 and 8 is not used). Drivers
+//! [`start`] a comparator with an IRQ handler, and [`stop`] it when they are done. With
+//! `tick=hpet` on the command line, comparator 0 drives the tick instead of the PIT.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{
+    acpi::{self, AddressSpace},
+    boot_info,
+    irq::{self, IrqError, IrqHandler, IrqReturn},
+    paging, pit, println,
+    sync::IrqSpinLock,
+    time::{
+        self,
+        clocksource::{self, ClockSource},
+        Duration, Hz,
+    },
+};
+
+const MMIO_SIZE: u64 = 0x400;
+
+// Register offsets.
+const CAPABILITIES: u64 = 0x000;
+const CONFIG: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0f0;
+
+const fn timer_config(n: usize) -> u64 {
+    0x100 + 0x20 * n as u64
+}
+
+const fn timer_comparator(n: usize) -> u64 {
+    0x108 + 0x20 * n as u64
+}
+
+// Capabilities register bits.
+const CAP_TIMERS_SHIFT: u32 = 8;
+const CAP_TIMERS_MASK: u64 = 0x1f;
+const CAP_COUNTER_64: u64 = 1 << 13;
+const CAP_PERIOD_SHIFT: u32 = 32;
+
+// Configuration register bits.
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+// Timer configuration register bits.
+const TIMER_LEVEL: u64 = 1 << 1;
+const TIMER_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+/// Lets the next comparator write set the counter's value rather than the period.
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32_BIT: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u32 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+const TIMER_FSB: u64 = 1 << 14;
+const TIMER_ROUTE_CAP_SHIFT: u32 = 32;
+
+/// Longest counter period the specification allows, 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+/// Most comparators an HPET can have.
+pub const MAX_COMPARATORS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Fires once, and again on each [`rearm`].
+    #[cfg(test)]
+    OneShot,
+    Periodic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    NoHpet,
+    NoComparator(usize),
+    /// The comparator was started, and hasn't been stopped since.
+    Busy(usize),
+    NotStarted(usize),
+    NotPeriodic(usize),
+    /// None of the GSIs the comparator can be routed to is usable.
+    NoRoute(usize),
+    Irq(IrqError),
+}
+
+impl fmt::Display for HpetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HpetError::NoHpet => write!(f, "no HPET"),
+            HpetError::NoComparator(n) => write!(f, "no comparator {}", n),
+            HpetError::Busy(n) => write!(f, "comparator {} is in use", n),
+            HpetError::NotStarted(n) => write!(f, "comparator {} is not started", n),
+            HpetError::NotPeriodic(n) => write!(f, "comparator {} can't be periodic", n),
+            HpetError::NoRoute(n) => write!(f, "comparator {} has no usable GSI", n),
+            HpetError::Irq(err) => write!(f, "{:?}", err),
+        }
+    }
+}
+
+pub struct Hpet {
+    base: VirtAddr,
+    /// Of the main counter, in femtoseconds.
+    period_fs: u64,
+    comparators: usize,
+    counter_64: bool,
+}
+
+impl Hpet {
+    fn read(&self, reg: u64) -> u64 {
+        unsafe { (self.base + reg).as_ptr::<u64>().read_volatile() }
+    }
+
+    fn write(&self, reg: u64, value: u64) {
+        unsafe { (self.base + reg).as_mut_ptr::<u64>().write_volatile(value) }
+    }
+
+    /// Reads the main counter. A 32-bit counter wraps every few minutes.
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    /// Main counter counts since it read `start`, allowing for a 32-bit counter wrapping.
+    pub fn counts_since(&self, start: u64) -> u64 {
+        let counts = self.counter().wrapping_sub(start);
+        if self.counter_64 {
+            counts
+        } else {
+            counts as u32 as u64
+        }
+    }
+
+    /// Counts per second.
+    pub fn frequency(&self) -> u64 {
+        FEMTOS_PER_SEC / self.period_fs
+    }
+
+    pub fn comparators(&self) -> usize {
+        self.comparators
+    }
+
+    /// Main counter counts in `duration`, at least 1.
+    fn counts(&self, duration: Duration) -> u64 {
+        let counts = duration.as_nanos() * FEMTOS_PER_NANO / self.period_fs as u128;
+        counts.clamp(1, u64::MAX as u128) as u64
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+/// The HPET, if [`init`] found one.
+pub fn get() -> Option<&'static Hpet> {
+    HPET.get()
+}
+
+#[derive(Clone, Copy)]
+struct Started {
+    line: u8,
+    handler: IrqHandler,
+    ctx: usize,
+}
+
+static STARTED: IrqSpinLock<[Option<Started>; MAX_COMPARATORS]> =
+    IrqSpinLock::new([None; MAX_COMPARATORS]);
+
+/// Starts comparator `n` interrupting after `interval`, once or every `interval` after that,
+/// with `handler` registered on its IRQ line as `name`. Returns the line, a GSI that other
+/// comparators may share if they have to.
+pub fn start(
+    n: usize,
+    mode: Mode,
+    interval: Duration,
+    name: &'static str,
+    handler: IrqHandler,
+    ctx: usize,
+) -> Result<u8, HpetError> {
+    let hpet = get().ok_or(HpetError::NoHpet)?;
+    if n >= hpet.comparators {
+        return Err(HpetError::NoComparator(n));
+    }
+    let config = hpet.read(timer_config(n));
+    if mode == Mode::Periodic && config & TIMER_PERIODIC_CAP == 0 {
+        return Err(HpetError::NotPeriodic(n));
+    }
+
+    let mut started = STARTED.lock();
+    if started[n].is_some() {
+        return Err(HpetError::Busy(n));
+    }
+    // Lines past the ISA ones are free for the taking; prefer one no other comparator uses.
+    let route_cap = config >> TIMER_ROUTE_CAP_SHIFT;
+    let routable = |gsi: &usize| route_cap & (1 << gsi) != 0;
+    let in_use = |gsi: &usize| started.iter().flatten().any(|s| s.line as usize == *gsi);
+    let line = (irq::ISA_LINES..irq::IRQ_LINES)
+        .filter(routable)
+        .find(|gsi| !in_use(gsi))
+        .or_else(|| (irq::ISA_LINES..irq::IRQ_LINES).find(routable))
+        .ok_or(HpetError::NoRoute(n))? as u8;
+    irq::register(line, name, handler, ctx).map_err(HpetError::Irq)?;
+    started[n] = Some(Started { line, handler, ctx });
+
+    // Program it stopped, then enable it, so it doesn't fire on a stale comparator value.
+    let counts = hpet.counts(interval);
+    let mut config = config
+        & !(TIMER_ENABLE
+            | TIMER_LEVEL
+            | TIMER_PERIODIC
+            | TIMER_32_BIT
+            | TIMER_FSB
+            | TIMER_ROUTE_MASK);
+    config |= (line as u64) << TIMER_ROUTE_SHIFT;
+    match mode {
+        #[cfg(test)]
+        Mode::OneShot => {
+            hpet.write(timer_config(n), config);
+            hpet.write(timer_comparator(n), hpet.counter().wrapping_add(counts));
+        }
+        Mode::Periodic => {
+            config |= TIMER_PERIODIC;
+            hpet.write(timer_config(n), config | TIMER_VALUE_SET);
+            // The first write sets the comparator, the second the period.
+            hpet.write(timer_comparator(n), hpet.counter().wrapping_add(counts));
+            hpet.write(timer_comparator(n), counts);
+        }
+    }
+    hpet.write(timer_config(n), config | TIMER_ENABLE);
+    Ok(line)
+}
+
+/// Fires one-shot comparator `n` again, `delay` from now.
+#[cfg(test)]
+pub fn rearm(n: usize, delay: Duration) -> Result<(), HpetError> {
+    let hpet = get().ok_or(HpetError::NoHpet)?;
+    if STARTED.lock().get(n).copied().flatten().is_none() {
+        return Err(HpetError::NotStarted(n));
+    }
+    let deadline = hpet.counter().wrapping_add(hpet.counts(delay));
+    hpet.write(timer_comparator(n), deadline);
+    Ok(())
+}
+
+/// Stops comparator `n`, and unregisters its handler.
+pub fn stop(n: usize) -> Result<(), HpetError> {
+    let hpet = get().ok_or(HpetError::NoHpet)?;
+    let started = STARTED
+        .lock()
+        .get_mut(n)
+        .and_then(Option::take)
+        .ok_or(HpetError::NotStarted(n))?;
+    let config = hpet.read(timer_config(n));
+    hpet.write(timer_config(n), config & !(TIMER_ENABLE | TIMER_PERIODIC));
+    irq::unregister(started.line, started.handler, started.ctx).map_err(HpetError::Irq)
+}
+
+struct Counter;
+
+impl ClockSource for Counter {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn frequency(&self) -> u64 {
+        get().map_or(0, Hpet::frequency)
+    }
+
+    fn read(&self) -> u64 {
+        get().map_or(0, Hpet::counter)
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+}
+
+/// Whether comparator 0 drives the tick.
+static TICK: AtomicBool = AtomicBool::new(false);
+
+fn tick(_ctx: usize) -> IrqReturn {
+    time::tick();
+    IrqReturn::Handled
+}
+
+/// Moves the tick from the PIT to comparator 0, at the same rate.
+fn take_over_tick() {
+    match start(0, Mode::Periodic, time::tick_period(), "hpet-tick", tick, 0) {
+        Ok(line) => {
+            pit::stop();
+            TICK.store(true, Ordering::Relaxed);
+            println!("Tick: HPET comparator 0, IRQ {}", line);
+        }
+        Err(err) => {
+            println!("Tick: staying on the PIT, {}", err);
+        }
+    }
+}
+
+/// Stops comparator 0 if it drives the tick, for when the tick goes away.
+pub fn stop_tick() {
+    if TICK.swap(false, Ordering::Relaxed) {
+        stop(0).ok();
+    }
+}
+
+/// Finds the HPET and starts its main counter. Must run after [`apic::init`](crate::apic::init)
+/// for the comparators to be routed.
+pub fn init() {
+    let Some(table) = acpi::get().and_then(|acpi| acpi.hpet.as_ref()) else {
+        println!("HPET: not present");
+        return;
+    };
+    if table.base.space != AddressSpace::SystemMemory {
+        println!("HPET: not memory mapped");
+        return;
+    }
+    let base = paging::map_mmio(PhysAddr::new(table.base.address), MMIO_SIZE);
+    let capabilities = unsafe { (base + CAPABILITIES).as_ptr::<u64>().read_volatile() };
+    let period_fs = capabilities >> CAP_PERIOD_SHIFT;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        println!("HPET: invalid counter period {} fs", period_fs);
+        return;
+    }
+    let hpet = HPET.call_once(|| Hpet {
+        base,
+        period_fs,
+        comparators: ((capabilities >> CAP_TIMERS_SHIFT) & CAP_TIMERS_MASK) as usize + 1,
+        counter_64: capabilities & CAP_COUNTER_64 != 0,
+    });
+
+    // Whatever the firmware left running is stopped, and the counter restarted from zero.
+    let config = hpet.read(CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+    hpet.write(CONFIG, config);
+    for n in 0..hpet.comparators {
+        let timer = hpet.read(timer_config(n));
+        hpet.write(timer_config(n), timer & !(TIMER_ENABLE | TIMER_PERIODIC));
+    }
+    hpet.write(MAIN_COUNTER, 0);
+    hpet.write(CONFIG, config | CONFIG_ENABLE);
+
+    println!(
+        "HPET: {} comparators, {}, {}-bit counter",
+        hpet.comparators(),
+        Hz(hpet.frequency()),
+        if hpet.counter_64 { 64 } else { 32 }
+    );
+    if hpet.counter_64 {
+        clocksource::register(&Counter);
+    }
+    if boot_info::boot_info().option("tick") == Some("hpet") {
+        take_over_tick();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    use super::*;
+
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(_ctx: usize) -> IrqReturn {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+        IrqReturn::Handled
+    }
+
+    /// A comparator the tick doesn't use, which can run in `mode`.
+    fn free_comparator(hpet: &Hpet, mode: Mode) -> Option<usize> {
+        (1..hpet.comparators).find(|&n| {
+            mode == Mode::OneShot || hpet.read(timer_config(n)) & TIMER_PERIODIC_CAP != 0
+        })
+    }
+
+    #[test_case]
+    fn counter_runs_at_its_frequency() {
+        let Some(hpet) = get() else {
+            return;
+        };
+        // A busy-wait only ever overshoots, so this can come out a little high, but never low.
+        let counted = without_interrupts(|| {
+            let start = hpet.counter();
+            time::udelay(10_000);
+            hpet.counts_since(start) * 100
+        });
+        assert!(counted >= hpet.frequency() * 99 / 100, "{} Hz", counted);
+        assert!(counted <= hpet.frequency() * 11 / 10, "{} Hz", counted);
+    }
+
+    #[test_case]
+    fn one_shot_comparator_fires_once() {
+        let Some(n) = get().and_then(|hpet| free_comparator(hpet, Mode::OneShot)) else {
+            return;
+        };
+        FIRED.store(0, Ordering::SeqCst);
+        let interval = Duration::from_millis(5);
+        match start(n, Mode::OneShot, interval, "hpet-test", count, 0) {
+            Err(HpetError::NoRoute(_)) => return,
+            result => assert!(result.is_ok(), "{:?}", result),
+        }
+        assert_eq!(
+            start(n, Mode::OneShot, interval, "hpet-test", count, 0),
+            Err(HpetError::Busy(n))
+        );
+        time::sleep(Duration::from_millis(30));
+        assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+
+        rearm(n, interval).unwrap();
+        time::sleep(Duration::from_millis(30));
+        assert_eq!(FIRED.load(Ordering::SeqCst), 2);
+        stop(n).unwrap();
+        assert_eq!(stop(n), Err(HpetError::NotStarted(n)));
+    }
+
+    #[test_case]
+    fn periodic_comparator_fires_repeatedly() {
+        let Some(n) = get().and_then(|hpet| free_comparator(hpet, Mode::Periodic)) else {
+            return;
+        };
+        FIRED.store(0, Ordering::SeqCst);
+        let interval = Duration::from_millis(2);
+        match start(n, Mode::Periodic, interval, "hpet-test", count, 0) {
+            Err(HpetError::NoRoute(_)) => return,
+            result => assert!(result.is_ok(), "{:?}", result),
+        }
+        time::sleep(Duration::from_millis(50));
+        stop(n).unwrap();
+        let fired = FIRED.load(Ordering::SeqCst);
+        assert!(fired >= 10, "{} interrupts", fired);
+
+        time::sleep(Duration::from_millis(10));
+        assert_eq!(FIRED.load(Ordering::SeqCst), fired);
+    }
+}
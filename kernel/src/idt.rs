@@ -1,14 +1,23 @@
-use core::ops::{Deref, DerefMut, Index, IndexMut};
+use core::{
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{compiler_fence, Ordering},
+};
 
-use spin::lazy::Lazy;
+use spin::Mutex;
+#[cfg(test)]
+use x86_64::structures::idt::{
+    DivergingHandlerFunc, DivergingHandlerFuncWithErrCode, HandlerFuncWithErrCode,
+    PageFaultHandlerFunc,
+};
 use x86_64::{
-    instructions::port::Port,
-    structures::idt::{
-        Entry, HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
-    },
+    instructions::interrupts::without_interrupts,
+    structures::idt::{Entry, EntryOptions, HandlerFunc, InterruptDescriptorTable},
+    PrivilegeLevel, VirtAddr,
 };
 
-use crate::{gdt, pic, println};
+use crate::{exception, gdt, irq};
 
 #[repr(u8)]
 #[allow(dead_code)]
@@ -48,285 +57,399 @@ pub enum InterruptIndex {
     SecurityException = 30,
     Reserved31 = 31, // reserved
 
-    // Interrupts
-    Timer = pic::PIC_1_OFFSET,
+    // Hardware IRQs, see irq.rs
+    Timer = irq::IRQ_BASE,
     Keyboard,
-    MaybeSpurious = 39,
+    MaybeSpurious = irq::IRQ_BASE + 7,
 }
 
-pub struct IdtBuilder(InterruptDescriptorTable);
+impl InterruptIndex {
+    /// The CPU exceptions, in vector order, leaving out the reserved vectors.
+    pub const EXCEPTIONS: [InterruptIndex; 24] = {
+        use InterruptIndex::*;
+        [
+            Divide,
+            Debug,
+            NonMaskable,
+            Breakpoint,
+            Overflow,
+            BoundRangeExceeded,
+            InvalidOpcode,
+            DeviceNotAvailable,
+            DoubleFault,
+            CoprocessorSegmentOverrun,
+            InvalidTss,
+            SegmentNotPresent,
+            StackSegmentFault,
+            GeneralProtectionFault,
+            PageFault,
+            X87FloatingPoint,
+            AlignmentCheck,
+            MachineCheck,
+            SimdFloatingPoint,
+            Virtualization,
+            CPProtectionException,
+            HypervisorInjectionException,
+            VMMCommunicationException,
+            SecurityException,
+        ]
+    };
 
-impl IdtBuilder {
-    pub fn new() -> Self {
-        Self(InterruptDescriptorTable::new())
+    pub const fn vector(self) -> u8 {
+        self as u8
     }
 
-    pub fn into_inner(self) -> InterruptDescriptorTable {
-        self.0
+    /// The kind of handler the CPU expects for this vector.
+    #[cfg(test)]
+    pub const fn kind(self) -> Option<HandlerKind> {
+        use InterruptIndex::*;
+        match self {
+            DoubleFault => Some(HandlerKind::DivergingWithErrorCode),
+            MachineCheck => Some(HandlerKind::Diverging),
+            PageFault => Some(HandlerKind::PageFault),
+            InvalidTss
+            | SegmentNotPresent
+            | StackSegmentFault
+            | GeneralProtectionFault
+            | AlignmentCheck
+            | CPProtectionException
+            | VMMCommunicationException
+            | SecurityException => Some(HandlerKind::WithErrorCode),
+            Reserved15 | Reserved22 | Reserved23 | Reserved24 | Reserved25 | Reserved26
+            | Reserved27 | Reserved31 => None,
+            _ => Some(HandlerKind::Plain),
+        }
     }
 }
 
-impl Deref for IdtBuilder {
-    type Target = InterruptDescriptorTable;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
+/// The shapes of handler function an IDT entry can have.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandlerKind {
+    Plain,
+    WithErrorCode,
+    Diverging,
+    DivergingWithErrorCode,
+    PageFault,
 }
 
-impl DerefMut for IdtBuilder {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
+/// A handler for any vector, whatever its signature.
+///
+/// `IdtBuilder::set` checks the kind against the vector, so handlers for vectors with error
+/// codes no longer need to go through the named fields of [`InterruptDescriptorTable`].
+///
+/// [`build`] doesn't use it: every exception goes through the assembly stubs in exception.rs,
+/// which save all registers for the common handler and so have no Rust signature to check.
+#[cfg(test)]
+#[derive(Clone, Copy)]
+pub enum Handler {
+    Plain(HandlerFunc),
+    WithErrorCode(HandlerFuncWithErrCode),
+    Diverging(DivergingHandlerFunc),
+    DivergingWithErrorCode(DivergingHandlerFuncWithErrCode),
+    PageFault(PageFaultHandlerFunc),
 }
 
-impl Index<InterruptIndex> for IdtBuilder {
-    type Output = Entry<HandlerFunc>;
-
-    fn index(&self, index: InterruptIndex) -> &Self::Output {
-        &self.0[index as usize]
+#[cfg(test)]
+impl Handler {
+    pub fn kind(self) -> HandlerKind {
+        match self {
+            Handler::Plain(_) => HandlerKind::Plain,
+            Handler::WithErrorCode(_) => HandlerKind::WithErrorCode,
+            Handler::Diverging(_) => HandlerKind::Diverging,
+            Handler::DivergingWithErrorCode(_) => HandlerKind::DivergingWithErrorCode,
+            Handler::PageFault(_) => HandlerKind::PageFault,
+        }
     }
-}
 
-impl IndexMut<InterruptIndex> for IdtBuilder {
-    fn index_mut(&mut self, index: InterruptIndex) -> &mut Self::Output {
-        &mut self.0[index as usize]
+    fn addr(self) -> VirtAddr {
+        let addr = match self {
+            Handler::Plain(f) => f as usize,
+            Handler::WithErrorCode(f) => f as usize,
+            Handler::Diverging(f) => f as usize,
+            Handler::DivergingWithErrorCode(f) => f as usize,
+            Handler::PageFault(f) => f as usize,
+        };
+        VirtAddr::new(addr as u64)
     }
 }
 
-extern "x86-interrupt" fn double_fault(stack_frame: InterruptStackFrame, _error: u64) -> ! {
-    println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-    loop {}
-}
-
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn timer(_stack_frame: InterruptStackFrame) {
-    pic::end_interrupt(InterruptIndex::Timer as u8);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdtError {
+    /// Vectors below 32 belong to the CPU and are set up by [`init`].
+    Exception(u8),
+    /// Somebody already registered a handler for the vector.
+    InUse(u8),
+    /// Nothing is registered for the vector.
+    #[cfg(test)]
+    NotRegistered(u8),
 }
 
-extern "x86-interrupt" fn keyboard(_stack_frame: InterruptStackFrame) {
-    let mut port: Port<u8> = Port::new(0x60);
-
-    let scancode: u8 = unsafe { port.read() };
-
-    let _key = match scancode {
-        0x02..=0x0b => Some(b"1234567890"[scancode as usize - 0x02] as char),
-        0x10..=0x19 => Some(b"qwertyuiop"[scancode as usize - 0x10] as char),
-
-        0x1e..=0x26 => Some(b"asdfghjkl"[scancode as usize - 0x1e] as char),
-        0x2c..=0x32 => Some(b"zxcvbnm"[scancode as usize - 0x2c] as char),
-        0x39 => Some(' '),
-        0xF => Some(' '), // Tab
-        _ => None,
-    };
-    // if let Some(key) = key {
-    //     print!("{}", key);
-    // }
-    // else {
-    //     println!("Unknown key: 0x{:0X}", scancode);
-    // }
-    pic::end_interrupt(InterruptIndex::Keyboard as u8);
-}
+pub struct IdtBuilder(InterruptDescriptorTable);
 
-extern "x86-interrupt" fn divide_handler(_stack_frame: InterruptStackFrame) {
-    println!("Divide by zero");
+impl IdtBuilder {
+    pub fn new() -> Self {
+        Self(InterruptDescriptorTable::new())
+    }
 
-    pic::end_interrupt(InterruptIndex::Divide as u8);
-}
+    /// Installs `handler` for `index`, returning the entry's options so that the IST index and
+    /// DPL can be set. Panics if the handler's signature doesn't fit the vector.
+    #[cfg(test)]
+    pub fn set(&mut self, index: InterruptIndex, handler: Handler) -> &mut EntryOptions {
+        assert_eq!(
+            index.kind(),
+            Some(handler.kind()),
+            "Wrong kind of handler for {:?}",
+            index
+        );
+        // The kind matches the entry's handler type, so the address is a valid handler.
+        unsafe { set_addr(&mut self.0, index.vector(), handler.addr()) }
+    }
 
-extern "x86-interrupt" fn debug_handler(_stack_frame: InterruptStackFrame) {
-    println!("Debug");
+    /// Installs the raw entry point at `addr` for `index`.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must point to code that handles the interrupt's stack frame (and error code, if
+    /// any) correctly and returns with `iretq`, if it returns at all.
+    pub unsafe fn set_addr(&mut self, index: InterruptIndex, addr: VirtAddr) -> &mut EntryOptions {
+        set_addr(&mut self.0, index.vector(), addr)
+    }
 
-    pic::end_interrupt(InterruptIndex::Debug as u8);
+    pub fn into_inner(self) -> InterruptDescriptorTable {
+        self.0
+    }
 }
 
-extern "x86-interrupt" fn non_maskable_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    println!("Non-maskable interrupt");
+impl Deref for IdtBuilder {
+    type Target = InterruptDescriptorTable;
 
-    pic::end_interrupt(InterruptIndex::NonMaskable as u8);
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
 }
 
-extern "x86-interrupt" fn overflow_handler(_stack_frame: InterruptStackFrame) {
-    println!("Overflow");
-
-    pic::end_interrupt(InterruptIndex::Overflow as u8);
+impl DerefMut for IdtBuilder {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
 }
 
-extern "x86-interrupt" fn bound_range_exceeded_handler(_stack_frame: InterruptStackFrame) {
-    println!("Bound range exceeded");
-
-    pic::end_interrupt(InterruptIndex::BoundRangeExceeded as u8);
+/// Points the entry for `vector` at `addr`, going through the named fields for the exceptions
+/// whose entries have a handler type other than [`HandlerFunc`].
+unsafe fn set_addr(
+    idt: &mut InterruptDescriptorTable,
+    vector: u8,
+    addr: VirtAddr,
+) -> &mut EntryOptions {
+    match vector {
+        8 => idt.double_fault.set_handler_addr(addr),
+        10 => idt.invalid_tss.set_handler_addr(addr),
+        11 => idt.segment_not_present.set_handler_addr(addr),
+        12 => idt.stack_segment_fault.set_handler_addr(addr),
+        13 => idt.general_protection_fault.set_handler_addr(addr),
+        14 => idt.page_fault.set_handler_addr(addr),
+        17 => idt.alignment_check.set_handler_addr(addr),
+        18 => idt.machine_check.set_handler_addr(addr),
+        21 => idt.cp_protection_exception.set_handler_addr(addr),
+        29 => idt.vmm_communication_exception.set_handler_addr(addr),
+        30 => idt.security_exception.set_handler_addr(addr),
+        vector => idt[vector as usize].set_handler_addr(addr),
+    }
 }
 
-extern "x86-interrupt" fn invalid_opcode_handler(_stack_frame: InterruptStackFrame) {
-    println!("Invalid opcode");
+static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 
-    pic::end_interrupt(InterruptIndex::InvalidOpcode as u8);
-}
-
-extern "x86-interrupt" fn device_not_available_handler(_stack_frame: InterruptStackFrame) {
-    println!("Device not available");
+/// Serializes runtime changes to [`IDT`].
+static REGISTER_LOCK: Mutex<()> = Mutex::new(());
 
-    pic::end_interrupt(InterruptIndex::DeviceNotAvailable as u8);
-}
+fn build() -> InterruptDescriptorTable {
+    use InterruptIndex::*;
+    let mut idt = IdtBuilder::new();
 
-extern "x86-interrupt" fn invalid_tss_handler(_stack_frame: InterruptStackFrame, _error: u64) {
-    println!("Invalid TSS");
+    // All CPU exceptions go through the common path in exception.rs.
+    for index in InterruptIndex::EXCEPTIONS {
+        let options = unsafe { idt.set_addr(index, exception::stub(index.vector())) };
+        match index {
+            DoubleFault => unsafe {
+                options.set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            },
+            // Lets `int3` work from user mode.
+            Breakpoint => {
+                options.set_privilege_level(PrivilegeLevel::Ring3);
+            }
+            _ => {}
+        }
+    }
 
-    pic::end_interrupt(InterruptIndex::InvalidTss as u8);
+    idt.into_inner()
 }
 
-extern "x86-interrupt" fn segment_not_present_handler(
-    _stack_frame: InterruptStackFrame,
-    _error: u64,
-) {
-    // println!("Segment not present");
-
-    pic::end_interrupt(InterruptIndex::SegmentNotPresent as u8);
+pub fn init() {
+    unsafe {
+        *addr_of_mut!(IDT) = build();
+    }
+    load();
 }
 
-extern "x86-interrupt" fn stack_segment_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    _error: u64,
-) {
-    // println!("Stack segment fault");
-
-    pic::end_interrupt(InterruptIndex::StackSegmentFault as u8);
+/// Loads the IDT on the current CPU. All CPUs share the one built by [`init`].
+pub fn load() {
+    unsafe {
+        (*addr_of!(IDT)).load();
+    }
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error: u64,
-) {
-    println!("General protection fault: {:?}", stack_frame);
-    println!("Error code: {}", error);
-
-    pic::end_interrupt(InterruptIndex::GeneralProtectionFault as u8);
-    // pic::end_interrupt(13);
-    loop {
-        //     // x86_64::instructions::hlt();
+/// Index of the options word, which has the present bit, in an entry seen as `u16`s.
+const OPTIONS_WORD: usize = 2;
+const OPTIONS_PRESENT: u16 = 1 << 15;
+
+/// Overwrites an entry of the live IDT, which other CPUs may be using, so that none of them
+/// can see a present entry that is part old and part new: the entry is marked not present,
+/// then everything but the options word is written, and the options word goes in last. x86
+/// doesn't reorder stores, so the fences are only there to keep the compiler from doing so.
+///
+/// # Safety
+///
+/// `new` must be missing, or point at a handler for the entry's vector.
+unsafe fn write_live(entry: &mut Entry<HandlerFunc>, new: Entry<HandlerFunc>) {
+    const WORDS: usize = mem::size_of::<Entry<HandlerFunc>>() / 2;
+    let dst = entry as *mut Entry<HandlerFunc> as *mut u16;
+    let src = &new as *const Entry<HandlerFunc> as *const u16;
+    unsafe {
+        let options = dst.add(OPTIONS_WORD).read_volatile();
+        dst.add(OPTIONS_WORD)
+            .write_volatile(options & !OPTIONS_PRESENT);
+        compiler_fence(Ordering::SeqCst);
+        for word in (0..WORDS).filter(|&word| word != OPTIONS_WORD) {
+            dst.add(word).write_volatile(src.add(word).read());
+        }
+        compiler_fence(Ordering::SeqCst);
+        dst.add(OPTIONS_WORD)
+            .write_volatile(src.add(OPTIONS_WORD).read());
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    _error: PageFaultErrorCode,
-) {
-    // println!("Page fault: {:?}", stack_frame);
-    // loop {
-    //     // x86_64::instructions::hlt();
-    // }
-
-    pic::end_interrupt(InterruptIndex::PageFault as u8);
+fn check_vector(vector: u8) -> Result<(), IdtError> {
+    if vector < 32 {
+        Err(IdtError::Exception(vector))
+    } else {
+        Ok(())
+    }
 }
 
-extern "x86-interrupt" fn x87_floating_point_handler(_stack_frame: InterruptStackFrame) {
-    // println!("x87 floating point");
-
-    pic::end_interrupt(InterruptIndex::X87FloatingPoint as u8);
+/// Installs `handler` for `vector` in the live IDT. See [`register_with`].
+pub fn register(vector: u8, handler: HandlerFunc) -> Result<(), IdtError> {
+    register_with(vector, handler, |_| {})
 }
 
-extern "x86-interrupt" fn alignment_check_handler(_stack_frame: InterruptStackFrame, _error: u64) {
-    // println!("Alignment check");
-
-    pic::end_interrupt(InterruptIndex::AlignmentCheck as u8);
+/// Installs `handler` for `vector` in the live IDT, letting `configure` set the entry's IST
+/// index, DPL and so on before it takes effect. Only vectors 32-255 can be registered, and
+/// only if nothing else has claimed them.
+pub fn register_with(
+    vector: u8,
+    handler: HandlerFunc,
+    configure: impl FnOnce(&mut EntryOptions),
+) -> Result<(), IdtError> {
+    check_vector(vector)?;
+    without_interrupts(|| {
+        let _guard = REGISTER_LOCK.lock();
+        let idt = unsafe { &mut *addr_of_mut!(IDT) };
+        let entry = &mut idt[vector as usize];
+        if entry.handler_addr().as_u64() != 0 {
+            return Err(IdtError::InUse(vector));
+        }
+
+        let mut new = Entry::missing();
+        configure(new.set_handler_fn(handler));
+        unsafe { write_live(entry, new) };
+        Ok(())
+    })
 }
 
-extern "x86-interrupt" fn machine_check_handler(_stack_frame: InterruptStackFrame) -> ! {
-    // println!("Machine check");
-    loop {
-        x86_64::instructions::hlt();
-    }
-
-    // pic::end_interrupt(InterruptIndex::MachineCheck as u8);
+/// Removes the handler for `vector` from the live IDT. Every vector registered so far stays
+/// for good, so only the tests call this.
+#[cfg(test)]
+pub fn unregister(vector: u8) -> Result<(), IdtError> {
+    check_vector(vector)?;
+    without_interrupts(|| {
+        let _guard = REGISTER_LOCK.lock();
+        let idt = unsafe { &mut *addr_of_mut!(IDT) };
+        let entry = &mut idt[vector as usize];
+        if entry.handler_addr().as_u64() == 0 {
+            return Err(IdtError::NotRegistered(vector));
+        }
+        unsafe { write_live(entry, Entry::missing()) };
+        Ok(())
+    })
 }
 
-extern "x86-interrupt" fn simd_floating_point_handler(_stack_frame: InterruptStackFrame) {
-    // println!("SIMD floating point");
-
-    pic::end_interrupt(InterruptIndex::SimdFloatingPoint as u8);
-}
+#[cfg(test)]
+mod tests {
+    use core::{
+        arch::asm,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
 
-extern "x86-interrupt" fn virtualization_handler(_stack_frame: InterruptStackFrame) {
-    // println!("Virtualization");
+    use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
 
-    pic::end_interrupt(InterruptIndex::Virtualization as u8);
-}
+    use super::*;
 
-extern "x86-interrupt" fn security_exception_handler(
-    _stack_frame: InterruptStackFrame,
-    _error: u64,
-) {
-    // println!("Security exception");
+    const TEST_VECTOR: u8 = 0xf0;
 
-    pic::end_interrupt(InterruptIndex::SecurityException as u8);
-}
+    static HITS: AtomicUsize = AtomicUsize::new(0);
 
-extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    if (pic::read_isr() & (1 << 7)) != 0 {
-        // Spurious interrupt
-        pic::end_interrupt(InterruptIndex::MaybeSpurious as u8);
+    extern "x86-interrupt" fn count_hit(_stack_frame: InterruptStackFrame) {
+        HITS.fetch_add(1, Ordering::SeqCst);
     }
-}
 
-static mut IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
-    use InterruptIndex::*;
-    let mut idt = IdtBuilder::new();
+    #[test_case]
+    fn runtime_registration() {
+        HITS.store(0, Ordering::SeqCst);
+        register(TEST_VECTOR, count_hit).unwrap();
+        assert_eq!(
+            register(TEST_VECTOR, count_hit),
+            Err(IdtError::InUse(TEST_VECTOR))
+        );
+
+        unsafe { asm!("int {}", const TEST_VECTOR) };
+        assert_eq!(HITS.load(Ordering::SeqCst), 1);
+
+        unregister(TEST_VECTOR).unwrap();
+        assert_eq!(
+            unregister(TEST_VECTOR),
+            Err(IdtError::NotRegistered(TEST_VECTOR))
+        );
+    }
 
-    idt[Divide].set_handler_fn(divide_handler);
-    idt[Breakpoint].set_handler_fn(breakpoint_handler);
-    idt[Debug].set_handler_fn(debug_handler);
-    idt[NonMaskable].set_handler_fn(non_maskable_interrupt_handler);
-    idt[Overflow].set_handler_fn(overflow_handler);
-    idt[BoundRangeExceeded].set_handler_fn(bound_range_exceeded_handler);
-    idt[InvalidOpcode].set_handler_fn(invalid_opcode_handler);
-    idt[DeviceNotAvailable].set_handler_fn(device_not_available_handler);
-    idt[X87FloatingPoint].set_handler_fn(x87_floating_point_handler);
-    idt[SimdFloatingPoint].set_handler_fn(simd_floating_point_handler);
-    idt[Virtualization].set_handler_fn(virtualization_handler);
-
-    // TODO: come up with an interface that allows setting both kinds of interrupts
-
-    // [AlignmentCheck]
-    idt.alignment_check.set_handler_fn(alignment_check_handler);
-    // [MachineCheck]
-    idt.machine_check.set_handler_fn(machine_check_handler);
-    // [SegmentNotPresent]
-    idt.segment_not_present
-        .set_handler_fn(segment_not_present_handler);
-    // [StackSegmentFault]
-    idt.stack_segment_fault
-        .set_handler_fn(stack_segment_fault_handler);
-    // [GeneralProtectionFault]
-    idt.general_protection_fault
-        .set_handler_fn(general_protection_fault_handler);
-    // [PageFault]
-    idt.page_fault.set_handler_fn(page_fault_handler);
-    // [SecurityException]
-    idt.security_exception
-        .set_handler_fn(security_exception_handler);
-    // [InvalidTss]
-    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
-
-    // [DoubleFault]
-    unsafe {
-        idt.double_fault
-            .set_handler_fn(double_fault)
-            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    extern "x86-interrupt" fn page_fault(
+        _stack_frame: InterruptStackFrame,
+        _error_code: PageFaultErrorCode,
+    ) {
     }
 
-    idt[Timer].set_handler_fn(timer);
-    idt[Keyboard].set_handler_fn(keyboard);
-    idt[MaybeSpurious].set_handler_fn(spurious_interrupt_handler);
+    #[test_case]
+    fn typed_handlers_land_in_their_entry() {
+        let mut idt = IdtBuilder::new();
+        idt.set(InterruptIndex::PageFault, Handler::PageFault(page_fault))
+            .set_privilege_level(PrivilegeLevel::Ring3);
+        let idt = idt.into_inner();
+        assert_eq!(
+            idt.page_fault.handler_addr().as_u64(),
+            page_fault as extern "x86-interrupt" fn(_, _) as usize as u64
+        );
+        assert_eq!(idt[0xf0].handler_addr().as_u64(), 0);
+    }
 
-    idt.into_inner()
-});
+    #[test_case]
+    fn exceptions_are_off_limits() {
+        assert_eq!(
+            register(InterruptIndex::PageFault.vector(), count_hit),
+            Err(IdtError::Exception(14))
+        );
+    }
 
-pub fn init() {
-    unsafe {
-        IDT.load();
+    #[test_case]
+    fn breakpoint_returns() {
+        x86_64::instructions::interrupts::int3();
     }
 }
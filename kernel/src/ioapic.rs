@@ -0,0 +1,328 @@
+//! I/O APIC.
+//!
+//! An I/O APIC receives interrupts from devices on its input pins and sends them on to local
+//! APICs, as programmed in its redirection table (one 64-bit entry per pin). Pins are numbered
+//! system wide as global system interrupts (GSIs); each I/O APIC handles the GSIs from its
+//! `gsi_base` on.
+//!
+//! The 16 ISA IRQs are identity mapped to GSIs 0-15 with edge triggered, active high
+//! signalling, except where the firmware says otherwise. Those exceptions ("interrupt source
+//! overrides") and the I/O APICs themselves are normally described by ACPI, which reports them
+//! through [`add`] and [`add_isa_override`]. When nobody did, [`init`] assumes the usual PC
+//! layout: a single I/O APIC at [`DEFAULT_ADDRESS`] with the PIT on GSI 2. Pins wired to NMI,
+//! reported through [`add_nmi_source`], are the only ones [`init`] leaves unmasked.
+
+use alloc::vec::Vec;
+
+use spin::Mutex;
+use x86_64::{instructions::interrupts::without_interrupts, PhysAddr, VirtAddr};
+
+use crate::{paging, println};
+
+pub const DEFAULT_ADDRESS: u64 = 0xfec0_0000;
+
+const MAX_IOAPICS: usize = 8;
+
+// Registers, accessed indirectly through IOREGSEL/IOWIN.
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+// Redirection entry bits.
+const ENTRY_DELIVERY_NMI: u64 = 0b100 << 8;
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+const ENTRY_DESTINATION_SHIFT: u64 = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// Where an ISA IRQ is actually wired to, if not to the GSI with the same number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+impl IsaOverride {
+    const fn identity(irq: u8) -> Self {
+        Self {
+            irq,
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger: TriggerMode::Edge,
+        }
+    }
+}
+
+/// A GSI wired to NMI. NMIs are always sent edge triggered, so there is no trigger mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NmiSource {
+    pub gsi: u32,
+    pub polarity: Polarity,
+}
+
+struct IoApic {
+    id: u8,
+    base: VirtAddr,
+    gsi_base: u32,
+    pins: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            (self.base + IOREGSEL)
+                .as_mut_ptr::<u32>()
+                .write_volatile(reg);
+            (self.base + IOWIN).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            (self.base + IOREGSEL)
+                .as_mut_ptr::<u32>()
+                .write_volatile(reg);
+            (self.base + IOWIN)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value);
+        }
+    }
+
+    fn read_entry(&self, pin: u32) -> u64 {
+        let low = self.read(REG_REDIRECTION + pin * 2);
+        let high = self.read(REG_REDIRECTION + pin * 2 + 1);
+        (high as u64) << 32 | low as u64
+    }
+
+    fn write_entry(&self, pin: u32, entry: u64) {
+        // Mask the pin while it is half written.
+        self.write(REG_REDIRECTION + pin * 2, ENTRY_MASKED as u32);
+        self.write(REG_REDIRECTION + pin * 2 + 1, (entry >> 32) as u32);
+        self.write(REG_REDIRECTION + pin * 2, entry as u32);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.pins
+    }
+}
+
+struct State {
+    ioapics: Vec<IoApic>,
+    isa: [IsaOverride; 16],
+    has_overrides: bool,
+    nmi_sources: Vec<NmiSource>,
+    /// APIC ID that all IRQs are sent to.
+    destination: u32,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    ioapics: Vec::new(),
+    isa: {
+        let mut isa = [IsaOverride::identity(0); 16];
+        let mut irq = 0;
+        while irq < 16 {
+            isa[irq] = IsaOverride::identity(irq as u8);
+            irq += 1;
+        }
+        isa
+    },
+    has_overrides: false,
+    nmi_sources: Vec::new(),
+    destination: 0,
+});
+
+/// Adds the I/O APIC with registers at `phys` that handles the GSIs from `gsi_base` on.
+pub fn add(id: u8, phys: PhysAddr, gsi_base: u32) {
+    let base = paging::map_mmio(phys, 0x20);
+    let mut ioapic = IoApic {
+        id,
+        base,
+        gsi_base,
+        pins: 0,
+    };
+    ioapic.pins = ((ioapic.read(REG_VERSION) >> 16) & 0xff) + 1;
+
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        assert!(state.ioapics.len() < MAX_IOAPICS, "Too many I/O APICs");
+        state.ioapics.push(ioapic);
+    });
+}
+
+/// Records that ISA IRQ `irq` is wired differently than the default.
+pub fn add_isa_override(isa: IsaOverride) {
+    assert!(isa.irq < 16, "Not an ISA IRQ: {}", isa.irq);
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        state.isa[isa.irq as usize] = isa;
+        state.has_overrides = true;
+    });
+}
+
+/// Records that `nmi.gsi` is wired to NMI.
+pub fn add_nmi_source(nmi: NmiSource) {
+    without_interrupts(|| STATE.lock().nmi_sources.push(nmi));
+}
+
+/// Sets up all I/O APICs with every pin masked but the NMI sources, sending interrupts to the local APIC with ID
+/// `destination`. Returns false if there is no I/O APIC.
+pub fn init(destination: u32) -> bool {
+    let probe_default = without_interrupts(|| STATE.lock().ioapics.is_empty());
+    if probe_default {
+        // Nothing was reported, so look for one where it usually is.
+        add(0, PhysAddr::new(DEFAULT_ADDRESS), 0);
+    }
+
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        // Nothing there reads back as all ones.
+        state
+            .ioapics
+            .retain(|ioapic| ioapic.read(REG_VERSION) != u32::MAX);
+        if state.ioapics.is_empty() {
+            return false;
+        }
+        if !state.has_overrides {
+            state.isa[0].gsi = 2;
+        }
+        state.destination = destination;
+
+        for ioapic in &state.ioapics {
+            for pin in 0..ioapic.pins {
+                ioapic.write_entry(pin, ENTRY_MASKED);
+            }
+            println!(
+                "I/O APIC {} (ID register {:#x}): GSIs {}-{}.",
+                ioapic.id,
+                ioapic.read(REG_ID) >> 24,
+                ioapic.gsi_base,
+                ioapic.gsi_base + ioapic.pins - 1
+            );
+        }
+        for nmi in &state.nmi_sources {
+            let Some(ioapic) = state.ioapics.iter().find(|ioapic| ioapic.handles(nmi.gsi)) else {
+                continue;
+            };
+            let mut entry = ENTRY_DELIVERY_NMI | (destination as u64) << ENTRY_DESTINATION_SHIFT;
+            if nmi.polarity == Polarity::ActiveLow {
+                entry |= ENTRY_ACTIVE_LOW;
+            }
+            ioapic.write_entry(nmi.gsi - ioapic.gsi_base, entry);
+        }
+        true
+    })
+}
+
+/// Runs `f` with the I/O APIC handling ISA IRQ `irq`, and that IRQ's pin on it, if it is
+/// connected.
+fn with_isa_pin<R>(irq: u8, f: impl FnOnce(&IoApic, u32, &IsaOverride, u32) -> R) -> Option<R> {
+    without_interrupts(|| {
+        let state = STATE.lock();
+        let isa = &state.isa[irq as usize];
+        // An IRQ whose GSI was taken over by another IRQ (IRQ 2 by the PIT, usually) isn't
+        // connected to anything.
+        if isa.gsi == irq as u32
+            && state
+                .isa
+                .iter()
+                .any(|other| other.irq != irq && other.gsi == isa.gsi)
+        {
+            return None;
+        }
+        let ioapic = state
+            .ioapics
+            .iter()
+            .find(|ioapic| ioapic.handles(isa.gsi))?;
+        Some(f(ioapic, isa.gsi - ioapic.gsi_base, isa, state.destination))
+    })
+}
+
+/// Points ISA IRQ `irq` at `vector`, masked.
+pub fn route_isa(irq: u8, vector: u8) {
+    with_isa_pin(irq, |ioapic, pin, isa, destination| {
+        let mut entry = vector as u64 | ENTRY_MASKED;
+        if isa.polarity == Polarity::ActiveLow {
+            entry |= ENTRY_ACTIVE_LOW;
+        }
+        if isa.trigger == TriggerMode::Level {
+            entry |= ENTRY_LEVEL;
+        }
+        entry |= (destination as u64) << ENTRY_DESTINATION_SHIFT;
+        ioapic.write_entry(pin, entry);
+    });
+}
+
+pub fn mask_isa(irq: u8) {
+    with_isa_pin(irq, |ioapic, pin, _, _| {
+        ioapic.write_entry(pin, ioapic.read_entry(pin) | ENTRY_MASKED);
+    });
+}
+
+pub fn unmask_isa(irq: u8) {
+    with_isa_pin(irq, |ioapic, pin, _, _| {
+        ioapic.write_entry(pin, ioapic.read_entry(pin) & !ENTRY_MASKED);
+    });
+}
+
+/// Whether ISA `irq` is masked, or has no I/O APIC pin.
+#[cfg(test)]
+pub fn is_isa_masked(irq: u8) -> bool {
+    with_isa_pin(irq, |ioapic, pin, _, _| {
+        ioapic.read_entry(pin) & ENTRY_MASKED != 0
+    })
+    .unwrap_or(true)
+}
+
+/// Runs `f` with the I/O APIC handling `gsi`, and its pin on it, if there is one.
+fn with_gsi_pin<R>(gsi: u32, f: impl FnOnce(&IoApic, u32, u32) -> R) -> Option<R> {
+    without_interrupts(|| {
+        let state = STATE.lock();
+        let ioapic = state.ioapics.iter().find(|ioapic| ioapic.handles(gsi))?;
+        Some(f(ioapic, gsi - ioapic.gsi_base, state.destination))
+    })
+}
+
+/// Points `gsi`, beyond the ISA IRQs, at `vector`, edge triggered and active high, masked.
+pub fn route_gsi(gsi: u32, vector: u8) {
+    with_gsi_pin(gsi, |ioapic, pin, destination| {
+        let entry = vector as u64 | ENTRY_MASKED | (destination as u64) << ENTRY_DESTINATION_SHIFT;
+        ioapic.write_entry(pin, entry);
+    });
+}
+
+pub fn mask_gsi(gsi: u32) {
+    with_gsi_pin(gsi, |ioapic, pin, _| {
+        ioapic.write_entry(pin, ioapic.read_entry(pin) | ENTRY_MASKED);
+    });
+}
+
+pub fn unmask_gsi(gsi: u32) {
+    with_gsi_pin(gsi, |ioapic, pin, _| {
+        ioapic.write_entry(pin, ioapic.read_entry(pin) & !ENTRY_MASKED);
+    });
+}
+
+/// Whether `gsi` is masked, or has no I/O APIC pin.
+#[cfg(test)]
+pub fn is_gsi_masked(gsi: u32) -> bool {
+    with_gsi_pin(gsi, |ioapic, pin, _| {
+        ioapic.read_entry(pin) & ENTRY_MASKED != 0
+    })
+    .unwrap_or(true)
+}
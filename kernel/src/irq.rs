@@ -0,0 +1,471 @@
+//! Hardware IRQ dispatch.
+//!
+//! Each of the 16 legacy IRQ lines gets its own IDT vector (starting at [`IRQ_BASE`]) whose
+//! entry point just calls [`dispatch`] with the line number. Drivers claim a line at runtime with
+//! [`register`]; a line can be shared by up to [`MAX_SHARED`] handlers, which are called in
+//! registration order until one of them reports that the interrupt was meant for it.
+//!
+//! Handlers never send the EOI themselves, the dispatcher does that once they have all run. A
+//! line that keeps firing without anybody handling it is masked after [`UNHANDLED_LIMIT`]
+//! interrupts in a row, so that a stuck device can't keep the CPU busy forever. Lines are also
+//! masked while nobody has registered for them, and unmasked on [`register`].
+//!
+//! Once the I/O APIC delivers IRQs, lines from [`ISA_LINES`] on can be used as well, for the
+//! GSIs with the same numbers, which the I/O APIC has beyond the ISA IRQs. They are edge
+//! triggered and active high, like the ISA ones.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::{instructions::interrupts::without_interrupts, structures::idt::InterruptStackFrame};
+
+use crate::{apic, idt, ioapic, pic, println, sched};
+
+/// Lines the PIC has, one per ISA IRQ.
+pub const ISA_LINES: usize = 16;
+
+/// Lines there are with the I/O APIC, which has 24 pins.
+pub const IRQ_LINES: usize = 24;
+
+/// Vector of IRQ line 0 when the PIC delivers IRQs; the other lines follow it.
+pub const IRQ_BASE: u8 = pic::PIC_1_OFFSET;
+
+/// Vector of IRQ line 0 when the I/O APIC delivers IRQs. Kept apart from the PIC's vectors, so
+/// that spurious interrupts from the (masked) PIC can't be mistaken for real ones.
+pub const IOAPIC_IRQ_BASE: u8 = IRQ_BASE + ISA_LINES as u8;
+
+/// Number of handlers that can share a line.
+pub const MAX_SHARED: usize = 4;
+
+/// Consecutive unhandled interrupts after which a line is masked.
+pub const UNHANDLED_LIMIT: u32 = 100;
+
+/// Whether a handler dealt with the interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    /// The device behind this handler did not raise the interrupt; try the next handler.
+    NotMine,
+}
+
+/// Called in interrupt context, with interrupts disabled, with the `ctx` it was registered
+/// with.
+pub type IrqHandler = fn(ctx: usize) -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidLine(u8),
+    /// All [`MAX_SHARED`] slots on the line are taken.
+    LineFull(u8),
+    NotRegistered(u8),
+}
+
+#[derive(Clone, Copy)]
+struct Action {
+    name: &'static str,
+    handler: IrqHandler,
+    ctx: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    actions: [Option<Action>; MAX_SHARED],
+    /// Unhandled interrupts since the last handled one.
+    unhandled: u32,
+    masked: bool,
+}
+
+impl Line {
+    const fn new() -> Self {
+        Self {
+            actions: [None; MAX_SHARED],
+            unhandled: 0,
+            masked: true,
+        }
+    }
+}
+
+static LINES: Mutex<[Line; IRQ_LINES]> = Mutex::new([Line::new(); IRQ_LINES]);
+
+/// Per-line statistics.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IrqStats {
+    /// Interrupts received, including unhandled and spurious ones.
+    pub count: u64,
+    pub unhandled: u64,
+    pub spurious: u64,
+    pub handlers: usize,
+    pub masked: bool,
+}
+
+struct Counters {
+    count: AtomicU64,
+    unhandled: AtomicU64,
+    spurious: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_COUNTS: Counters = Counters {
+    count: AtomicU64::new(0),
+    unhandled: AtomicU64::new(0),
+    spurious: AtomicU64::new(0),
+};
+
+static COUNTERS: [Counters; IRQ_LINES] = [NO_COUNTS; IRQ_LINES];
+
+/// Set once IRQs are delivered by the I/O APIC instead of the PIC.
+static USING_IOAPIC: AtomicBool = AtomicBool::new(false);
+
+type Stubs<const N: usize> = [extern "x86-interrupt" fn(InterruptStackFrame); N];
+
+macro_rules! irq_stubs {
+    ($entry:ident: $($line:literal),*) => {
+        [$({
+            extern "x86-interrupt" fn stub(_stack_frame: InterruptStackFrame) {
+                $entry($line);
+            }
+            stub
+        }),*]
+    };
+}
+
+static PIC_STUBS: Stubs<ISA_LINES> =
+    irq_stubs!(pic_interrupt: 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+static IOAPIC_STUBS: Stubs<IRQ_LINES> = irq_stubs!(dispatch: 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11,
+    12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23);
+
+fn using_ioapic() -> bool {
+    USING_IOAPIC.load(Ordering::Relaxed)
+}
+
+fn mask_line(line: u8) {
+    if !using_ioapic() {
+        pic::mask(line);
+    } else if (line as usize) < ISA_LINES {
+        ioapic::mask_isa(line);
+    } else {
+        ioapic::mask_gsi(line as u32);
+    }
+}
+
+fn unmask_line(line: u8) {
+    if !using_ioapic() {
+        pic::unmask(line);
+    } else if (line as usize) < ISA_LINES {
+        ioapic::unmask_isa(line);
+    } else {
+        ioapic::unmask_gsi(line as u32);
+    }
+}
+
+/// Whether `line` exists with the interrupt controller in use.
+fn is_valid(line: u8) -> bool {
+    let lines = if using_ioapic() { IRQ_LINES } else { ISA_LINES };
+    (line as usize) < lines
+}
+
+fn end_of_interrupt(line: u8) {
+    if using_ioapic() {
+        apic::eoi();
+    } else {
+        pic::end_interrupt(IRQ_BASE + line);
+    }
+}
+
+/// Installs the entry points for all IRQ lines. Must run after [`idt::init`].
+pub fn init() {
+    for (line, stub) in PIC_STUBS.iter().enumerate() {
+        idt::register(IRQ_BASE + line as u8, *stub).expect("IRQ vector already in use");
+    }
+    for (line, stub) in IOAPIC_STUBS.iter().enumerate() {
+        idt::register(IOAPIC_IRQ_BASE + line as u8, *stub).expect("IRQ vector already in use");
+    }
+}
+
+/// Moves IRQ delivery from the PIC to the I/O APIC, keeping every line's handlers and mask.
+/// Called by [`apic::init`] once the I/O APIC is set up.
+pub fn use_ioapic() {
+    without_interrupts(|| {
+        let lines = LINES.lock();
+        pic::disable();
+        USING_IOAPIC.store(true, Ordering::Relaxed);
+        for (line, state) in lines.iter().enumerate() {
+            let line = line as u8;
+            if (line as usize) < ISA_LINES {
+                ioapic::route_isa(line, IOAPIC_IRQ_BASE + line);
+            } else {
+                ioapic::route_gsi(line as u32, IOAPIC_IRQ_BASE + line);
+            }
+            if !state.masked {
+                unmask_line(line);
+            }
+        }
+    });
+}
+
+/// Entry for interrupts on the PIC's vectors.
+fn pic_interrupt(line: u8) {
+    // A PIC raises its IRQ 7 when an interrupt goes away before it could be acknowledged, and
+    // that must not get a regular EOI. The PIC can do that even with every line masked.
+    if pic::check_spurious(line) {
+        let counters = &COUNTERS[line as usize];
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        counters.spurious.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    if using_ioapic() {
+        // Raised by the PIC just before it was disabled; the line is routed through the I/O
+        // APIC now and will come in there if the device still wants attention.
+        pic::end_interrupt(IRQ_BASE + line);
+        return;
+    }
+    dispatch(line);
+}
+
+/// Adds `handler` to `line`. `name` is only used for [`dump`].
+pub fn register(
+    line: u8,
+    name: &'static str,
+    handler: IrqHandler,
+    ctx: usize,
+) -> Result<(), IrqError> {
+    if !is_valid(line) {
+        return Err(IrqError::InvalidLine(line));
+    }
+    without_interrupts(|| {
+        let mut lines = LINES.lock();
+        let line_state = &mut lines[line as usize];
+        let slot = line_state
+            .actions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::LineFull(line))?;
+        *slot = Some(Action { name, handler, ctx });
+        line_state.unhandled = 0;
+        line_state.masked = false;
+        unmask_line(line);
+        Ok(())
+    })
+}
+
+/// Removes the handler registered for `line` with the same `handler` and `ctx`.
+pub fn unregister(line: u8, handler: IrqHandler, ctx: usize) -> Result<(), IrqError> {
+    if !is_valid(line) {
+        return Err(IrqError::InvalidLine(line));
+    }
+    without_interrupts(|| {
+        let mut lines = LINES.lock();
+        let line_state = &mut lines[line as usize];
+        let slot = line_state
+            .actions
+            .iter_mut()
+            .find(|slot| {
+                slot.is_some_and(|action| {
+                    action.handler as usize == handler as usize && action.ctx == ctx
+                })
+            })
+            .ok_or(IrqError::NotRegistered(line))?;
+        *slot = None;
+        if line_state.actions.iter().all(Option::is_none) {
+            line_state.masked = true;
+            mask_line(line);
+        }
+        Ok(())
+    })
+}
+
+fn dispatch(line: u8) {
+    let counters = &COUNTERS[line as usize];
+    counters.count.fetch_add(1, Ordering::Relaxed);
+
+    // Copy the handlers out so that they can (un)register handlers themselves.
+    let actions = LINES.lock()[line as usize].actions;
+    let handled = actions
+        .iter()
+        .flatten()
+        .any(|action| (action.handler)(action.ctx) == IrqReturn::Handled);
+
+    if !handled {
+        counters.unhandled.fetch_add(1, Ordering::Relaxed);
+    }
+    {
+        let mut lines = LINES.lock();
+        let state = &mut lines[line as usize];
+        if handled {
+            state.unhandled = 0;
+        } else {
+            state.unhandled += 1;
+            if state.unhandled >= UNHANDLED_LIMIT && !state.masked {
+                state.masked = true;
+                mask_line(line);
+                println!(
+                    "IRQ {}: {} interrupts nobody handled, masking the line",
+                    line, UNHANDLED_LIMIT
+                );
+            }
+        }
+    }
+
+    end_of_interrupt(line);
+    sched::preempt();
+}
+
+/// Statistics for `line`.
+pub fn stats(line: u8) -> IrqStats {
+    let counters = &COUNTERS[line as usize];
+    let (handlers, masked) = without_interrupts(|| {
+        let lines = LINES.lock();
+        let state = &lines[line as usize];
+        (state.actions.iter().flatten().count(), state.masked)
+    });
+    IrqStats {
+        count: counters.count.load(Ordering::Relaxed),
+        unhandled: counters.unhandled.load(Ordering::Relaxed),
+        spurious: counters.spurious.load(Ordering::Relaxed),
+        handlers,
+        masked,
+    }
+}
+
+/// Prints a line for every IRQ that has handlers or has fired.
+pub fn dump() {
+    if !using_ioapic() {
+        println!(
+            "PIC: IRR {:#06x}, ISR {:#06x}",
+            pic::read_irr(),
+            pic::read_isr()
+        );
+    }
+    println!("IRQ      count  unhandled  spurious  handlers");
+    for line in 0..IRQ_LINES as u8 {
+        let stats = stats(line);
+        if stats.count == 0 && stats.handlers == 0 {
+            continue;
+        }
+        let names = without_interrupts(|| LINES.lock()[line as usize].actions);
+        crate::print!(
+            "{:>3} {:>10} {:>10} {:>9}  ",
+            line,
+            stats.count,
+            stats.unhandled,
+            stats.spurious
+        );
+        for action in names.iter().flatten() {
+            crate::print!("{} ", action.name);
+        }
+        println!("{}", if stats.masked { "(masked)" } else { "" });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{arch::asm, sync::atomic::AtomicUsize};
+
+    use super::*;
+
+    // Nothing is wired to IRQ 5 on the machines we run on, so it's free to play with.
+    const TEST_LINE: u8 = 5;
+
+    static CALLS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+    fn not_mine(ctx: usize) -> IrqReturn {
+        CALLS[ctx].fetch_add(1, Ordering::SeqCst);
+        IrqReturn::NotMine
+    }
+
+    fn mine(ctx: usize) -> IrqReturn {
+        CALLS[ctx].fetch_add(1, Ordering::SeqCst);
+        IrqReturn::Handled
+    }
+
+    fn hardware_masked(line: u8) -> bool {
+        if using_ioapic() && (line as usize) >= ISA_LINES {
+            ioapic::is_gsi_masked(line as u32)
+        } else if using_ioapic() {
+            ioapic::is_isa_masked(line)
+        } else {
+            pic::is_masked(line)
+        }
+    }
+
+    fn raise() {
+        if using_ioapic() {
+            unsafe { asm!("int {}", const IOAPIC_IRQ_BASE + TEST_LINE) };
+        } else {
+            unsafe { asm!("int {}", const IRQ_BASE + TEST_LINE) };
+        }
+    }
+
+    #[test_case]
+    fn shared_line_calls_handlers_in_order() {
+        CALLS
+            .iter()
+            .for_each(|calls| calls.store(0, Ordering::SeqCst));
+        register(TEST_LINE, "test-a", not_mine, 0).unwrap();
+        register(TEST_LINE, "test-b", mine, 1).unwrap();
+        let before = stats(TEST_LINE);
+
+        raise();
+        assert_eq!(CALLS[0].load(Ordering::SeqCst), 1);
+        assert_eq!(CALLS[1].load(Ordering::SeqCst), 1);
+
+        let after = stats(TEST_LINE);
+        assert_eq!(after.count, before.count + 1);
+        assert_eq!(after.unhandled, before.unhandled);
+        assert_eq!(after.handlers, 2);
+
+        unregister(TEST_LINE, not_mine, 0).unwrap();
+        unregister(TEST_LINE, mine, 1).unwrap();
+        assert_eq!(
+            unregister(TEST_LINE, mine, 1),
+            Err(IrqError::NotRegistered(TEST_LINE))
+        );
+    }
+
+    #[test_case]
+    fn line_is_masked_after_unhandled_limit() {
+        register(TEST_LINE, "test-unhandled", not_mine, 0).unwrap();
+        assert!(!hardware_masked(TEST_LINE));
+        for _ in 0..UNHANDLED_LIMIT {
+            raise();
+        }
+        assert!(stats(TEST_LINE).masked);
+        assert!(hardware_masked(TEST_LINE));
+        unregister(TEST_LINE, not_mine, 0).unwrap();
+    }
+
+    #[test_case]
+    fn unregistering_last_handler_masks_line() {
+        register(TEST_LINE, "test", mine, 0).unwrap();
+        assert!(!stats(TEST_LINE).masked);
+        unregister(TEST_LINE, mine, 0).unwrap();
+        assert!(stats(TEST_LINE).masked);
+        assert!(hardware_masked(TEST_LINE));
+    }
+
+    #[test_case]
+    fn invalid_line() {
+        let lines = if using_ioapic() { IRQ_LINES } else { ISA_LINES } as u8;
+        assert_eq!(
+            register(lines, "test", mine, 0),
+            Err(IrqError::InvalidLine(lines))
+        );
+    }
+
+    #[test_case]
+    fn lines_past_the_isa_ones_need_the_ioapic() {
+        // Nothing is wired to the last pin either.
+        let line = IRQ_LINES as u8 - 1;
+        if !using_ioapic() {
+            assert_eq!(
+                register(line, "test", mine, 0),
+                Err(IrqError::InvalidLine(line))
+            );
+            return;
+        }
+        register(line, "test", mine, 0).unwrap();
+        assert!(!hardware_masked(line));
+        unregister(line, mine, 0).unwrap();
+        assert!(hardware_masked(line));
+    }
+}
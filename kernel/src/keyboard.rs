@@ -0,0 +1,115 @@
+//! PS/2 keyboard.
+//!
+//! Scancodes (set 1) are read from the controller's data port when IRQ 1 fires, and handled by
+//! the keyboard thread, a real-time one so that keys get through while background work runs.
+//! They are only decoded for now, nothing consumes the keys yet, except for a few Ctrl+Alt
+//! shortcuts: Delete reboots, T prints the scheduler's CPUs and threads, and I the IRQ
+//! statistics.
+
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use crate::{
+    irq::{self, IrqReturn},
+    power,
+    sched::{self, Class},
+    sync::{IrqSpinLock, WaitQueue},
+};
+
+pub const IRQ: u8 = 1;
+
+const DATA_PORT: u16 = 0x60;
+
+/// Set in the scancode of a key being released.
+const RELEASED: u8 = 0x80;
+// Left or right, which only differ by an 0xe0 prefix.
+const CTRL: u8 = 0x1d;
+const ALT: u8 = 0x38;
+/// Delete, or keypad `.` without the prefix.
+const DELETE: u8 = 0x53;
+const KEY_T: u8 = 0x14;
+const KEY_I: u8 = 0x17;
+
+/// Scancodes the keyboard thread hasn't got to yet. More than that are dropped.
+const QUEUE_SIZE: usize = 64;
+const PRIORITY: u8 = 50;
+
+static CTRL_DOWN: AtomicBool = AtomicBool::new(false);
+static ALT_DOWN: AtomicBool = AtomicBool::new(false);
+
+static SCANCODES: IrqSpinLock<VecDeque<u8>> = IrqSpinLock::new(VecDeque::new());
+static WAITERS: WaitQueue = WaitQueue::new();
+
+fn interrupt(_ctx: usize) -> IrqReturn {
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+
+    let scancode: u8 = unsafe { port.read() };
+
+    {
+        // The capacity was reserved up front, so this doesn't allocate.
+        let mut scancodes = SCANCODES.lock();
+        if scancodes.len() < QUEUE_SIZE {
+            scancodes.push_back(scancode);
+        }
+    }
+    WAITERS.wake_one();
+    IrqReturn::Handled
+}
+
+/// Sleeps until there is a scancode.
+fn next_scancode() -> u8 {
+    let mut scancode = None;
+    WAITERS.wait_until(|| {
+        scancode = SCANCODES.lock().pop_front();
+        scancode.is_some()
+    });
+    scancode.unwrap()
+}
+
+fn handle(scancode: u8) {
+    let pressed = scancode & RELEASED == 0;
+    let shortcut = pressed && CTRL_DOWN.load(Ordering::Relaxed) && ALT_DOWN.load(Ordering::Relaxed);
+    match scancode & !RELEASED {
+        CTRL => CTRL_DOWN.store(pressed, Ordering::Relaxed),
+        ALT => ALT_DOWN.store(pressed, Ordering::Relaxed),
+        DELETE if shortcut => power::reboot(),
+        KEY_T if shortcut => sched::dump(),
+        KEY_I if shortcut => irq::dump(),
+        _ => {}
+    }
+
+    let _key = match scancode {
+        0x02..=0x0b => Some(b"1234567890"[scancode as usize - 0x02] as char),
+        0x10..=0x19 => Some(b"qwertyuiop"[scancode as usize - 0x10] as char),
+
+        0x1e..=0x26 => Some(b"asdfghjkl"[scancode as usize - 0x1e] as char),
+        0x2c..=0x32 => Some(b"zxcvbnm"[scancode as usize - 0x2c] as char),
+        0x39 => Some(' '),
+        0xF => Some(' '), // Tab
+        _ => None,
+    };
+    // if let Some(key) = key {
+    //     print!("{}", key);
+    // }
+    // else {
+    //     println!("Unknown key: 0x{:0X}", scancode);
+    // }
+}
+
+/// Claims IRQ 1. Keys are queued until [`start_thread`] starts handling them.
+pub fn init() {
+    SCANCODES.lock().reserve(QUEUE_SIZE);
+    irq::register(IRQ, "keyboard", interrupt, 0)
+        .expect("Failed to register the keyboard interrupt");
+}
+
+/// Starts the keyboard thread, once the scheduler is running.
+pub fn start_thread() {
+    sched::Builder::new("keyboard")
+        .class(Class::RealTime { priority: PRIORITY })
+        .spawn(|| loop {
+            handle(next_scancode());
+        });
+}
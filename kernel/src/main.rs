@@ -1,37 +1,64 @@
 #![no_std]
 #![no_main]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
-use core::{arch::asm, panic::PanicInfo};
+extern crate alloc;
+
+use core::{alloc::Layout, arch::asm, panic::PanicInfo};
 
 use x86_64::instructions::interrupts;
 
+mod acpi;
+mod apic;
 mod boot_info;
 mod debug;
+mod exception;
+mod frame;
 mod gdt;
+mod heap;
+mod hpet;
 mod idt;
+mod ioapic;
+mod irq;
+mod keyboard;
 mod mem;
+mod paging;
+mod percpu;
 mod pic;
 mod pit;
+mod power;
+mod rtc;
+mod sched;
+mod serial;
+mod smp;
+mod sync;
+mod time;
+mod tsc;
 mod vga;
 
 #[panic_handler]
 pub(crate) unsafe fn panic(info: &PanicInfo) -> ! {
-    println!("Panic: ");
-    if let Some(location) = info.location() {
-        println!("{} at {}:", location.file(), location.line());
-    }
-    if let Some(message) = info.message().as_str() {
-        println!("{}", message);
-    } else {
-        println!("unknown");
+    match info.location() {
+        Some(location) => exception::fatal_error(&format_args!(
+            "Panic at {}:{}:\n{}\n",
+            location.file(),
+            location.line(),
+            info.message()
+        )),
+        None => exception::fatal_error(&format_args!("Panic:\n{}\n", info.message())),
     }
+}
 
-    loop {
-        x86_64::instructions::hlt();
-    }
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!(
+        "Kernel heap exhausted: failed to allocate {} bytes (align {})",
+        layout.size(),
+        layout.align()
+    );
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -54,7 +81,10 @@ pub fn test_runner(tests: &[&dyn Fn()]) {
     for test in tests {
         test();
     }
+    serial::flush();
     exit_qemu(QemuExitCode::Success);
+    // Without QEMU's debug exit device, there is nothing left to do.
+    power::shutdown();
 }
 
 pub extern "C" fn user_mode_entry() -> ! {
@@ -72,47 +102,106 @@ pub extern "C" fn user_mode_entry() -> ! {
     loop {}
 }
 
-const KERNEL_STACK_SIZE: usize = 8 * 1024;
+// Must match KERNEL_STACK_SIZE in boot64.asm, which switches to this stack before calling
+// kernel_main. Only the boot CPU runs on it; APs get their stacks from smp::init.
+const KERNEL_STACK_SIZE: usize = 64 * 1024;
+#[no_mangle]
 static mut KERNEL_STACK: [u8; KERNEL_STACK_SIZE] = [0; KERNEL_STACK_SIZE];
 
 #[no_mangle]
 pub extern "C" fn kernel_main(mboot_ptr: usize) -> ! {
-    // Set up the stack for the kernel.
-    unsafe {
-        asm! {
-            "mov rsp, {stack}",
-            stack =  in(reg) core::ptr::addr_of_mut!(KERNEL_STACK),
-        };
-    }
-
     // Initialize the boot info so that we can use it as needed with a 'static lifetime.
     boot_info::init(mboot_ptr).expect("Failed to initialize boot info");
 
-    // Parse the memory map that the bootloader (hopefully) provided.
-    mem::find_available_regions();
-
     println!("Hello from 64-bit Rust! Successfully entered long mode.");
 
-    // Set up the GDT.
+    // Set up the GDT. This has to happen before the bootstrap mappings go away, since the
+    // bootstrap GDT lives in the lower half.
     gdt::init();
 
-    // Set up the IDT entries.
+    // Set up the IDT entries, so that faults during memory setup get reported.
     idt::init();
 
-    // Setup interrupt timer, 10ms preempt by default.
-    pit::init();
+    // Parse the memory map that the bootloader (hopefully) provided.
+    mem::find_available_regions();
+
+    // Hand the free regions over to the physical frame allocator.
+    frame::init();
+
+    // Replace the bootstrap page tables with our own, dropping the identity map.
+    paging::init();
+
+    // Map the kernel heap so that the `alloc` crate can be used from here on.
+    heap::init();
+
+    // Install the IRQ entry points, so that drivers can claim their lines.
+    irq::init();
+
+    // Bring up the serial ports, so that everything printed from here on also goes out of COM1.
+    serial::init();
+
+    // Start the clock: the PIT ticks at 100 Hz unless the command line says otherwise, and the
+    // scheduler preempts on the tick (see sched::TIME_SLICE_TICKS).
+    time::init();
+
+    keyboard::init();
 
     // Setup the PIC.
     pic::init();
 
+    // Find the ACPI tables, which describe the interrupt controllers among other things.
+    acpi::init();
+
+    // Hand IRQs over to the local and I/O APIC, if the machine has them.
+    apic::init();
+
+    // Start the HPET's counter, and its comparators' interrupts now that they can be routed.
+    hpet::init();
+
+    // Measure the TSC against the HPET or the PIT, and tell the time with it if it runs at a
+    // steady rate.
+    tsc::init();
+
+    // Measure the local APIC timers' rate, against the TSC if it could be measured.
+    apic::timer::init();
+
+    // Read the date from the RTC, and set the wall clock from it.
+    rtc::init();
+
+    // Give the boot CPU its per-CPU data, and a GDT and TSS of its own.
+    percpu::init(0);
+
+    // Give each CPU a tick of its own, or none at all when it has nothing to do with `nohz=on`.
+    // The APs start theirs as they come online.
+    time::init_local_ticks();
+
+    // Wake up the other CPUs.
+    smp::init();
+
+    // From here on, the boot code is a thread like any other and can be preempted.
+    sched::init();
+
     // This will be done later once we enter user mode.
     interrupts::enable();
     println!("Interrupts enabled");
 
+    keyboard::start_thread();
+    serial::start_console_thread();
+
     let info = boot_info::boot_info();
 
     println!("Loaded by {}", info.loader);
     println!("Command line: {:?}", info.cmdline);
+    if let Some(now) = time::wall_clock() {
+        println!("Date: {}", now);
+    }
+    let (free_frames, total_frames) = frame::stats();
+    println!(
+        "Memory: {} of {} frames free, {} KB of heap free",
+        free_frames,
+        total_frames,
+        heap::free_bytes() / 1024
+    );
 
     // let selectors = gdt::selectors();
     // let mut tss = selectors.tss.0;
@@ -139,10 +228,9 @@ pub extern "C" fn kernel_main(mboot_ptr: usize) -> ! {
     //     };
     // }
 
-    // #[cfg(test)]
-    // test_main();
+    #[cfg(test)]
+    test_main();
 
-    loop {
-        x86_64::instructions::hlt();
-    }
+    // Nothing left for the boot thread to do; CPU 0 goes on with its idle thread.
+    sched::exit()
 }
@@ -1,4 +1,7 @@
-use core::{mem::MaybeUninit, ptr::addr_of_mut};
+use core::{
+    mem::MaybeUninit,
+    ptr::{addr_of, addr_of_mut},
+};
 
 use multiboot2::{MemoryArea, MemoryAreaType};
 
@@ -12,13 +15,22 @@ pub fn find_available_regions() {
     let boot_info = crate::boot_info::boot_info();
     let regions = boot_info.mem_map;
 
-    let free_regions = &mut unsafe { *addr_of_mut!(FREE_REGIONS) };
-    let free_regions_count = &mut unsafe { *addr_of_mut!(FREE_REGIONS_COUNT) };
-    let free_bytes = &mut unsafe { *addr_of_mut!(FREE_BYTES) };
+    // Borrow the statics in place; dereferencing them by value would copy the arrays
+    // and none of the writes below would stick.
+    let free_regions = unsafe { &mut *addr_of_mut!(FREE_REGIONS) };
+    let free_regions_count = unsafe { &mut *addr_of_mut!(FREE_REGIONS_COUNT) };
+    let free_bytes = unsafe { &mut *addr_of_mut!(FREE_BYTES) };
 
     for region in regions {
         match region.typ().into() {
             MemoryAreaType::Available => {
+                if *free_regions_count == free_regions.len() {
+                    println!(
+                        "Too many free regions, ignoring {:#x}",
+                        region.start_address()
+                    );
+                    continue;
+                }
                 free_regions[*free_regions_count] = MaybeUninit::new(region.clone());
                 *free_regions_count += 1;
                 *free_bytes += region.size() as usize;
@@ -59,6 +71,14 @@ pub fn find_available_regions() {
     );
 }
 
+/// The `Available` regions recorded by [`find_available_regions`].
+pub fn available_regions() -> &'static [MemoryArea] {
+    let regions = unsafe { &*addr_of!(FREE_REGIONS) };
+    let count = unsafe { *addr_of!(FREE_REGIONS_COUNT) };
+    // SAFETY: the first `count` entries were initialized by `find_available_regions`.
+    unsafe { core::slice::from_raw_parts(regions.as_ptr().cast::<MemoryArea>(), count) }
+}
+
 #[allow(unused)]
 extern "C" {
     pub fn k_memset(ptr: *mut u8, value: u8, count: usize);
@@ -0,0 +1,885 @@
+//! 4-level paging, managed from Rust.
+//!
+//! boot.asm only sets up enough paging to get into long mode and jump to the higher half: the
+//! first GiB of physical memory identity mapped, aliased at [`PHYS_OFFSET`], and aliased again
+//! at [`KERNEL_OFFSET`] where the kernel is linked. [`init`] replaces those tables with a fresh
+//! PML4 that maps the kernel image at [`KERNEL_OFFSET`] with per-section permissions, maps all
+//! of physical memory at [`PHYS_OFFSET`] so that page tables and other physical memory can be
+//! reached from anywhere (cached for RAM, uncached for the rest), and takes over CR3. Nothing is mapped in the lower half after that.
+//!
+//! Virtual memory layout (kernel half):
+//!
+//! | Start                   | Contents                          |
+//! |-------------------------|-----------------------------------|
+//! | `0xffff_8000_0000_0000` | Direct map of physical memory     |
+//! | `0xffff_9000_0000_0000` | Kernel heap (see `heap`)          |
+//! | `0xffff_a000_0000_0000` | MMIO mappings, see [`map_mmio`]   |
+//! | `0xffff_ffff_8000_0000` | Kernel image                      |
+//!
+//! Other CPUs may have translations cached for whatever is unmapped or made less permissive, so
+//! [`AddressSpace::unmap`] and [`AddressSpace::protect`] shoot them down with a cross call to
+//! every CPU that could be using the address space. Mapping a page that wasn't present needs no
+//! shootdown, since the TLB doesn't cache non-present entries.
+
+use core::{
+    fmt,
+    hint::spin_loop,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use alloc::vec::Vec;
+
+use multiboot2::{MemoryArea, MemoryAreaType};
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::{
+    instructions::{interrupts::without_interrupts, tlb},
+    registers::{
+        control::{Cr0, Cr0Flags, Cr2, Cr3, Cr3Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            page_table::PageTableEntry, Page, PageSize, PageTable, PageTableFlags, PhysFrame,
+            Size1GiB, Size2MiB, Size4KiB,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    apic, boot_info, frame, println,
+    smp::{self, CpuMask},
+};
+
+/// Base of the direct map of physical memory.
+pub const PHYS_OFFSET: u64 = 0xffff_8000_0000_0000;
+/// Base of the region that device memory is mapped into.
+pub const MMIO_START: u64 = 0xffff_a000_0000_0000;
+/// Where the kernel image is linked, relative to where it is loaded. Must match linker.ld.
+pub const KERNEL_OFFSET: u64 = 0xffff_ffff_8000_0000;
+
+/// Above this many pages, a shootdown flushes the whole TLB instead of page by page.
+const SHOOTDOWN_FLUSH_ALL_PAGES: u64 = 32;
+
+extern "C" {
+    // Defined in linker.ld
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// A frame was needed for a page table but none were left.
+    OutOfMemory,
+    /// The page is already mapped.
+    AlreadyMapped,
+    /// The page is not mapped.
+    NotMapped,
+    /// The page lies inside a bigger huge page, or is a huge page of a different size.
+    SizeMismatch,
+    /// The range overlaps a region that is already part of the address space.
+    #[cfg(test)]
+    RegionOverlap,
+}
+
+/// Returns the address at which `addr` is reachable through the direct map.
+#[inline(always)]
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + PHYS_OFFSET)
+}
+
+/// End of the physical memory covered by the direct map, set by [`init`].
+static DIRECT_MAP_END: AtomicU64 = AtomicU64::new(0);
+
+/// Whether all `len` bytes from `start` are in the direct map.
+pub fn is_direct_mapped(start: PhysAddr, len: u64) -> bool {
+    start
+        .as_u64()
+        .checked_add(len)
+        .is_some_and(|end| end <= DIRECT_MAP_END.load(Ordering::Relaxed))
+}
+
+/// Whether pages of size `S` are mapped with `HUGE_PAGE` entries.
+fn is_huge<S: PageSize>() -> bool {
+    S::SIZE != Size4KiB::SIZE
+}
+
+fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() }
+}
+
+fn new_table() -> Result<PhysFrame, PagingError> {
+    let frame = frame::alloc_frame().ok_or(PagingError::OutOfMemory)?;
+    table_at(frame).zero();
+    Ok(frame)
+}
+
+/// Returns the table `entry` points to, creating it if `create` is set.
+fn next_table(
+    entry: &mut PageTableEntry,
+    flags: PageTableFlags,
+    create: bool,
+) -> Result<&'static mut PageTable, PagingError> {
+    if entry.is_unused() {
+        if !create {
+            return Err(PagingError::NotMapped);
+        }
+        let frame = new_table()?;
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Err(PagingError::SizeMismatch);
+    }
+
+    // Intermediate entries have to be at least as permissive as the leaves below them.
+    if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+    }
+    Ok(table_at(PhysFrame::containing_address(entry.addr())))
+}
+
+/// A range of virtual memory whose pages are mapped to zeroed frames on demand, by the page
+/// fault handler.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+}
+
+impl Region {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Whether the access described by `error` is allowed in this region.
+    fn permits(&self, error: PageFaultErrorCode) -> bool {
+        let flags = self.flags;
+        (!error.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            || flags.contains(PageTableFlags::WRITABLE))
+            && (!error.contains(PageFaultErrorCode::USER_MODE)
+                || flags.contains(PageTableFlags::USER_ACCESSIBLE))
+            && (!error.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+                || !flags.contains(PageTableFlags::NO_EXECUTE))
+    }
+}
+
+/// A set of page tables rooted at a PML4, along with the demand paged regions in it.
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    regions: Vec<Region>,
+}
+
+impl AddressSpace {
+    /// Creates an address space with nothing mapped in it.
+    pub fn new() -> Result<Self, PagingError> {
+        Ok(Self {
+            pml4: new_table()?,
+            regions: Vec::new(),
+        })
+    }
+
+    pub fn pml4(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.pml4
+    }
+
+    /// Loads the address space into CR3.
+    ///
+    /// # Safety
+    ///
+    /// The code, stack and data currently in use must be mapped in this address space.
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.pml4, Cr3Flags::empty());
+    }
+
+    /// Walks down to the entry that maps a page of size `S` at `addr`.
+    fn entry<S: PageSize>(
+        &self,
+        addr: VirtAddr,
+        flags: PageTableFlags,
+        create: bool,
+    ) -> Result<&'static mut PageTableEntry, PagingError> {
+        let p4 = table_at(self.pml4);
+        let p3 = next_table(&mut p4[addr.p4_index()], flags, create)?;
+        if S::SIZE == Size1GiB::SIZE {
+            return Ok(&mut p3[addr.p3_index()]);
+        }
+        let p2 = next_table(&mut p3[addr.p3_index()], flags, create)?;
+        if S::SIZE == Size2MiB::SIZE {
+            return Ok(&mut p2[addr.p2_index()]);
+        }
+        let p1 = next_table(&mut p2[addr.p2_index()], flags, create)?;
+        Ok(&mut p1[addr.p1_index()])
+    }
+
+    fn flush(&self, addr: VirtAddr) {
+        if self.is_active() {
+            tlb::flush(addr);
+        }
+    }
+
+    /// Flushes `size` bytes from `start` out of the TLBs of the other CPUs that may be using
+    /// this address space: all of them for the kernel half, which every address space shares,
+    /// and those that have it loaded otherwise.
+    fn shootdown(&self, start: VirtAddr, size: u64) {
+        let pml4 = self.pml4;
+        let kernel_half = start.as_u64() >= PHYS_OFFSET;
+        let pages = size.div_ceil(Size4KiB::SIZE);
+        smp::call_on(CpuMask::others(), &|| {
+            if !kernel_half && Cr3::read().0 != pml4 {
+                return;
+            }
+            if pages > SHOOTDOWN_FLUSH_ALL_PAGES {
+                tlb::flush_all();
+            } else {
+                for page in 0..pages {
+                    tlb::flush(start + page * Size4KiB::SIZE);
+                }
+            }
+        });
+    }
+
+    /// Maps `page` to `frame`. `HUGE_PAGE` is added to `flags` for 2 MiB and 1 GiB pages.
+    pub fn map<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        let entry = self.entry::<S>(page.start_address(), flags, true)?;
+        if !entry.is_unused() {
+            return Err(PagingError::AlreadyMapped);
+        }
+
+        let mut flags = flags | PageTableFlags::PRESENT;
+        if is_huge::<S>() {
+            flags |= PageTableFlags::HUGE_PAGE;
+        }
+        entry.set_addr(frame.start_address(), flags);
+        self.flush(page.start_address());
+        Ok(())
+    }
+
+    /// Unmaps `page`, returning the frame it was mapped to. The frame is not freed.
+    pub fn unmap<S: PageSize>(&mut self, page: Page<S>) -> Result<PhysFrame<S>, PagingError> {
+        let frame = self.unmap_local(page)?;
+        self.shootdown(page.start_address(), S::SIZE);
+        Ok(frame)
+    }
+
+    /// Like [`unmap`](Self::unmap), but only flushes the TLB of this CPU. The caller has to do
+    /// the shootdown.
+    fn unmap_local<S: PageSize>(&mut self, page: Page<S>) -> Result<PhysFrame<S>, PagingError> {
+        let entry = self.entry::<S>(page.start_address(), PageTableFlags::empty(), false)?;
+        if entry.is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) != is_huge::<S>() {
+            return Err(PagingError::SizeMismatch);
+        }
+
+        let frame = PhysFrame::containing_address(entry.addr());
+        entry.set_unused();
+        self.flush(page.start_address());
+        Ok(frame)
+    }
+
+    /// Replaces the flags of an existing mapping.
+    #[cfg(test)]
+    pub fn protect<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        let entry = self.entry::<S>(page.start_address(), flags, false)?;
+        if entry.is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) != is_huge::<S>() {
+            return Err(PagingError::SizeMismatch);
+        }
+        let mut flags = flags | PageTableFlags::PRESENT;
+        if is_huge::<S>() {
+            flags |= PageTableFlags::HUGE_PAGE;
+        }
+        entry.set_flags(flags);
+        self.flush(page.start_address());
+        self.shootdown(page.start_address(), S::SIZE);
+        Ok(())
+    }
+
+    /// Translates a virtual address, whatever size of page it is mapped with.
+    #[cfg(test)]
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        let mut table = table_at(self.pml4);
+        let indices = [
+            (addr.p4_index(), 0),
+            (addr.p3_index(), Size1GiB::SIZE),
+            (addr.p2_index(), Size2MiB::SIZE),
+            (addr.p1_index(), Size4KiB::SIZE),
+        ];
+        for (index, page_size) in indices {
+            let entry = &table[index];
+            if entry.is_unused() || !entry.flags().contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            let is_leaf =
+                page_size == Size4KiB::SIZE || entry.flags().contains(PageTableFlags::HUGE_PAGE);
+            if is_leaf && page_size != 0 {
+                let offset = addr.as_u64() & (page_size - 1);
+                return Some((entry.addr() + offset, entry.flags()));
+            }
+            table = table_at(PhysFrame::containing_address(entry.addr()));
+        }
+        None
+    }
+
+    /// Adds a region of `size` bytes at `start` (both page aligned) that gets backed by zeroed
+    /// frames as it is touched.
+    #[cfg(test)]
+    pub fn add_region(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        assert!(start.is_aligned(Size4KiB::SIZE) && size.is_multiple_of(Size4KiB::SIZE));
+        let end = start + size;
+        if self.regions.iter().any(|r| r.start < end && start < r.end) {
+            return Err(PagingError::RegionOverlap);
+        }
+        self.regions.push(Region { start, end, flags });
+        Ok(())
+    }
+
+    /// Removes the region starting at `start`, unmapping and freeing whatever was faulted in.
+    #[cfg(test)]
+    pub fn remove_region(&mut self, start: VirtAddr) -> Result<(), PagingError> {
+        let index = self
+            .regions
+            .iter()
+            .position(|r| r.start == start)
+            .ok_or(PagingError::NotMapped)?;
+        let region = self.regions.remove(index);
+
+        let first = Page::<Size4KiB>::containing_address(region.start);
+        let last = Page::containing_address(region.end - 1u64);
+        let frames: Vec<_> = Page::range_inclusive(first, last)
+            .filter_map(|page| self.unmap_local(page).ok())
+            .collect();
+        // Other CPUs may still have the pages in their TLBs, so the frames only go back once
+        // those entries are gone.
+        self.shootdown(region.start, region.end - region.start);
+        for frame in frames {
+            frame::free_frame(frame);
+        }
+        Ok(())
+    }
+
+    pub fn region(&self, addr: VirtAddr) -> Option<&Region> {
+        self.regions.iter().find(|r| r.contains(addr))
+    }
+
+    /// Tries to resolve a page fault at `addr` by faulting in a page of one of the regions.
+    pub fn handle_fault(
+        &mut self,
+        addr: VirtAddr,
+        error: PageFaultErrorCode,
+    ) -> Result<(), FaultError> {
+        if error.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            return Err(FaultError::ReservedBit);
+        }
+        if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return Err(FaultError::ProtectionViolation);
+        }
+        let region = self.region(addr).ok_or(FaultError::NoRegion)?;
+        if !region.permits(error) {
+            return Err(FaultError::AccessDenied);
+        }
+
+        let flags = region.flags;
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let frame = frame::alloc_frame().ok_or(FaultError::OutOfMemory)?;
+        unsafe {
+            phys_to_virt(frame.start_address())
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, Size4KiB::SIZE as usize);
+        }
+        self.map(page, frame, flags).map_err(|_| {
+            frame::free_frame(frame);
+            FaultError::OutOfMemory
+        })
+    }
+}
+
+/// Why a page fault could not be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The address is not part of any region.
+    NoRegion,
+    /// The address is in a region, but the region does not allow this kind of access.
+    AccessDenied,
+    /// The page is present, so the access itself broke its protection.
+    ProtectionViolation,
+    /// A reserved bit was set in one of the page table entries.
+    ReservedBit,
+    /// No frame was left to back the page with.
+    OutOfMemory,
+    /// The fault happened while the page tables were being modified.
+    TablesLocked,
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FaultError::NoRegion => "address is not mapped and not part of any region",
+            FaultError::AccessDenied => "access not permitted by the region",
+            FaultError::ProtectionViolation => "page protection violation",
+            FaultError::ReservedBit => "reserved bit set in a page table entry",
+            FaultError::OutOfMemory => "out of memory while faulting in a page",
+            FaultError::TablesLocked => "fault while the page tables were locked",
+        })
+    }
+}
+
+/// Describes the access that caused a page fault, e.g. "kernel write to non-present page".
+pub struct FaultCause(pub PageFaultErrorCode);
+
+impl fmt::Display for FaultCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let error = self.0;
+        let mode = if error.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        let access = if error.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if error.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "present page (protection violation)"
+        } else {
+            "non-present page"
+        };
+        write!(f, "{} {} {}", mode, access, page)?;
+        if error.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set")?;
+        }
+        if error.contains(PageFaultErrorCode::PROTECTION_KEY) {
+            write!(f, ", protection key")?;
+        }
+        Ok(())
+    }
+}
+
+/// The entries visited while translating an address, from the PML4 down.
+pub struct PageWalk {
+    addr: VirtAddr,
+    entries: [Option<(u16, PhysAddr, PageTableFlags)>; 4],
+}
+
+/// Walks the active page tables for `addr`, without taking any locks.
+pub fn walk(addr: VirtAddr) -> PageWalk {
+    let mut walk = PageWalk {
+        addr,
+        entries: [None; 4],
+    };
+    let mut table = table_at(Cr3::read().0);
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    for (level, index) in indices.into_iter().enumerate() {
+        let entry = &table[index];
+        walk.entries[level] = Some((u16::from(index), entry.addr(), entry.flags()));
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        table = table_at(PhysFrame::containing_address(entry.addr()));
+    }
+    walk
+}
+
+impl fmt::Display for PageWalk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [&str; 4] = ["PML4", "PDPT", "PD  ", "PT  "];
+        writeln!(f, "Page walk for {:#x}:", self.addr)?;
+        for (name, entry) in NAMES.iter().zip(self.entries.iter()) {
+            let Some((index, addr, flags)) = entry else {
+                break;
+            };
+            writeln!(f, "  {}[{:3}] = {:#012x} {:?}", name, index, addr, flags)?;
+        }
+        Ok(())
+    }
+}
+
+/// Called by the page fault handler. Resolves faults in demand paged regions of the kernel
+/// address space; anything else is returned as an error for the handler to report.
+pub fn handle_page_fault(error: PageFaultErrorCode) -> Result<(), FaultError> {
+    let addr = Cr2::read();
+    if KERNEL_SPACE.get().is_none() {
+        return Err(FaultError::NoRegion);
+    }
+    // A fault while this CPU holds the tables is a bug in the paging code, and waiting for the
+    // lock would never finish. Another CPU holding them will let go eventually.
+    if KERNEL_SPACE_OWNER.load(Ordering::Acquire) == apic::id() {
+        return Err(FaultError::TablesLocked);
+    }
+    with_kernel_space(|space| {
+        if !space.is_active() {
+            return Err(FaultError::NoRegion);
+        }
+        space.handle_fault(addr, error)
+    })
+}
+
+static KERNEL_SPACE: Once<Mutex<AddressSpace>> = Once::new();
+/// APIC ID of the CPU holding the [`KERNEL_SPACE`] lock, or [`NO_OWNER`].
+static KERNEL_SPACE_OWNER: AtomicU32 = AtomicU32::new(NO_OWNER);
+const NO_OWNER: u32 = u32::MAX;
+
+/// Takes the kernel address space lock. The CPU holding it may be waiting for this one to
+/// answer a TLB shootdown, so cross calls are handled while spinning.
+fn lock_kernel_space(space: &Mutex<AddressSpace>) -> MutexGuard<'_, AddressSpace> {
+    loop {
+        if let Some(guard) = space.try_lock() {
+            KERNEL_SPACE_OWNER.store(apic::id(), Ordering::Release);
+            return guard;
+        }
+        smp::handle_calls();
+        spin_loop();
+    }
+}
+
+/// Runs `f` on the kernel address space.
+pub fn with_kernel_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> R {
+    let space = KERNEL_SPACE.get().expect("Paging not initialized");
+    without_interrupts(|| {
+        let mut guard = lock_kernel_space(space);
+        let result = f(&mut guard);
+        KERNEL_SPACE_OWNER.store(NO_OWNER, Ordering::Release);
+        result
+    })
+}
+
+/// Maps `page` to `frame` in the kernel address space.
+pub fn map<S: PageSize>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    with_kernel_space(|space| space.map(page, frame, flags))
+}
+
+/// Unmaps `page` from the kernel address space, returning the frame it was mapped to.
+pub fn unmap<S: PageSize>(page: Page<S>) -> Result<PhysFrame<S>, PagingError> {
+    with_kernel_space(|space| space.unmap(page))
+}
+
+/// Changes the flags of `page` in the kernel address space.
+#[cfg(test)]
+pub fn protect<S: PageSize>(page: Page<S>, flags: PageTableFlags) -> Result<(), PagingError> {
+    with_kernel_space(|space| space.protect(page, flags))
+}
+
+/// Translates `addr` using the kernel address space.
+#[cfg(test)]
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    with_kernel_space(|space| space.translate(addr).map(|(phys, _)| phys))
+}
+
+/// Adds a demand paged region to the kernel address space, see [`AddressSpace::add_region`].
+#[cfg(test)]
+pub fn add_region(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), PagingError> {
+    with_kernel_space(|space| space.add_region(start, size, flags))
+}
+
+/// Removes a demand paged region from the kernel address space.
+#[cfg(test)]
+pub fn remove_region(start: VirtAddr) -> Result<(), PagingError> {
+    with_kernel_space(|space| space.remove_region(start))
+}
+
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Maps `size` bytes of device memory starting at `phys` as uncached, returning the virtual
+/// address of `phys`.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> VirtAddr {
+    let start = phys.align_down(Size4KiB::SIZE);
+    let end = (phys + size).align_up(Size4KiB::SIZE);
+    let len = end - start;
+    let virt = NEXT_MMIO.fetch_add(len, Ordering::Relaxed);
+
+    let flags = PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    for offset in (0..len).step_by(Size4KiB::SIZE as usize) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt + offset));
+        let frame = PhysFrame::containing_address(start + offset);
+        map(page, frame, flags).expect("Failed to map MMIO region");
+    }
+
+    VirtAddr::new(virt + (phys - start))
+}
+
+fn has_1gib_pages() -> bool {
+    let leaf = core::arch::x86_64::__cpuid(0x8000_0001);
+    leaf.edx & (1 << 26) != 0
+}
+
+/// Whether the memory map says `area` is RAM, as opposed to device memory or firmware.
+fn is_ram(area: &MemoryArea) -> bool {
+    matches!(
+        area.typ().into(),
+        MemoryAreaType::Available
+            | MemoryAreaType::AcpiAvailable
+            | MemoryAreaType::ReservedHibernate
+    )
+}
+
+/// Whether `addr` is RAM, and where the memory map next changes after it.
+fn classify(areas: &[MemoryArea], addr: u64, end: u64) -> (bool, u64) {
+    let mut ram = false;
+    let mut next = end;
+    for area in areas {
+        // Partial pages of RAM are left uncached with whatever shares the page.
+        let start = area.start_address().next_multiple_of(Size4KiB::SIZE);
+        let end = area.end_address() / Size4KiB::SIZE * Size4KiB::SIZE;
+        if start <= addr && addr < end && is_ram(area) {
+            ram = true;
+        }
+        for boundary in [start, end] {
+            if boundary > addr {
+                next = next.min(boundary);
+            }
+        }
+    }
+    (ram, next)
+}
+
+/// Maps `[0, size)` of physical memory at `virt` using the biggest pages available. Only RAM
+/// is cached; holes and device memory are mapped uncached, so they don't alias the mappings of
+/// [`map_mmio`] with a different memory type.
+fn map_linear(space: &mut AddressSpace, virt: u64, size: u64, flags: PageTableFlags) {
+    let areas = boot_info::boot_info().mem_map;
+    let huge = has_1gib_pages();
+    let mut offset = 0;
+    while offset < size {
+        let (ram, run_end) = classify(areas, offset, size);
+        let flags = if ram {
+            flags
+        } else {
+            flags | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+        };
+        while offset < run_end {
+            let page_virt = VirtAddr::new(virt + offset);
+            let phys = PhysAddr::new(offset);
+            let left = run_end - offset;
+            if huge && offset % Size1GiB::SIZE == 0 && left >= Size1GiB::SIZE {
+                let page = Page::<Size1GiB>::containing_address(page_virt);
+                let frame = PhysFrame::containing_address(phys);
+                space.map(page, frame, flags).expect("Failed to map memory");
+                offset += Size1GiB::SIZE;
+            } else if offset % Size2MiB::SIZE == 0 && left >= Size2MiB::SIZE {
+                let page = Page::<Size2MiB>::containing_address(page_virt);
+                let frame = PhysFrame::containing_address(phys);
+                space.map(page, frame, flags).expect("Failed to map memory");
+                offset += Size2MiB::SIZE;
+            } else {
+                let page = Page::<Size4KiB>::containing_address(page_virt);
+                let frame = PhysFrame::containing_address(phys);
+                space.map(page, frame, flags).expect("Failed to map memory");
+                offset += Size4KiB::SIZE;
+            }
+        }
+    }
+}
+
+/// Maps the kernel image at [`KERNEL_OFFSET`]: code read-only, read-only data non-executable,
+/// and everything else writable but non-executable.
+fn map_kernel_image(space: &mut AddressSpace) {
+    let sections = unsafe {
+        [
+            (&__text_start, &__text_end, PageTableFlags::empty()),
+            (&__rodata_start, &__rodata_end, PageTableFlags::NO_EXECUTE),
+            (
+                &__data_start,
+                &__data_end,
+                PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            ),
+        ]
+    };
+    for (start, end, flags) in sections {
+        let start = Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(start));
+        let end = Page::containing_address(VirtAddr::from_ptr(end) - 1u64);
+        for page in Page::range_inclusive(start, end) {
+            let phys = PhysAddr::new(page.start_address().as_u64() - KERNEL_OFFSET);
+            space
+                .map(page, PhysFrame::containing_address(phys), flags)
+                .expect("Failed to map the kernel image");
+        }
+    }
+}
+
+/// Builds the kernel's page tables and switches to them.
+pub fn init() {
+    unsafe {
+        // Honour NO_EXECUTE, and WRITABLE in ring 0 as well.
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+
+    let mut space = AddressSpace::new().expect("Out of memory while setting up paging");
+
+    map_kernel_image(&mut space);
+
+    // Direct map everything the memory map knows about, holes included.
+    let phys_end = boot_info::boot_info()
+        .mem_map
+        .iter()
+        .map(|area| area.end_address())
+        .max()
+        .unwrap_or(Size1GiB::SIZE)
+        .next_multiple_of(Size2MiB::SIZE);
+    map_linear(
+        &mut space,
+        PHYS_OFFSET,
+        phys_end,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    );
+    DIRECT_MAP_END.store(phys_end, Ordering::Relaxed);
+
+    // This drops the bootstrap's identity map (and the .boot sections with it); everything we
+    // still need is reachable through the higher half.
+    unsafe {
+        space.activate();
+    }
+    println!(
+        "Paging: PML4 at {:#x}, {} MB direct mapped.",
+        space.pml4().start_address(),
+        phys_end / (1024 * 1024)
+    );
+
+    KERNEL_SPACE.call_once(|| Mutex::new(space));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_PAGE: u64 = MMIO_START - 0x1000_0000;
+
+    #[test_case]
+    fn map_translate_unmap() {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TEST_PAGE));
+        let frame = frame::alloc_frame().unwrap();
+        map(page, frame, PageTableFlags::WRITABLE).unwrap();
+        assert_eq!(
+            translate(page.start_address() + 0x123u64),
+            Some(frame.start_address() + 0x123u64)
+        );
+        assert_eq!(
+            map(page, frame, PageTableFlags::WRITABLE),
+            Err(PagingError::AlreadyMapped)
+        );
+
+        unsafe {
+            page.start_address()
+                .as_mut_ptr::<u64>()
+                .write_volatile(0x600d);
+            let direct = phys_to_virt(frame.start_address()).as_ptr::<u64>();
+            assert_eq!(direct.read_volatile(), 0x600d);
+        }
+
+        assert_eq!(unmap(page), Ok(frame));
+        assert_eq!(translate(page.start_address()), None);
+        assert_eq!(unmap(page), Err(PagingError::NotMapped));
+        frame::free_frame(frame);
+    }
+
+    #[test_case]
+    fn only_ram_is_direct_mapped_cached() {
+        let direct_flags = |phys: PhysAddr| {
+            with_kernel_space(|space| space.translate(phys_to_virt(phys)).unwrap().1)
+        };
+        let frame = frame::alloc_frame().unwrap();
+        assert!(!direct_flags(frame.start_address()).contains(PageTableFlags::NO_CACHE));
+        frame::free_frame(frame);
+        // VGA memory
+        assert!(direct_flags(PhysAddr::new(0xb8000)).contains(PageTableFlags::NO_CACHE));
+    }
+
+    #[test_case]
+    fn protect_changes_flags() {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TEST_PAGE + 0x1000));
+        let frame = frame::alloc_frame().unwrap();
+        map(page, frame, PageTableFlags::WRITABLE).unwrap();
+        protect(page, PageTableFlags::NO_EXECUTE).unwrap();
+        let flags = with_kernel_space(|space| space.translate(page.start_address()))
+            .unwrap()
+            .1;
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+        assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+        unmap(page).unwrap();
+        frame::free_frame(frame);
+    }
+
+    #[test_case]
+    fn regions_are_demand_paged() {
+        let start = VirtAddr::new(TEST_PAGE + 0x40_0000);
+        add_region(start, 4 * Size4KiB::SIZE, PageTableFlags::WRITABLE).unwrap();
+        assert_eq!(
+            add_region(
+                start + Size4KiB::SIZE,
+                Size4KiB::SIZE,
+                PageTableFlags::empty()
+            ),
+            Err(PagingError::RegionOverlap)
+        );
+        assert_eq!(translate(start), None);
+
+        let ptr = (start + 2 * Size4KiB::SIZE).as_mut_ptr::<u64>();
+        unsafe {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(42);
+            assert_eq!(ptr.read_volatile(), 42);
+        }
+        assert!(translate(start + 2 * Size4KiB::SIZE).is_some());
+        assert_eq!(translate(start), None);
+
+        remove_region(start).unwrap();
+        assert_eq!(translate(start + 2 * Size4KiB::SIZE), None);
+    }
+
+    #[test_case]
+    fn huge_pages_translate() {
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(TEST_PAGE + 0x20_0000));
+        let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0x20_0000));
+        map(page, frame, PageTableFlags::empty()).unwrap();
+        assert_eq!(
+            translate(page.start_address() + 0x1_2345u64),
+            Some(PhysAddr::new(0x21_2345))
+        );
+        let small = Page::<Size4KiB>::containing_address(page.start_address());
+        assert_eq!(unmap(small), Err(PagingError::SizeMismatch));
+        assert_eq!(unmap(page), Ok(frame));
+    }
+}
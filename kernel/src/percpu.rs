@@ -0,0 +1,184 @@
+//! Per-CPU data.
+//!
+//! Every CPU gets a [`PerCpu`] of its own, along with its own GDT and TSS (a TSS can't be shared:
+//! the CPU marks it busy when it is loaded) and the stacks the TSS points to. While in the kernel,
+//! `IA32_GS_BASE` holds the address of the current CPU's structure, whose first field points back
+//! at itself, so a single `mov reg, gs:[0]` finds it. `IA32_KERNEL_GS_BASE` holds the user's GS
+//! base (zero for now), which `swapgs` exchanges on the way in from and out to ring 3.
+//!
+//! Fields are read with [`percpu!`], e.g. `percpu!(cpu)`. Code that can be preempted (and so
+//! moved to another CPU) between reading a field and using it should disable interrupts around
+//! both.
+
+use alloc::boxed::Box;
+#[cfg(test)]
+use core::mem::offset_of;
+use core::{
+    arch::asm,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+};
+
+use x86_64::{
+    registers::model_specific::{GsBase, KernelGsBase},
+    structures::tss::TaskStateSegment,
+    VirtAddr,
+};
+
+use crate::{
+    apic,
+    frame::{self, FRAME_SIZE},
+    gdt::{self, Gdt, DOUBLE_FAULT_STACK_SIZE},
+    paging,
+    smp::MAX_CPUS,
+};
+
+/// Size of the stack the CPU switches to on entry from ring 3 (RSP0 in the TSS).
+const KERNEL_STACK_FRAMES: usize = 4;
+
+/// Offset of [`PerCpu::syscall_scratch`], for the syscall entry code.
+#[cfg(test)]
+pub const SYSCALL_SCRATCH_OFFSET: usize = offset_of!(PerCpu, syscall_scratch);
+/// Offset of [`PerCpu::kernel_stack`], for the syscall entry code.
+#[cfg(test)]
+pub const KERNEL_STACK_OFFSET: usize = offset_of!(PerCpu, kernel_stack);
+
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this structure; must stay the first field.
+    self_ptr: VirtAddr,
+    /// Where syscall entry stashes the user stack pointer before switching stacks.
+    pub syscall_scratch: AtomicU64,
+    /// Top of this CPU's ring 0 stack, also in the TSS as RSP0.
+    pub kernel_stack: VirtAddr,
+    /// CPU number, 0 being the boot CPU.
+    pub cpu: usize,
+    pub apic_id: u32,
+    /// The task running on this CPU, null until there is a scheduler.
+    pub current_task: AtomicPtr<()>,
+    pub tss: &'static TaskStateSegment,
+    pub gdt: &'static Gdt,
+}
+
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+/// Allocates a stack of `frames` frames, returning its top.
+fn alloc_stack(frames: usize) -> VirtAddr {
+    let start = frame::alloc_contiguous(frames, FRAME_SIZE).expect("Out of memory for stacks");
+    paging::phys_to_virt(start.start_address()) + frames as u64 * FRAME_SIZE
+}
+
+/// Sets up the per-CPU data, GDT and TSS of the current CPU, which is CPU number `cpu`. Needs
+/// the heap, and the local APIC enabled on this CPU.
+pub fn init(cpu: usize) -> &'static PerCpu {
+    assert!(cpu < MAX_CPUS, "CPU number {} out of range", cpu);
+
+    let kernel_stack = alloc_stack(KERNEL_STACK_FRAMES);
+    let double_fault_stack = alloc_stack(DOUBLE_FAULT_STACK_SIZE.div_ceil(FRAME_SIZE as usize));
+    let tss = Box::leak(Box::new(gdt::new_tss(kernel_stack, double_fault_stack)));
+    let gdt = Box::leak(Box::new(gdt::build(tss)));
+    gdt::load(gdt);
+
+    let percpu = Box::leak(Box::new(PerCpu {
+        self_ptr: VirtAddr::zero(),
+        syscall_scratch: AtomicU64::new(0),
+        kernel_stack,
+        cpu,
+        apic_id: apic::id(),
+        current_task: AtomicPtr::new(ptr::null_mut()),
+        tss,
+        gdt,
+    }));
+    percpu.self_ptr = VirtAddr::from_ptr(percpu);
+
+    GsBase::write(percpu.self_ptr);
+    KernelGsBase::write(VirtAddr::zero());
+    let old = CPUS[cpu].swap(percpu, Ordering::SeqCst);
+    assert!(old.is_null(), "CPU {} initialized twice", cpu);
+    percpu
+}
+
+/// The current CPU's data. [`init`] must have run on this CPU.
+pub fn current() -> &'static PerCpu {
+    let percpu: *const PerCpu;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) percpu,
+            options(nostack, preserves_flags, readonly)
+        );
+        &*percpu
+    }
+}
+
+/// The data of CPU number `cpu`, if it has been initialized.
+pub fn get(cpu: usize) -> Option<&'static PerCpu> {
+    let percpu = CPUS.get(cpu)?.load(Ordering::SeqCst);
+    unsafe { percpu.as_ref() }
+}
+
+/// Reads a field of the current CPU's [`PerCpu`].
+#[macro_export]
+macro_rules! percpu {
+    ($field:ident) => {
+        $crate::percpu::current().$field
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    use super::*;
+
+    #[test_case]
+    fn gs_points_at_the_current_cpu() {
+        without_interrupts(|| {
+            let percpu = current();
+            assert_eq!(GsBase::read(), VirtAddr::from_ptr(percpu));
+            assert!(ptr::eq(get(percpu!(cpu)).unwrap(), percpu));
+        });
+    }
+
+    #[test_case]
+    fn tests_run_on_the_boot_cpu() {
+        assert_eq!(percpu!(cpu), 0);
+        assert_eq!(percpu!(apic_id), apic::id());
+    }
+
+    #[test_case]
+    fn syscall_fields_are_reached_through_gs() {
+        without_interrupts(|| {
+            let kernel_stack: u64;
+            let scratch: u64;
+            unsafe {
+                asm!(
+                    "mov {stack}, gs:[{stack_offset}]",
+                    "mov qword ptr gs:[{scratch_offset}], 0x1234",
+                    "mov {scratch}, gs:[{scratch_offset}]",
+                    stack = out(reg) kernel_stack,
+                    scratch = out(reg) scratch,
+                    stack_offset = const KERNEL_STACK_OFFSET,
+                    scratch_offset = const SYSCALL_SCRATCH_OFFSET,
+                    options(nostack, preserves_flags),
+                );
+            }
+            assert_eq!(VirtAddr::new(kernel_stack), percpu!(kernel_stack));
+            assert_eq!(scratch, 0x1234);
+            assert_eq!(percpu!(syscall_scratch).swap(0, Ordering::Relaxed), 0x1234);
+        });
+    }
+
+    #[test_case]
+    fn every_cpu_has_its_own_tss() {
+        let cpus: alloc::vec::Vec<_> = (0..MAX_CPUS).filter_map(get).collect();
+        for (i, a) in cpus.iter().enumerate() {
+            for b in &cpus[i + 1..] {
+                assert!(!ptr::eq(a.tss, b.tss));
+                assert_ne!(a.kernel_stack, b.kernel_stack);
+                let (a_ist, b_ist) = (a.tss.interrupt_stack_table, b.tss.interrupt_stack_table);
+                assert_ne!(a_ist, b_ist);
+            }
+        }
+    }
+}
@@ -28,9 +28,10 @@
 //!   - 111 (7): Square wave generator, same as 011
 //! - Bits 0: Binaryh/BCD mode (0 = 16-bit binary, 1 = four-digit BCD)
 
-use spin::mutex::Mutex;
 use x86_64::instructions::port::Port;
 
+use crate::sync::{IrqSpinLock, IrqSpinLockGuard};
+
 pub struct Pic {
     comm: Port<u8>,
     data: Port<u8>,
@@ -53,6 +54,7 @@ impl Pic {
         }
     }
 
+    #[cfg(test)]
     #[inline(always)]
     pub fn read(&mut self) -> u8 {
         unsafe { self.data.read() }
@@ -74,15 +76,95 @@ impl Pic {
 pub struct PicPair {
     pub pic_1: Pic,
     pub pic_2: Pic,
+    /// Interrupt masks of both PICs, master in the low byte. A set bit masks the line.
+    masks: u16,
 }
 
 const INIT_CMD: u8 = 0x11;
 const INTERRUPT_END_CMD: u8 = 0x20;
 const MODE_8086: u8 = 0x01;
+const READ_IRR_CMD: u8 = 0x0a;
+const READ_ISR_CMD: u8 = 0x0b;
+
+/// The line the slave PIC is chained to on the master.
+pub const CASCADE_LINE: u8 = 2;
 
 impl PicPair {
+    /// Everything but the cascade starts out masked; lines are unmasked as drivers claim them.
     pub const fn new(pic_1: Pic, pic_2: Pic) -> Self {
-        Self { pic_1, pic_2 }
+        Self {
+            pic_1,
+            pic_2,
+            masks: !(1 << CASCADE_LINE),
+        }
+    }
+
+    fn write_masks(&mut self) {
+        let [master, slave] = self.masks.to_le_bytes();
+        self.pic_1.write(master);
+        self.pic_2.write(slave);
+    }
+
+    /// Masks IRQ `line` (0-15).
+    pub fn mask(&mut self, line: u8) {
+        self.masks |= 1 << line;
+        self.write_masks();
+    }
+
+    /// Unmasks IRQ `line` (0-15).
+    pub fn unmask(&mut self, line: u8) {
+        self.masks &= !(1 << line);
+        self.write_masks();
+    }
+
+    #[cfg(test)]
+    pub fn is_masked(&self, line: u8) -> bool {
+        self.masks & (1 << line) != 0
+    }
+
+    /// Masks every line on both PICs, for when the APIC takes over.
+    pub fn disable(&mut self) {
+        self.masks = 0xffff;
+        self.write_masks();
+    }
+
+    fn read_register(&mut self, command: u8) -> u16 {
+        self.pic_1.command(command);
+        self.pic_2.command(command);
+        unsafe { u16::from_le_bytes([self.pic_1.comm.read(), self.pic_2.comm.read()]) }
+    }
+
+    /// Interrupt request register: lines that have raised an interrupt which hasn't been
+    /// delivered to the CPU yet. Master in the low byte.
+    pub fn read_irr(&mut self) -> u16 {
+        self.read_register(READ_IRR_CMD)
+    }
+
+    /// In-service register: lines whose interrupt has been delivered but not yet acknowledged
+    /// with an EOI. Master in the low byte.
+    pub fn read_isr(&mut self) -> u16 {
+        self.read_register(READ_ISR_CMD)
+    }
+
+    /// Checks whether an interrupt on IRQ 7 or 15 is spurious, and if so, sends whatever EOI it
+    /// needs.
+    ///
+    /// A PIC signals its lowest priority line (7) when the request that made it interrupt the
+    /// CPU went away before the CPU acknowledged it, without setting the line's ISR bit. Such an
+    /// interrupt must not get an EOI, since that would end some other, real interrupt. If it
+    /// came from the slave, the master did see a real interrupt on the cascade line, so the
+    /// master (only) still needs its EOI.
+    pub fn check_spurious(&mut self, line: u8) -> bool {
+        if line != 7 && line != 15 {
+            return false;
+        }
+        if self.read_isr() & (1 << line) != 0 {
+            return false;
+        }
+        if line == 15 {
+            self.pic_1.command(INTERRUPT_END_CMD);
+        }
+        true
     }
 
     pub fn init(&mut self) {
@@ -95,10 +177,6 @@ impl PicPair {
         // source: [pic8295](https://docs.rs/pic8259/latest)
         let mut wait_port: Port<u8> = Port::new(0x80);
 
-        // TODO: do I need to do this?
-        let mask1 = self.pic_1.read();
-        let mask2 = self.pic_2.read();
-
         unsafe {
             // Send initialization commands
             self.pic_1.command(INIT_CMD);
@@ -123,11 +201,10 @@ impl PicPair {
             wait_port.write(0);
             self.pic_2.write(MODE_8086);
             wait_port.write(0);
-
-            // Restore saved masks
-            self.pic_1.write(mask1);
-            self.pic_2.write(mask2);
         }
+
+        // Initialization resets the masks, so apply ours again.
+        self.write_masks();
     }
 
     pub fn end_interrupt(&mut self, id: u8) {
@@ -145,22 +222,13 @@ impl PicPair {
 pub const PIC_1_OFFSET: u8 = 0x20;
 pub const PIC_2_OFFSET: u8 = 0x28;
 
-static mut PICS: Mutex<PicPair> = Mutex::new(PicPair::new(
+static PICS: IrqSpinLock<PicPair> = IrqSpinLock::new(PicPair::new(
     Pic::new(0x20, 0x21, PIC_1_OFFSET),
     Pic::new(0xa0, 0xa1, PIC_2_OFFSET),
 ));
 
-pub fn acquire_pics<'a>() -> spin::MutexGuard<'a, PicPair> {
-    unsafe { PICS.lock() }
-}
-
-pub fn read_isr() -> u8 {
-    let mut pics = acquire_pics();
-    let pic = &mut pics.pic_1;
-    unsafe {
-        pic.command(0x0b);
-        pic.comm.read()
-    }
+pub fn acquire_pics<'a>() -> IrqSpinLockGuard<'a, PicPair> {
+    PICS.lock()
 }
 
 pub fn init() {
@@ -170,3 +238,67 @@ pub fn init() {
 pub fn end_interrupt(id: u8) {
     acquire_pics().end_interrupt(id);
 }
+
+pub fn mask(line: u8) {
+    acquire_pics().mask(line);
+}
+
+pub fn unmask(line: u8) {
+    acquire_pics().unmask(line);
+}
+
+/// Whether `line` is masked.
+#[cfg(test)]
+pub fn is_masked(line: u8) -> bool {
+    acquire_pics().is_masked(line)
+}
+
+/// Masks all lines, see [`PicPair::disable`].
+pub fn disable() {
+    acquire_pics().disable();
+}
+
+pub fn read_irr() -> u16 {
+    acquire_pics().read_irr()
+}
+
+pub fn read_isr() -> u16 {
+    acquire_pics().read_isr()
+}
+
+/// See [`PicPair::check_spurious`].
+pub fn check_spurious(line: u8) -> bool {
+    acquire_pics().check_spurious(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn mask_and_unmask() {
+        // Nothing is wired to IRQ 5 on the machines we run on.
+        let line = 5;
+        let was_masked = is_masked(line);
+
+        unmask(line);
+        assert!(!is_masked(line));
+        assert_eq!(acquire_pics().pic_1.read() & (1 << line), 0);
+
+        mask(line);
+        assert!(is_masked(line));
+        assert_ne!(acquire_pics().pic_1.read() & (1 << line), 0);
+
+        if !was_masked {
+            unmask(line);
+        }
+    }
+
+    #[test_case]
+    fn only_lines_7_and_15_can_be_spurious() {
+        let mut pics = acquire_pics();
+        for line in (0..16).filter(|&line| line != 7 && line != 15) {
+            assert!(!pics.check_spurious(line));
+        }
+    }
+}
@@ -1,15 +1,103 @@
+//! 8254 PIT driver (see pic.rs for the registers). Channel 0 interrupts at the tick rate, and
+//! channel 2, which is not wired to an interrupt, is used for busy-waiting.
+
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
 use x86_64::instructions::port::Port;
 
-pub fn init() {
-    let mut port_43: Port<u8> = Port::new(0x43);
-    let mut port_40: Port<u8> = Port::new(0x40);
-    let data = (1 << 2) | (3 << 4);
+use crate::{
+    irq::{self, IrqReturn},
+    sync::IrqSpinLock,
+    time,
+};
+
+/// Frequency of the clock the channels count down, in Hz.
+pub const FREQUENCY: u32 = 1_193_182;
+
+/// IRQ line of channel 0.
+pub const IRQ: u8 = 0;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator).
+const COMMAND_CHANNEL_0_RATE: u8 = (3 << 4) | (2 << 1);
+/// Channel 0, lobyte/hibyte access, mode 0, which holds the output low until a count is written.
+const COMMAND_CHANNEL_0_STOP: u8 = 3 << 4;
+/// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count).
+const COMMAND_CHANNEL_2_ONE_SHOT: u8 = (2 << 6) | (3 << 4);
+
+/// Keyboard controller port B, which also gates channel 2.
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE_2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT_2: u8 = 1 << 5;
+
+/// Channel 0 divisor, 0 until [`init`].
+static DIVISOR: AtomicU32 = AtomicU32::new(0);
+
+/// Channel 2 can only do one wait at a time.
+static CHANNEL_2_LOCK: IrqSpinLock<()> = IrqSpinLock::new(());
+
+fn tick(_ctx: usize) -> IrqReturn {
+    time::tick();
+    IrqReturn::Handled
+}
+
+/// Starts channel 0 interrupting about `hz` times a second, as close as a whole divisor of
+/// [`FREQUENCY`] gets.
+pub fn init(hz: u32) {
+    let divisor = (FREQUENCY + hz / 2) / hz.max(1);
+    let divisor = divisor.clamp(2, u16::MAX as u32);
+    DIVISOR.store(divisor, Ordering::Relaxed);
+
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel_0: Port<u8> = Port::new(CHANNEL_0);
     unsafe {
-        port_43.write(data);
+        command.write(COMMAND_CHANNEL_0_RATE);
+        channel_0.write((divisor & 0xff) as u8);
+        channel_0.write((divisor >> 8) as u8);
     }
-    let divisor = 1193182 / 100;
+
+    irq::register(IRQ, "pit", tick, 0).expect("Failed to register the PIT interrupt");
+}
+
+/// Stops channel 0 interrupting, for when another timer takes over the tick. The tick rate
+/// stays what [`init`] set.
+pub fn stop() {
+    irq::unregister(IRQ, tick, 0).ok();
+    unsafe { Port::new(COMMAND).write(COMMAND_CHANNEL_0_STOP) };
+}
+
+/// Clock cycles between two channel 0 interrupts.
+pub fn divisor() -> u32 {
+    DIVISOR.load(Ordering::Relaxed)
+}
+
+/// Busy-waits for `cycles` cycles of [`FREQUENCY`] on channel 2. Works with interrupts
+/// disabled, and before [`init`].
+pub fn wait(cycles: u16) {
+    let _lock = CHANNEL_2_LOCK.lock();
+    let mut port_b: Port<u8> = Port::new(PORT_B);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel_2: Port<u8> = Port::new(CHANNEL_2);
     unsafe {
-        port_40.write((divisor & 0xff) as u8);
-        port_40.write((divisor >> 8) as u8);
+        let saved = port_b.read();
+        // Stop the channel while it is set up, and keep the speaker quiet.
+        let stopped = saved & !(PORT_B_GATE_2 | PORT_B_SPEAKER);
+        port_b.write(stopped);
+        command.write(COMMAND_CHANNEL_2_ONE_SHOT);
+        channel_2.write((cycles & 0xff) as u8);
+        channel_2.write((cycles >> 8) as u8);
+        port_b.write(stopped | PORT_B_GATE_2);
+        // The output goes high once the count reaches zero.
+        while port_b.read() & PORT_B_OUT_2 == 0 {
+            spin_loop();
+        }
+        port_b.write(saved);
     }
 }
@@ -0,0 +1,283 @@
+//! Powering off and rebooting the machine.
+//!
+//! Shutdown puts the machine into the ACPI S5 (soft off) sleep state: the sleep type for S5 comes
+//! from the `\_S5` package in the DSDT, and is written to the PM1 control registers together with
+//! the sleep enable bit. Reboot tries the FADT reset register first, then the 8042 keyboard
+//! controller's reset line, and finally triple faults the CPU, which resets it on any PC.
+
+use core::fmt;
+
+use x86_64::{
+    instructions::{hlt, interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+    VirtAddr,
+};
+
+use crate::{
+    acpi::{self, Fadt, GenericAddress},
+    println, smp, time,
+};
+
+// PM1 control register bits.
+const SCI_EN: u64 = 1 << 0;
+const SLP_TYP_SHIFT: u64 = 10;
+const SLP_TYP_MASK: u64 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u64 = 1 << 13;
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+// AML opcodes the `\_S5` scan understands.
+const AML_NAME: u8 = 0x08;
+const AML_PACKAGE: u8 = 0x12;
+const AML_ZERO: u8 = 0x00;
+const AML_ONE: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_WORD_PREFIX: u8 = 0x0b;
+const AML_DWORD_PREFIX: u8 = 0x0c;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    NoAcpi,
+    NoPm1Control,
+    NoDsdt,
+    /// The DSDT has no `\_S5` package we could make sense of.
+    NoS5,
+    /// Everything was written, but the machine is still running.
+    StillRunning,
+}
+
+impl fmt::Display for PowerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerError::NoAcpi => write!(f, "no ACPI FADT"),
+            PowerError::NoPm1Control => write!(f, "no PM1 control register"),
+            PowerError::NoDsdt => write!(f, "no valid DSDT"),
+            PowerError::NoS5 => write!(f, "no \\_S5 object in the DSDT"),
+            PowerError::StillRunning => write!(f, "the machine did not power off"),
+        }
+    }
+}
+
+/// Reads an AML integer constant at the start of `aml`, returning it and its encoded length.
+fn aml_integer(aml: &[u8]) -> Option<(u64, usize)> {
+    let le = |len: usize| {
+        let bytes = aml.get(1..1 + len)?;
+        Some((
+            bytes
+                .iter()
+                .rev()
+                .fold(0u64, |value, byte| value << 8 | *byte as u64),
+            1 + len,
+        ))
+    };
+    match *aml.first()? {
+        AML_ZERO => Some((0, 1)),
+        AML_ONE => Some((1, 1)),
+        AML_BYTE_PREFIX => le(1),
+        AML_WORD_PREFIX => le(2),
+        AML_DWORD_PREFIX => le(4),
+        _ => None,
+    }
+}
+
+/// Finds `Name (\_S5, Package () { SLP_TYPa, SLP_TYPb, ... })` in `aml` and returns the two
+/// sleep types. This is a plain byte scan, not an AML interpreter, but firmware virtually always
+/// defines `\_S5` this way.
+pub fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|(_, name)| name == b"_S5_")
+        .find_map(|(at, _)| {
+            // Preceded by NameOp, possibly with a root prefix in between.
+            let is_name = match at {
+                0 => false,
+                1 => aml[0] == AML_NAME,
+                _ => aml[at - 1] == AML_NAME || (aml[at - 1] == b'\\' && aml[at - 2] == AML_NAME),
+            };
+            if !is_name {
+                return None;
+            }
+
+            let package = aml.get(at + 4..)?;
+            if *package.first()? != AML_PACKAGE {
+                return None;
+            }
+            // PkgLength: the top two bits of the lead byte count the bytes that follow it.
+            let pkg_length_size = 1 + (*package.get(1)? >> 6) as usize;
+            // Then NumElements, then the elements.
+            let mut elements = package.get(1 + pkg_length_size + 1..)?;
+            let (typ_a, len) = aml_integer(elements)?;
+            elements = &elements[len..];
+            let typ_b = aml_integer(elements).map_or(0, |(value, _)| value);
+            Some((typ_a as u8, typ_b as u8))
+        })
+}
+
+/// Switches the chipset from legacy (SMM) to ACPI mode, if the firmware left it in the former.
+fn enable_acpi(fadt: &Fadt, pm1a: &GenericAddress) {
+    let enabled = || !matches!(unsafe { pm1a.read() }, Some(value) if value & SCI_EN == 0);
+    if enabled() || fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    unsafe {
+        Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable);
+    }
+    for _ in 0..300 {
+        if enabled() {
+            return;
+        }
+        time::mdelay(1);
+    }
+    println!("ACPI mode could not be enabled, trying to power off anyway.");
+}
+
+fn acpi_shutdown() -> Result<(), PowerError> {
+    let acpi = acpi::get().ok_or(PowerError::NoAcpi)?;
+    let fadt = acpi.fadt.as_ref().ok_or(PowerError::NoAcpi)?;
+    let pm1a = fadt.pm1a_control.ok_or(PowerError::NoPm1Control)?;
+    let dsdt = acpi.dsdt().ok_or(PowerError::NoDsdt)?;
+    let (typ_a, typ_b) = find_s5(dsdt.data()).ok_or(PowerError::NoS5)?;
+
+    enable_acpi(fadt, &pm1a);
+
+    let controls = [(Some(pm1a), typ_a), (fadt.pm1b_control, typ_b)];
+    let controls = controls
+        .iter()
+        .filter_map(|(control, typ)| Some((control.as_ref()?, *typ as u64)));
+    unsafe {
+        // The sleep type goes into both registers before either gets the enable bit.
+        for (control, typ) in controls.clone() {
+            let value = control.read().unwrap_or(0) & !(SLP_TYP_MASK | SLP_EN);
+            control.write(value | typ << SLP_TYP_SHIFT);
+        }
+        for (control, _) in controls {
+            control.write(control.read().unwrap_or(0) | SLP_EN);
+        }
+    }
+
+    time::mdelay(1000);
+    Err(PowerError::StillRunning)
+}
+
+/// Stops the CPU for good.
+fn halt() -> ! {
+    loop {
+        interrupts::disable();
+        hlt();
+    }
+}
+
+/// Powers the machine off. If that doesn't work, halts it.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    println!("Powering off.");
+    smp::park_others();
+    if let Err(reason) = acpi_shutdown() {
+        println!("ACPI power off failed: {}.", reason);
+    }
+    println!("It is now safe to turn off the computer.");
+    halt()
+}
+
+/// Resets the machine.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    println!("Rebooting.");
+    smp::park_others();
+
+    let fadt = acpi::get().and_then(|acpi| acpi.fadt.as_ref());
+    if let Some(fadt) = fadt.filter(|fadt| fadt.flags & Fadt::RESET_REG_SUP != 0) {
+        if let Some(reset) = fadt.reset_register {
+            if unsafe { reset.write(fadt.reset_value as u64) } {
+                time::mdelay(500);
+            }
+        }
+    }
+
+    // Pulse the CPU reset line through the keyboard controller, once it takes commands.
+    let mut status: Port<u8> = Port::new(KBC_STATUS);
+    let mut command: Port<u8> = Port::new(KBC_COMMAND);
+    unsafe {
+        for _ in 0..0x10000 {
+            if status.read() & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        command.write(KBC_PULSE_RESET);
+    }
+    time::mdelay(500);
+
+    // With an empty IDT, the breakpoint becomes a double fault and then a triple fault.
+    unsafe {
+        lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::new(0),
+        });
+    }
+    interrupts::int3();
+    halt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn s5_package() {
+        // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero })
+        let aml = [
+            0x10,
+            0x08,
+            AML_NAME,
+            b'\\',
+            b'_',
+            b'S',
+            b'5',
+            b'_',
+            AML_PACKAGE,
+            0x08,
+            0x04,
+            AML_BYTE_PREFIX,
+            0x05,
+            AML_ZERO,
+            AML_ZERO,
+            AML_ZERO,
+        ];
+        assert_eq!(find_s5(&aml), Some((5, 0)));
+
+        // Name (_S5, Package (0x02) { 0x07, 0x0107 })
+        let aml = [
+            AML_NAME,
+            b'_',
+            b'S',
+            b'5',
+            b'_',
+            AML_PACKAGE,
+            0x07,
+            0x02,
+            AML_ONE,
+            AML_WORD_PREFIX,
+            0x07,
+            0x01,
+        ];
+        assert_eq!(find_s5(&aml), Some((1, 7)));
+    }
+
+    #[test_case]
+    fn s5_needs_a_name() {
+        // A method called _S5_ isn't what we are looking for.
+        let aml = [0x14, 0x09, b'_', b'S', b'5', b'_', 0x00];
+        assert_eq!(find_s5(&aml), None);
+    }
+
+    #[test_case]
+    fn qemu_dsdt_has_s5() {
+        let Some(dsdt) = acpi::get().and_then(|acpi| acpi.dsdt()) else {
+            return;
+        };
+        assert!(find_s5(dsdt.data()).is_some());
+    }
+}
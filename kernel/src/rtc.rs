@@ -0,0 +1,460 @@
+//! CMOS real-time clock.
+//!
+//! The RTC keeps the date and time, normally in UTC, while the machine is off. Its registers
+//! are in the CMOS, read by writing their index to [`INDEX`] and then reading [`DATA`]. Status
+//! register B says whether the values are BCD or binary, and whether the hour is in 24-hour
+//! form or 12-hour form with bit 7 set after noon; the firmware picks, so [`read`] handles all
+//! of them. The RTC updates its registers once a second, and they are inconsistent while it
+//! does, so they are read when no update is in progress, until two reads in a row agree. The
+//! year only has two digits; the FADT says which CMOS register has the century, if any.
+//!
+//! The RTC can also interrupt on IRQ 8, periodically at a power of two rate from 2 to 8192 Hz
+//! (see [`start_periodic`]), and when the time of day matches an alarm (see [`set_alarm`]).
+//! Register C says why it interrupted, and no more interrupts come until it has been read.
+//! An alarm at the start of every minute sets the wall clock again, so that it doesn't drift
+//! away from the RTC's seconds.
+
+use core::{
+    fmt,
+    hint::spin_loop,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::instructions::port::Port;
+
+use crate::{
+    acpi,
+    irq::{self, IrqReturn},
+    println,
+    sync::IrqSpinLock,
+    time::{self, DateTime},
+};
+
+pub const IRQ: u8 = 8;
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+/// Set in the index to keep NMIs masked, which is left clear.
+const INDEX_NMI_DISABLE: u8 = 1 << 7;
+
+// Register indices.
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATING: u8 = 1 << 7;
+#[cfg(test)]
+const STATUS_A_RATE_MASK: u8 = 0x0f;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_ALARM: u8 = 1 << 5;
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+const STATUS_C_ALARM: u8 = 1 << 5;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+/// Set along with the flag of whatever raised the interrupt.
+const STATUS_C_IRQ: u8 = 1 << 7;
+
+const HOUR_PM: u8 = 1 << 7;
+/// Any value from here up in an alarm register matches every value.
+const ALARM_ANY: u8 = 0xc0;
+
+/// Rate of the RTC's time base, which the periodic interrupt divides.
+#[cfg(test)]
+const BASE_HZ: u32 = 32_768;
+#[cfg(test)]
+const MIN_HZ: u32 = 2;
+#[cfg(test)]
+const MAX_HZ: u32 = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// Not a power of two from 2 to 8192 Hz.
+    #[cfg(test)]
+    InvalidRate(u32),
+    InvalidAlarm,
+}
+
+impl fmt::Display for RtcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(test)]
+            RtcError::InvalidRate(hz) => write!(f, "no periodic rate of {} Hz", hz),
+            RtcError::InvalidAlarm => write!(f, "invalid alarm time"),
+        }
+    }
+}
+
+/// A time of day for [`set_alarm`], where `None` matches any value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Alarm {
+    pub hour: Option<u8>,
+    pub minute: Option<u8>,
+    pub second: Option<u8>,
+}
+
+impl Alarm {
+    fn is_valid(&self) -> bool {
+        self.hour.is_none_or(|hour| hour < 24)
+            && self.minute.is_none_or(|minute| minute < 60)
+            && self.second.is_none_or(|second| second < 60)
+    }
+}
+
+/// How the RTC stores its values, from status register B.
+#[derive(Debug, Clone, Copy)]
+struct Format {
+    binary: bool,
+    hour_24: bool,
+}
+
+impl Format {
+    fn decode(self, value: u8) -> u8 {
+        if self.binary {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0x0f)
+        }
+    }
+
+    fn encode(self, value: u8) -> u8 {
+        if self.binary {
+            value
+        } else {
+            ((value / 10) << 4) | (value % 10)
+        }
+    }
+
+    /// From 0 to 23, whatever form the RTC keeps it in.
+    fn decode_hour(self, value: u8) -> u8 {
+        let hour = self.decode(value & !HOUR_PM);
+        match (self.hour_24, value & HOUR_PM != 0) {
+            (true, _) => hour,
+            // 12 AM is midnight, and 12 PM noon.
+            (false, pm) => hour % 12 + if pm { 12 } else { 0 },
+        }
+    }
+
+    fn encode_hour(self, hour: u8) -> u8 {
+        if self.hour_24 {
+            return self.encode(hour);
+        }
+        let pm = if hour >= 12 { HOUR_PM } else { 0 };
+        let hour = match hour % 12 {
+            0 => 12,
+            hour => hour,
+        };
+        self.encode(hour) | pm
+    }
+}
+
+/// Callbacks for the RTC's interrupts, run in interrupt context.
+struct Handlers {
+    periodic: Option<fn()>,
+    alarm: Option<fn()>,
+}
+
+/// The index port is shared by all the registers.
+static CMOS: IrqSpinLock<()> = IrqSpinLock::new(());
+static HANDLERS: IrqSpinLock<Handlers> = IrqSpinLock::new(Handlers {
+    periodic: None,
+    alarm: None,
+});
+static PERIODIC_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// Reads a CMOS register. The CMOS lock must be held.
+fn read_register(index: u8) -> u8 {
+    let mut index_port: Port<u8> = Port::new(INDEX);
+    let mut data: Port<u8> = Port::new(DATA);
+    unsafe {
+        index_port.write(index & !INDEX_NMI_DISABLE);
+        data.read()
+    }
+}
+
+/// Writes a CMOS register. The CMOS lock must be held.
+fn write_register(index: u8, value: u8) {
+    let mut index_port: Port<u8> = Port::new(INDEX);
+    let mut data: Port<u8> = Port::new(DATA);
+    unsafe {
+        index_port.write(index & !INDEX_NMI_DISABLE);
+        data.write(value);
+    }
+}
+
+fn format() -> Format {
+    let status_b = read_register(STATUS_B);
+    Format {
+        binary: status_b & STATUS_B_BINARY != 0,
+        hour_24: status_b & STATUS_B_24_HOUR != 0,
+    }
+}
+
+/// CMOS index of the century, if the FADT names one.
+fn century_register() -> Option<u8> {
+    acpi::get()?
+        .fadt
+        .as_ref()
+        .map(|fadt| fadt.century)
+        .filter(|&index| index != 0)
+}
+
+/// The date and time registers, the century last, read in one go between two updates.
+fn read_registers(century: Option<u8>) -> [u8; 7] {
+    while read_register(STATUS_A) & STATUS_A_UPDATING != 0 {
+        spin_loop();
+    }
+    let [second, minute, hour, day, month, year] =
+        [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(read_register);
+    let century = century.map_or(0, read_register);
+    [second, minute, hour, day, month, year, century]
+}
+
+/// Reads the date and time.
+pub fn read() -> DateTime {
+    let _cmos = CMOS.lock();
+    let century_index = century_register();
+    let mut registers = read_registers(century_index);
+    loop {
+        let again = read_registers(century_index);
+        if again == registers {
+            break;
+        }
+        registers = again;
+    }
+    let [second, minute, hour, day, month, year, century] = registers;
+    let format = format();
+    let century = match century_index {
+        Some(_) => format.decode(century) as u16,
+        None => 20,
+    };
+    DateTime {
+        year: century * 100 + format.decode(year) as u16,
+        month: format.decode(month),
+        day: format.decode(day),
+        hour: format.decode_hour(hour),
+        minute: format.decode(minute),
+        second: format.decode(second),
+    }
+}
+
+/// Calls `callback` from the RTC interrupt `hz` times a second, which must be a power of two
+/// from 2 to 8192. Replaces the callback and rate set before.
+#[cfg(test)]
+pub fn start_periodic(hz: u32, callback: fn()) -> Result<(), RtcError> {
+    if !hz.is_power_of_two() || !(MIN_HZ..=MAX_HZ).contains(&hz) {
+        return Err(RtcError::InvalidRate(hz));
+    }
+    // The rate is `BASE_HZ >> (rate - 1)`.
+    let rate = (BASE_HZ / hz).trailing_zeros() as u8 + 1;
+    HANDLERS.lock().periodic = Some(callback);
+    let _cmos = CMOS.lock();
+    let status_a = read_register(STATUS_A);
+    write_register(STATUS_A, (status_a & !STATUS_A_RATE_MASK) | rate);
+    let status_b = read_register(STATUS_B);
+    write_register(STATUS_B, status_b | STATUS_B_PERIODIC);
+    Ok(())
+}
+
+#[cfg(test)]
+pub fn stop_periodic() {
+    {
+        let _cmos = CMOS.lock();
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b & !STATUS_B_PERIODIC);
+    }
+    HANDLERS.lock().periodic = None;
+}
+
+/// Periodic interrupts since boot.
+#[cfg(test)]
+pub fn periodic_interrupts() -> u64 {
+    PERIODIC_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Calls `callback` from the RTC interrupt whenever the time of day matches `alarm`, in the
+/// RTC's time zone. Replaces the alarm set before.
+pub fn set_alarm(alarm: Alarm, callback: fn()) -> Result<(), RtcError> {
+    if !alarm.is_valid() {
+        return Err(RtcError::InvalidAlarm);
+    }
+    HANDLERS.lock().alarm = Some(callback);
+    let _cmos = CMOS.lock();
+    let format = format();
+    let encode = |value: Option<u8>, encode: fn(Format, u8) -> u8| {
+        value.map_or(ALARM_ANY, |value| encode(format, value))
+    };
+    write_register(SECONDS_ALARM, encode(alarm.second, Format::encode));
+    write_register(MINUTES_ALARM, encode(alarm.minute, Format::encode));
+    write_register(HOURS_ALARM, encode(alarm.hour, Format::encode_hour));
+    let status_b = read_register(STATUS_B);
+    write_register(STATUS_B, status_b | STATUS_B_ALARM);
+    Ok(())
+}
+
+#[cfg(test)]
+pub fn cancel_alarm() {
+    {
+        let _cmos = CMOS.lock();
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b & !STATUS_B_ALARM);
+    }
+    HANDLERS.lock().alarm = None;
+}
+
+/// Every minute, on the minute.
+const EVERY_MINUTE: Alarm = Alarm {
+    hour: None,
+    minute: None,
+    second: Some(0),
+};
+
+/// Sets the wall clock from the RTC, which has just moved on to a new minute.
+fn sync_wall_clock() {
+    let now = read();
+    if now.is_valid() {
+        time::set_wall_clock(now);
+    }
+}
+
+fn interrupt(_ctx: usize) -> IrqReturn {
+    let status_c = {
+        let _cmos = CMOS.lock();
+        read_register(STATUS_C)
+    };
+    if status_c & STATUS_C_IRQ == 0 {
+        return IrqReturn::NotMine;
+    }
+    let (periodic, alarm) = {
+        let handlers = HANDLERS.lock();
+        (handlers.periodic, handlers.alarm)
+    };
+    if status_c & STATUS_C_PERIODIC != 0 {
+        PERIODIC_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+        periodic.inspect(|callback| callback());
+    }
+    if status_c & STATUS_C_ALARM != 0 {
+        alarm.inspect(|callback| callback());
+    }
+    IrqReturn::Handled
+}
+
+/// Reads the date to set the wall clock from, and takes IRQ 8 for the alarm that keeps it set.
+/// Needs the ACPI tables for the century.
+pub fn init() {
+    {
+        let _cmos = CMOS.lock();
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b & !(STATUS_B_PERIODIC | STATUS_B_ALARM));
+        // Anything the firmware left pending.
+        read_register(STATUS_C);
+    }
+    irq::register(IRQ, "rtc", interrupt, 0).expect("Failed to register the RTC interrupt");
+
+    let now = read();
+    let format = {
+        let _cmos = CMOS.lock();
+        format()
+    };
+    if !now.is_valid() {
+        println!("RTC: invalid date {:?}", now);
+        return;
+    }
+    time::set_wall_clock(now);
+    set_alarm(EVERY_MINUTE, sync_wall_clock).expect("Invalid RTC alarm");
+    println!(
+        "RTC: {} ({}, {}-hour)",
+        now,
+        if format.binary { "binary" } else { "BCD" },
+        if format.hour_24 { 24 } else { 12 }
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::Duration;
+
+    const BCD_12_HOUR: Format = Format {
+        binary: false,
+        hour_24: false,
+    };
+
+    #[test_case]
+    fn values_are_decoded_in_every_format() {
+        assert_eq!(BCD_12_HOUR.decode(0x59), 59);
+        assert_eq!(BCD_12_HOUR.encode(59), 0x59);
+        let binary = Format {
+            binary: true,
+            hour_24: true,
+        };
+        assert_eq!(binary.decode(59), 59);
+        assert_eq!(binary.decode_hour(23), 23);
+        // 12 AM, 1 AM, 12 PM and 11 PM.
+        for (raw, hour) in [(0x12, 0), (0x01, 1), (0x92, 12), (0x91, 23)] {
+            assert_eq!(BCD_12_HOUR.decode_hour(raw), hour, "{:#x}", raw);
+            assert_eq!(BCD_12_HOUR.encode_hour(hour), raw);
+        }
+    }
+
+    #[test_case]
+    fn date_is_valid_and_follows_the_wall_clock() {
+        let date = read();
+        assert!(date.is_valid(), "{:?}", date);
+        assert!(date.year >= 2000, "{}", date);
+        let wall_clock = time::wall_clock().expect("Wall clock not set");
+        let drift = wall_clock.to_unix().abs_diff(date.to_unix());
+        assert!(drift <= 2, "RTC {}, wall clock {}", date, wall_clock);
+    }
+
+    #[test_case]
+    fn periodic_interrupt_fires() {
+        fn noop() {}
+        assert_eq!(start_periodic(100, noop), Err(RtcError::InvalidRate(100)));
+        assert_eq!(start_periodic(1, noop), Err(RtcError::InvalidRate(1)));
+        let before = periodic_interrupts();
+        start_periodic(256, noop).unwrap();
+        time::sleep(Duration::from_millis(100));
+        stop_periodic();
+        let fired = periodic_interrupts() - before;
+        assert!(fired >= 12, "{} interrupts", fired);
+    }
+
+    #[test_case]
+    fn alarms_are_checked() {
+        let alarm = Alarm {
+            hour: Some(24),
+            ..Alarm::default()
+        };
+        assert_eq!(set_alarm(alarm, || {}), Err(RtcError::InvalidAlarm));
+    }
+
+    #[test_case]
+    fn alarm_fires_at_its_time() {
+        static FIRED: AtomicU64 = AtomicU64::new(0);
+        fn fired() {
+            FIRED.fetch_add(1, Ordering::Relaxed);
+        }
+        let now = read();
+        if !now.is_valid() {
+            return;
+        }
+        let alarm = Alarm {
+            second: Some((now.second + 2) % 60),
+            ..Alarm::default()
+        };
+        set_alarm(alarm, fired).unwrap();
+        time::sleep(Duration::from_secs(3));
+        cancel_alarm();
+        set_alarm(EVERY_MINUTE, sync_wall_clock).unwrap();
+        assert_eq!(FIRED.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,1227 @@
+//! Kernel threads and a preemptive scheduler.
+//!
+//! Every thread has a stack of its own; the code that was running when [`init`] was called
+//! becomes the boot thread, which keeps the stack it was already on and stays on the boot CPU.
+//! Each CPU has a run queue of its own, and an idle thread that runs when its queue is empty.
+//! New threads go to the least busy CPU their affinity mask allows, and an idle CPU steals
+//! waiting threads from the others' queues before it halts.
+//!
+//! Which waiting thread runs next is up to the [`Policy`] of its [`Class`], chosen per thread:
+//! real-time threads by strict priority, fair threads by virtual runtime (see [`policy`]).
+//! The policy also decides, on every timer tick (see [`tick`]), and whenever a thread is
+//! queued, whether the running thread should make way. It is then switched out on the way out
+//! of the interrupt (see [`preempt`]). Every CPU ticks on its own local APIC timer, or with
+//! the PIT on a machine without one, which only has the one CPU. In tickless mode, a CPU's timer
+//! only goes off when the running thread's time slice ends (see [`slice_end`]), and the ticks
+//! in between are accounted all at once.
+//!
+//! A thread can also go to sleep until something wakes it up: it gets a [`Waker`] from
+//! [`prepare_block`], hands it to whoever will wake it, and calls [`block`]. A sleeping thread
+//! is in no run queue; only its waker knows where it is. See the sync module for the locks
+//! built on this.
+//!
+//! The switch itself is in switch.asm. The CPU's run queue stays locked across it and is
+//! unlocked by the thread switched to, so that no other CPU can steal the previous thread
+//! before its registers are saved. A thread that exits can't free the stack it is running on
+//! either, and one that may no longer run on this CPU can't be put on another CPU's queue
+//! while this one's is locked, so the thread switched to takes care of both. It also finishes
+//! putting a thread to sleep, since until its registers are saved, a waker on another CPU can't
+//! be allowed to queue it.
+
+pub mod policy;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    fmt,
+    ptr::{self, addr_of_mut},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, Ordering},
+};
+
+use spin::{Mutex, MutexGuard};
+use x86_64::{
+    instructions::interrupts::{self, without_interrupts},
+    structures::{idt::InterruptStackFrame, paging::PhysFrame},
+};
+
+use crate::{
+    apic,
+    frame::{self, FRAME_SIZE},
+    idt, paging, percpu, println,
+    smp::{self, CpuMask, MAX_CPUS},
+    time::{self, Instant},
+};
+
+pub use policy::{Class, Policy};
+use policy::{Fair, RealTime};
+
+/// Stack size of each thread, the same as the boot CPU's kernel stack.
+const STACK_FRAMES: usize = 16;
+
+/// Timer ticks a real-time thread may run before another one of the same priority gets a turn.
+pub const TIME_SLICE_TICKS: u32 = 1;
+
+/// Vector of the IPI that wakes up an idle CPU when a thread is queued on it, see [`kick`].
+pub const WAKE_VECTOR: u8 = 0xfb;
+
+extern "C" {
+    // Defined in switch.asm
+    fn context_switch(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_start();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    #[cfg(test)]
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum State {
+    Ready,
+    Running,
+    /// Going to sleep, but still on its CPU.
+    Blocked,
+    /// Off its CPU until it is woken up.
+    Sleeping,
+    Exited,
+}
+
+/// The state of a thread, which a [`Waker`] may change from another CPU.
+struct AtomicState(AtomicU8);
+
+impl AtomicState {
+    const fn new(state: State) -> Self {
+        Self(AtomicU8::new(state as u8))
+    }
+
+    fn load(&self) -> State {
+        match self.0.load(Ordering::Acquire) {
+            0 => State::Ready,
+            1 => State::Running,
+            2 => State::Blocked,
+            3 => State::Sleeping,
+            _ => State::Exited,
+        }
+    }
+
+    fn store(&self, state: State) {
+        self.0.store(state as u8, Ordering::Release);
+    }
+
+    /// Changes the state to `to` if it is `from`, and returns whether it was.
+    fn transition(&self, from: State, to: State) -> bool {
+        self.0
+            .compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+}
+
+/// A thread's kernel stack, freed with the thread.
+struct Stack {
+    start: PhysFrame,
+}
+
+impl Stack {
+    fn new() -> Self {
+        let start = frame::alloc_contiguous(STACK_FRAMES, FRAME_SIZE)
+            .expect("Out of memory for thread stacks");
+        Self { start }
+    }
+
+    fn top(&self) -> u64 {
+        paging::phys_to_virt(self.start.start_address()).as_u64() + STACK_FRAMES as u64 * FRAME_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        frame::free_contiguous(self.start, STACK_FRAMES);
+    }
+}
+
+pub struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: AtomicState,
+    /// CPUs the thread may run on.
+    affinity: CpuMask,
+    class: Class,
+    /// Stack pointer saved by `context_switch` while the thread isn't running.
+    rsp: u64,
+    /// `None` for threads running on a stack set up before the scheduler: the boot thread and
+    /// the idle threads of the APs. Never read, only freed along with the thread.
+    _stack: Option<Stack>,
+    /// Ticks left in the current time slice, for real-time threads.
+    slice: u32,
+    /// Weighted time the thread has been running for, for fair threads; see [`Fair`].
+    vruntime: u64,
+    /// Ticks the thread has been running for.
+    ticks: AtomicU64,
+}
+
+impl Thread {
+    fn new(name: &'static str, affinity: CpuMask, class: Class, stack: Option<Stack>) -> Box<Self> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Box::new(Self {
+            id: ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            name,
+            state: AtomicState::new(State::Ready),
+            affinity,
+            class,
+            rsp: 0,
+            _stack: stack,
+            slice: TIME_SLICE_TICKS,
+            vruntime: 0,
+            ticks: AtomicU64::new(0),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+}
+
+/// Threads waiting for one CPU, by class.
+struct RunQueue {
+    real_time: RealTime,
+    fair: Fair,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            real_time: RealTime::new(),
+            fair: Fair::new(),
+        }
+    }
+
+    fn policy(&mut self, class: Class) -> &mut dyn Policy {
+        match class {
+            Class::RealTime { .. } => &mut self.real_time,
+            Class::Fair { .. } => &mut self.fair,
+        }
+    }
+
+    /// The policies, in the order their threads get the CPU.
+    fn policies(&mut self) -> [&mut dyn Policy; 2] {
+        [&mut self.real_time, &mut self.fair]
+    }
+
+    /// Queues a thread arriving on this CPU.
+    fn enqueue(&mut self, mut thread: Box<Thread>) {
+        let policy = self.policy(thread.class);
+        policy.attach(&mut thread);
+        policy.push(thread);
+    }
+
+    fn pick_next(&mut self) -> Option<Box<Thread>> {
+        self.policies()
+            .into_iter()
+            .find_map(|policy| policy.pick_next())
+    }
+
+    /// Takes a waiting thread that may run on `cpu`, detached from this CPU.
+    fn steal(&mut self, cpu: usize) -> Option<Box<Thread>> {
+        self.policies().into_iter().find_map(|policy| {
+            let mut thread = policy.steal(cpu)?;
+            policy.detach(&mut thread);
+            Some(thread)
+        })
+    }
+
+    /// Whether `thread`, which was just queued, should preempt `current`.
+    fn preempts(&mut self, thread: &Thread, current: &Thread) -> bool {
+        match thread.class.rank().cmp(&current.class.rank()) {
+            core::cmp::Ordering::Less => true,
+            core::cmp::Ordering::Equal => self.policy(thread.class).preempts(thread, current),
+            core::cmp::Ordering::Greater => false,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.real_time.len() + self.fair.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn for_each(&self, mut f: impl FnMut(&Thread)) {
+        self.real_time.for_each(&mut f);
+        self.fair.for_each(&mut f);
+    }
+}
+
+/// Scheduler state of one CPU.
+struct Cpu {
+    /// Threads waiting for this CPU.
+    queue: Mutex<RunQueue>,
+    /// Runs when the queue is empty. Never in a queue itself.
+    idle: AtomicPtr<Thread>,
+    /// The thread switched away from, if it exited or may no longer run here.
+    previous: AtomicPtr<Thread>,
+    /// Whether a thread other than the idle thread is running.
+    busy: AtomicBool,
+    /// Set when the running thread should make way, see [`preempt`].
+    need_resched: AtomicBool,
+    switches: AtomicU64,
+    /// Threads this CPU took from other CPUs' queues.
+    steals: AtomicU64,
+    busy_ticks: AtomicU64,
+    idle_ticks: AtomicU64,
+    /// In tickless mode, nanoseconds since boot up to which ticks have been accounted.
+    ticked: AtomicU64,
+}
+
+impl Cpu {
+    const fn new() -> Self {
+        Self {
+            queue: Mutex::new(RunQueue::new()),
+            idle: AtomicPtr::new(ptr::null_mut()),
+            previous: AtomicPtr::new(ptr::null_mut()),
+            busy: AtomicBool::new(false),
+            need_resched: AtomicBool::new(false),
+            switches: AtomicU64::new(0),
+            steals: AtomicU64::new(0),
+            busy_ticks: AtomicU64::new(0),
+            idle_ticks: AtomicU64::new(0),
+            ticked: AtomicU64::new(0),
+        }
+    }
+
+    /// Threads running or waiting to run here, not counting the idle thread.
+    fn load(&self) -> usize {
+        without_interrupts(|| self.queue.lock().len()) + self.busy.load(Ordering::Relaxed) as usize
+    }
+}
+
+static CPUS: [Cpu; MAX_CPUS] = [const { Cpu::new() }; MAX_CPUS];
+
+/// Per-CPU scheduler statistics.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuStats {
+    pub switches: u64,
+    pub steals: u64,
+    /// Ticks spent running threads other than the idle thread.
+    pub busy_ticks: u64,
+    pub idle_ticks: u64,
+    pub queue_length: usize,
+}
+
+/// A snapshot of a thread, see [`threads`].
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: State,
+    /// The CPU it is running on or queued for.
+    pub cpu: usize,
+    pub affinity: CpuMask,
+    pub class: Class,
+    /// Ticks it has been running for.
+    pub ticks: u64,
+}
+
+impl ThreadInfo {
+    fn new(thread: &Thread, cpu: usize) -> Self {
+        Self {
+            id: thread.id,
+            name: thread.name,
+            state: thread.state.load(),
+            cpu,
+            affinity: thread.affinity,
+            class: thread.class,
+            ticks: thread.ticks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+type Entry = Box<dyn FnOnce() + Send>;
+
+/// Sets up a thread before it is started.
+pub struct Builder {
+    name: &'static str,
+    affinity: CpuMask,
+    class: Class,
+}
+
+impl Builder {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            affinity: CpuMask::all(),
+            class: Class::default(),
+        }
+    }
+
+    /// Restricts the thread to the CPUs in `mask`, which must include an online CPU.
+    pub fn affinity(mut self, mask: CpuMask) -> Self {
+        self.affinity = mask;
+        self
+    }
+
+    /// Sets the thread's scheduling class, fair with nice 0 by default.
+    pub fn class(mut self, class: Class) -> Self {
+        assert!(class.is_valid(), "Invalid scheduling class {:?}", class);
+        self.class = class;
+        self
+    }
+
+    /// Starts a thread that runs `f`, and exits when `f` returns.
+    pub fn spawn<F>(self, f: F) -> ThreadId
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let thread = self.build(f);
+        let id = thread.id;
+        without_interrupts(|| {
+            enqueue(thread);
+            preempt();
+        });
+        id
+    }
+
+    fn build<F>(self, f: F) -> Box<Thread>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let stack = Stack::new();
+        // What context_switch pops on the way to thread_start: see switch.asm.
+        let entry: *mut Entry = Box::into_raw(Box::new(Box::new(f)));
+        let frame = [
+            0,
+            0,
+            0,
+            entry as u64,
+            0,
+            0,
+            thread_start as unsafe extern "C" fn() as usize as u64,
+        ];
+        let rsp = stack.top() - size_of_val(&frame) as u64;
+        unsafe { (rsp as *mut [u64; 7]).write(frame) };
+
+        let mut thread = Thread::new(self.name, self.affinity, self.class, Some(stack));
+        thread.rsp = rsp;
+        thread
+    }
+}
+
+/// Starts a thread that runs `f` on any CPU, and exits when `f` returns.
+#[cfg(test)]
+pub fn spawn<F>(name: &'static str, f: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    Builder::new(name).spawn(f)
+}
+
+/// The thread running on this CPU, null if the scheduler isn't running here.
+fn current_ptr() -> *mut Thread {
+    percpu!(current_task).load(Ordering::Relaxed).cast()
+}
+
+/// Turns the code running on the boot CPU into the boot thread and starts the scheduler.
+/// Needs the per-CPU data, and the APs to be online.
+pub fn init() {
+    let boot = Thread::new("boot", CpuMask::single(0), Class::default(), None);
+    boot.state.store(State::Running);
+    percpu!(current_task).store(Box::into_raw(boot).cast(), Ordering::Relaxed);
+    CPUS[0].busy.store(true, Ordering::Relaxed);
+
+    let idle = Builder::new("idle")
+        .affinity(CpuMask::single(0))
+        .build(|| idle_loop());
+    CPUS[0].idle.store(Box::into_raw(idle), Ordering::Relaxed);
+
+    if smp::cpu_count() > 1 {
+        idt::register(WAKE_VECTOR, wake_interrupt).expect("Wake vector already in use");
+    }
+}
+
+/// Turns the code running on this AP into its idle thread. Called with interrupts disabled,
+/// once the per-CPU data is set up.
+pub fn run_idle() -> ! {
+    let cpu = percpu!(cpu);
+    let idle = Thread::new("idle", CpuMask::single(cpu), Class::default(), None);
+    idle.state.store(State::Running);
+    let idle = Box::into_raw(idle);
+    CPUS[cpu].idle.store(idle, Ordering::Relaxed);
+    percpu!(current_task).store(idle.cast(), Ordering::Relaxed);
+    idle_loop()
+}
+
+fn idle_loop() -> ! {
+    let cpu = smp::current_cpu();
+    let has_work = || !CPUS[cpu].queue.lock().is_empty();
+    loop {
+        interrupts::disable();
+        if !has_work() {
+            if let Some(thread) = steal(cpu) {
+                CPUS[cpu].queue.lock().enqueue(thread);
+            }
+        }
+        if has_work() {
+            schedule();
+            interrupts::enable();
+        } else {
+            // Anything that queues a thread here sends an interrupt, which ends the halt.
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// Takes a waiting thread that may run on `cpu` from another CPU's queue.
+fn steal(cpu: usize) -> Option<Box<Thread>> {
+    let count = smp::cpu_count();
+    (1..count)
+        .map(|offset| (cpu + offset) % count)
+        .find_map(|victim| CPUS[victim].queue.lock().steal(cpu))
+        .inspect(|_| {
+            CPUS[cpu].steals.fetch_add(1, Ordering::Relaxed);
+        })
+}
+
+/// Queues `thread` on the least busy CPU it may run on, and tells that CPU to reschedule if
+/// the thread should preempt the one running there, or in tickless mode, to reprogram its
+/// timer for the running thread's time slice. Interrupts must be disabled.
+fn enqueue(thread: Box<Thread>) {
+    let me = smp::current_cpu();
+    let target = thread
+        .affinity
+        .and(CpuMask::online())
+        .iter()
+        .min_by_key(|&cpu| (CPUS[cpu].load(), cpu != me))
+        .expect("Thread may not run on any online CPU");
+    let cpu = &CPUS[target];
+
+    let resched = {
+        let mut queue = cpu.queue.lock();
+        // Safe to look at with the queue locked, see `threads`.
+        let current = percpu::get(target)
+            .map_or(ptr::null_mut(), |percpu| {
+                percpu.current_task.load(Ordering::Relaxed)
+            })
+            .cast::<Thread>();
+        let idle = cpu.idle.load(Ordering::Relaxed);
+        let resched = match unsafe { current.as_ref() } {
+            Some(_) if current == idle => true,
+            Some(current) => queue.preempts(&thread, current),
+            None => false,
+        };
+        queue.enqueue(thread);
+        resched
+    };
+    if resched {
+        cpu.need_resched.store(true, Ordering::Relaxed);
+    }
+    if target == me {
+        time::reprogram();
+    } else if resched || time::is_tickless() {
+        kick(target);
+    }
+}
+
+/// Interrupts `cpu`, which makes it reschedule if it should, and reprogram its timer.
+pub fn kick(cpu: usize) {
+    if let (Some(lapic), Some(apic_id)) = (apic::local(), smp::apic_id(cpu)) {
+        lapic.send_ipi(apic_id, WAKE_VECTOR);
+    }
+}
+
+/// Called from thread_start in switch.asm with the closure passed to [`spawn`].
+#[no_mangle]
+extern "C" fn thread_entry(entry: *mut Entry) -> ! {
+    finish_switch();
+    interrupts::enable();
+    let entry = unsafe { Box::from_raw(entry) };
+    entry();
+    exit()
+}
+
+/// Switches to the thread that should run next on this CPU, which may be the current one if it
+/// can still run here, or the idle thread if there is nothing else. Interrupts must be
+/// disabled.
+fn schedule() {
+    let current = current_ptr();
+    if current.is_null() {
+        return;
+    }
+    let me = smp::current_cpu();
+    let cpu = &CPUS[me];
+    let idle = cpu.idle.load(Ordering::Relaxed);
+    let mut queue = cpu.queue.lock();
+    cpu.need_resched.store(false, Ordering::Relaxed);
+    unsafe {
+        // Not if it is blocked, exiting, or moving elsewhere.
+        let runnable = (*current).state.transition(State::Running, State::Ready);
+        if current != idle {
+            // Back in the queue, where the policy may well pick it again.
+            let mut previous = Box::from_raw(current);
+            let policy = queue.policy(previous.class);
+            if runnable && previous.affinity.contains(me) {
+                policy.push(previous);
+            } else {
+                policy.detach(&mut previous);
+                cpu.previous
+                    .store(Box::into_raw(previous), Ordering::Relaxed);
+            }
+        }
+        let next = queue.pick_next().map_or(idle, Box::into_raw);
+        (*next).state.store(State::Running);
+        if next == current {
+            drop(queue);
+            time::reprogram();
+            return;
+        }
+        if time::is_tickless() {
+            // The next thread's ticks start now.
+            cpu.ticked.store(time::now().as_nanos(), Ordering::Relaxed);
+        }
+        percpu!(current_task).store(next.cast(), Ordering::Relaxed);
+        cpu.busy.store(next != idle, Ordering::Relaxed);
+        cpu.switches.fetch_add(1, Ordering::Relaxed);
+
+        // Unlocked by the next thread, in finish_switch.
+        MutexGuard::leak(queue);
+        context_switch(addr_of_mut!((*current).rsp), (*next).rsp);
+    }
+    finish_switch();
+}
+
+/// First thing a thread does after being switched to.
+fn finish_switch() {
+    let cpu = &CPUS[smp::current_cpu()];
+    unsafe { cpu.queue.force_unlock() };
+    let previous = cpu.previous.swap(ptr::null_mut(), Ordering::Relaxed);
+    if !previous.is_null() {
+        let state = unsafe { &(*previous).state };
+        match state.load() {
+            State::Exited => drop(unsafe { Box::from_raw(previous) }),
+            // Its waker owns it from here on, see `Waker::wake`.
+            State::Blocked if state.transition(State::Blocked, State::Sleeping) => {}
+            // Moving elsewhere, or woken up before it got to sleep.
+            _ => {
+                state.store(State::Ready);
+                enqueue(unsafe { Box::from_raw(previous) });
+            }
+        }
+    }
+    time::reprogram();
+}
+
+/// Lets the other threads waiting for this CPU run first, as far as the current thread's
+/// policy allows: a real-time thread only makes way for threads of the same priority.
+#[cfg(test)]
+pub fn yield_now() {
+    without_interrupts(|| {
+        let current = current_ptr();
+        if current.is_null() {
+            return;
+        }
+        let cpu = &CPUS[smp::current_cpu()];
+        if current != cpu.idle.load(Ordering::Relaxed) {
+            let mut queue = cpu.queue.lock();
+            let class = unsafe { (*current).class };
+            queue.policy(class).yield_current(unsafe { &mut *current });
+        }
+        schedule();
+    });
+}
+
+/// Ends the current thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    let current = current_ptr();
+    assert!(!current.is_null(), "exit outside of a thread");
+    unsafe { (*current).state.store(State::Exited) };
+    schedule();
+    unreachable!("Exited thread was scheduled again");
+}
+
+/// Wakes up a thread that went to sleep, see [`prepare_block`].
+pub struct Waker {
+    thread: *mut Thread,
+}
+
+// A sleeping thread isn't touched by anything but its waker.
+unsafe impl Send for Waker {}
+
+impl Waker {
+    /// ID of the thread this wakes up.
+    #[cfg(test)]
+    pub fn id(&self) -> ThreadId {
+        unsafe { (*self.thread).id }
+    }
+
+    /// Queues the thread to run again, or if it hasn't left its CPU yet, stops it from going to
+    /// sleep. Can be used from interrupt handlers.
+    pub fn wake(self) {
+        let state = unsafe { &(*self.thread).state };
+        if state.transition(State::Blocked, State::Running) {
+            return;
+        }
+        if state.transition(State::Sleeping, State::Ready) {
+            // An interrupt handler may not have acknowledged its interrupt yet, so it is left
+            // to switch threads on its way out.
+            let in_thread = interrupts::are_enabled();
+            without_interrupts(|| {
+                enqueue(unsafe { Box::from_raw(self.thread) });
+                if in_thread {
+                    preempt();
+                }
+            });
+        }
+    }
+}
+
+/// Marks the current thread as going to sleep, and returns the waker that will wake it up. It
+/// goes to sleep at the next [`block`], or if it is preempted before that, unless the waker has
+/// been used in between.
+///
+/// Interrupts must be disabled until the waker has been handed over to whoever will use it, or
+/// the thread could be preempted, and so put to sleep, before anyone is able to wake it up.
+pub fn prepare_block() -> Waker {
+    debug_assert!(!interrupts::are_enabled());
+    let current = current_ptr();
+    assert!(!current.is_null(), "Blocking outside of a thread");
+    assert!(
+        current != CPUS[smp::current_cpu()].idle.load(Ordering::Relaxed),
+        "Idle thread can't block"
+    );
+    unsafe { (*current).state.store(State::Blocked) };
+    Waker { thread: current }
+}
+
+/// Puts the current thread to sleep after [`prepare_block`], unless it has been woken up since.
+pub fn block() {
+    without_interrupts(|| {
+        if unsafe { (*current_ptr()).state.load() } == State::Blocked {
+            schedule();
+        }
+    });
+}
+
+/// Restricts the current thread to the CPUs in `mask`, moving it if it is on another CPU.
+#[cfg(test)]
+pub fn set_affinity(mask: CpuMask) {
+    assert!(
+        !mask.and(CpuMask::online()).is_empty(),
+        "No online CPU in {:?}",
+        mask
+    );
+    without_interrupts(|| {
+        let current = current_ptr();
+        assert!(!current.is_null(), "set_affinity outside of a thread");
+        unsafe { (*current).affinity = mask };
+        if !mask.contains(smp::current_cpu()) {
+            schedule();
+        }
+    });
+}
+
+/// Changes the scheduling class of the current thread.
+#[cfg(test)]
+pub fn set_class(class: Class) {
+    assert!(class.is_valid(), "Invalid scheduling class {:?}", class);
+    without_interrupts(|| {
+        let current = current_ptr();
+        assert!(!current.is_null(), "set_class outside of a thread");
+        let cpu = &CPUS[smp::current_cpu()];
+        {
+            let mut queue = cpu.queue.lock();
+            let current = unsafe { &mut *current };
+            queue.policy(current.class).detach(current);
+            current.class = class;
+            queue.policy(class).attach(current);
+        }
+        // It may rank below a waiting thread now.
+        schedule();
+    });
+}
+
+/// Accounts `ticks` timer ticks to the current thread, and lets its policy decide whether it
+/// should make way.
+fn account_ticks(ticks: u32) {
+    let current = current_ptr();
+    if current.is_null() {
+        return;
+    }
+    let cpu = &CPUS[smp::current_cpu()];
+    let mut queue = cpu.queue.lock();
+    let current = unsafe { &mut *current };
+    current.ticks.fetch_add(ticks as u64, Ordering::Relaxed);
+    let resched = if ptr::eq(current, cpu.idle.load(Ordering::Relaxed)) {
+        cpu.idle_ticks.fetch_add(ticks as u64, Ordering::Relaxed);
+        !queue.is_empty()
+    } else {
+        cpu.busy_ticks.fetch_add(ticks as u64, Ordering::Relaxed);
+        queue.policy(current.class).tick(current, ticks)
+    };
+    if resched {
+        cpu.need_resched.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Called from this CPU's timer interrupt, on every tick.
+pub fn tick() {
+    account_ticks(1);
+}
+
+/// In tickless mode, accounts the whole ticks from the last ones accounted on this CPU up to
+/// `now`. Called from this CPU's timer interrupt.
+pub fn tick_until(now: Instant) {
+    let cpu = &CPUS[smp::current_cpu()];
+    let period = time::tick_period().as_nanos() as u64;
+    let ticked = cpu.ticked.load(Ordering::Relaxed);
+    let ticks = now.as_nanos().saturating_sub(ticked) / period;
+    if ticks > 0 {
+        cpu.ticked.store(ticked + ticks * period, Ordering::Relaxed);
+        account_ticks(ticks.min(u32::MAX as u64) as u32);
+    }
+}
+
+/// In tickless mode, starts counting this CPU's ticks again from `now`, forgetting about the
+/// time since the last ones.
+pub fn restart_ticks(now: Instant) {
+    CPUS[smp::current_cpu()]
+        .ticked
+        .store(now.as_nanos(), Ordering::Relaxed);
+}
+
+/// In tickless mode, when this CPU's running thread should make way for a waiting one if it
+/// doesn't before, according to its policy. `None` if nothing is waiting that would take over.
+pub fn slice_end() -> Option<Instant> {
+    let current = current_ptr();
+    if current.is_null() {
+        return None;
+    }
+    let cpu = &CPUS[smp::current_cpu()];
+    let queue = cpu.queue.lock();
+    if current == cpu.idle.load(Ordering::Relaxed) {
+        // Anything queued here would have woken it up.
+        return None;
+    }
+    let current = unsafe { &*current };
+    let ticks = match current.class {
+        Class::RealTime { .. } => queue.real_time.slice_left(current),
+        Class::Fair { .. } => queue.fair.slice_left(current),
+    }?;
+    let period = time::tick_period().as_nanos() as u64;
+    let ticked = cpu.ticked.load(Ordering::Relaxed);
+    Some(Instant::from_nanos(ticked + ticks as u64 * period))
+}
+
+extern "x86-interrupt" fn wake_interrupt(_stack_frame: InterruptStackFrame) {
+    apic::eoi();
+    time::reprogram();
+    preempt();
+}
+
+/// Switches threads if the running one should make way. Called at the end of interrupt
+/// handlers, once the interrupt has been acknowledged, and with interrupts disabled.
+pub fn preempt() {
+    if CPUS[smp::current_cpu()]
+        .need_resched
+        .load(Ordering::Relaxed)
+    {
+        schedule();
+    }
+}
+
+/// ID of the thread running on this CPU.
+#[cfg(test)]
+pub fn current_id() -> Option<ThreadId> {
+    without_interrupts(|| unsafe { current_ptr().as_ref() }.map(Thread::id))
+}
+
+/// Ticks the current thread has been running for.
+#[cfg(test)]
+pub fn runtime() -> u64 {
+    without_interrupts(|| {
+        unsafe { current_ptr().as_ref() }.map_or(0, |current| current.ticks.load(Ordering::Relaxed))
+    })
+}
+
+pub fn cpu_stats(cpu: usize) -> CpuStats {
+    let state = &CPUS[cpu];
+    CpuStats {
+        switches: state.switches.load(Ordering::Relaxed),
+        steals: state.steals.load(Ordering::Relaxed),
+        busy_ticks: state.busy_ticks.load(Ordering::Relaxed),
+        idle_ticks: state.idle_ticks.load(Ordering::Relaxed),
+        queue_length: without_interrupts(|| state.queue.lock().len()),
+    }
+}
+
+/// Every thread that is running or waiting to run, idle threads included.
+pub fn threads() -> Vec<ThreadInfo> {
+    let mut threads = Vec::new();
+    for (cpu, state) in CPUS.iter().enumerate().take(smp::cpu_count()) {
+        without_interrupts(|| {
+            // A CPU only switches threads, and frees exited ones, with its queue locked.
+            let queue = state.queue.lock();
+            let current = percpu::get(cpu)
+                .map_or(ptr::null_mut(), |percpu| {
+                    percpu.current_task.load(Ordering::Relaxed)
+                })
+                .cast::<Thread>();
+            if let Some(current) = unsafe { current.as_ref() } {
+                threads.push(ThreadInfo::new(current, cpu));
+            }
+            let idle = state.idle.load(Ordering::Relaxed);
+            if let Some(idle) = unsafe { idle.as_ref() }.filter(|_| idle != current) {
+                threads.push(ThreadInfo::new(idle, cpu));
+            }
+            queue.for_each(|thread| threads.push(ThreadInfo::new(thread, cpu)));
+        });
+    }
+    threads
+}
+
+/// Prints the statistics of every CPU, and every thread.
+pub fn dump() {
+    println!("CPU   switches  steals  busy ticks  idle ticks  queued");
+    for cpu in 0..smp::cpu_count() {
+        let stats = cpu_stats(cpu);
+        println!(
+            "{:>3} {:>10} {:>7} {:>11} {:>11} {:>7}",
+            cpu,
+            stats.switches,
+            stats.steals,
+            stats.busy_ticks,
+            stats.idle_ticks,
+            stats.queue_length
+        );
+    }
+    println!("Thread  CPU  affinity  state    class     ticks  name");
+    for thread in threads() {
+        let class = match thread.class {
+            Class::RealTime { priority } => alloc::format!("rt {}", priority),
+            Class::Fair { nice } => alloc::format!("nice {}", nice),
+        };
+        println!(
+            "{:>6} {:>4}  {:<8}  {:<8} {:<8} {:>5}  {}",
+            thread.id,
+            thread.cpu,
+            alloc::format!("{}", thread.affinity),
+            alloc::format!("{:?}", thread.state),
+            class,
+            thread.ticks,
+            thread.name
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{print, time};
+
+    /// Yields until `count` reaches `expected`.
+    fn wait_for(count: &AtomicUsize, expected: usize) {
+        while count.load(Ordering::SeqCst) < expected {
+            yield_now();
+        }
+    }
+
+    #[test_case]
+    fn spawned_threads_run_and_exit() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let ids: Vec<_> = (0..4)
+            .map(|_| {
+                let done = done.clone();
+                spawn("test", move || {
+                    done.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        wait_for(&done, ids.len());
+        assert!(ids.windows(2).all(|ids| ids[0] != ids[1]));
+        assert!(!ids.contains(&current_id().unwrap()));
+    }
+
+    #[test_case]
+    fn blocked_threads_sleep_until_woken() {
+        static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+        let done = Arc::new(AtomicUsize::new(0));
+        let id = {
+            let done = done.clone();
+            spawn("blocker", move || {
+                without_interrupts(|| *WAKER.lock() = Some(prepare_block()));
+                block();
+                done.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        let waker = loop {
+            if let Some(waker) = without_interrupts(|| WAKER.lock().take()) {
+                break waker;
+            }
+            yield_now();
+        };
+        assert_eq!(waker.id(), id);
+        let end = time::ticks() + 3;
+        while time::ticks() < end {
+            yield_now();
+        }
+        assert_eq!(done.load(Ordering::SeqCst), 0);
+        waker.wake();
+        wait_for(&done, 1);
+    }
+
+    #[test_case]
+    fn busy_threads_are_preempted() {
+        // Neither thread yields, and both are on this CPU; they can only take turns if the
+        // timer preempts them.
+        static LOG: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+        let done = Arc::new(AtomicUsize::new(0));
+        for name in [b'a', b'b'] {
+            let done = done.clone();
+            Builder::new("busy")
+                .affinity(CpuMask::single(0))
+                .spawn(move || {
+                    let end = time::ticks() + 10;
+                    while time::ticks() < end {
+                        without_interrupts(|| {
+                            let mut log = LOG.lock();
+                            if log.last() != Some(&name) {
+                                print!("{}", name as char);
+                                log.push(name);
+                            }
+                        });
+                    }
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+        }
+        wait_for(&done, 2);
+        println!();
+
+        let log = without_interrupts(|| core::mem::take(&mut *LOG.lock()));
+        assert!(log.len() >= 3, "threads didn't interleave: {:?}", log);
+    }
+
+    #[test_case]
+    fn exited_thread_stacks_are_freed() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let (free_before, _) = frame::stats();
+        for _ in 0..8 {
+            let done = done.clone();
+            Builder::new("test")
+                .affinity(CpuMask::single(0))
+                .spawn(move || {
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+        }
+        wait_for(&done, 8);
+        // The last one is freed by whoever runs after it.
+        yield_now();
+        let (free_after, _) = frame::stats();
+        assert!(free_before.saturating_sub(free_after) < STACK_FRAMES);
+    }
+
+    #[test_case]
+    fn affinity_is_respected() {
+        let last = smp::cpu_count() - 1;
+        let ran_on = Arc::new(AtomicUsize::new(usize::MAX));
+        let moved_to = Arc::new(AtomicUsize::new(usize::MAX));
+        {
+            let (ran_on, moved_to) = (ran_on.clone(), moved_to.clone());
+            Builder::new("pinned")
+                .affinity(CpuMask::single(last))
+                .spawn(move || {
+                    ran_on.store(smp::current_cpu(), Ordering::SeqCst);
+                    set_affinity(CpuMask::single(0));
+                    moved_to.store(smp::current_cpu(), Ordering::SeqCst);
+                });
+        }
+        while moved_to.load(Ordering::SeqCst) == usize::MAX {
+            yield_now();
+        }
+        assert_eq!(ran_on.load(Ordering::SeqCst), last);
+        assert_eq!(moved_to.load(Ordering::SeqCst), 0);
+    }
+
+    #[test_case]
+    fn idle_cpus_steal_work() {
+        if smp::cpu_count() == 1 {
+            return;
+        }
+        let steals_before: u64 = (0..smp::cpu_count()).map(|cpu| cpu_stats(cpu).steals).sum();
+        // All of them start out queued here, behind this thread; only stealing gets them to
+        // the other CPUs while it keeps the CPU.
+        let cpus = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let (cpus, done) = (cpus.clone(), done.clone());
+            Builder::new("stealable")
+                .affinity(CpuMask::single(0))
+                .spawn(move || {
+                    set_affinity(CpuMask::all());
+                    let end = time::ticks() + 5;
+                    while time::ticks() < end {
+                        cpus.fetch_or(1 << smp::current_cpu(), Ordering::SeqCst);
+                    }
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+        }
+        wait_for(&done, 4);
+
+        let steals_after: u64 = (0..smp::cpu_count()).map(|cpu| cpu_stats(cpu).steals).sum();
+        assert!(steals_after > steals_before);
+        assert!(cpus.load(Ordering::SeqCst) & !1 != 0);
+    }
+
+    /// A CPU for tests to keep busy: one of the APs if there are any, so that the boot thread
+    /// doesn't get in the way.
+    fn test_cpu() -> usize {
+        smp::cpu_count() - 1
+    }
+
+    #[test_case]
+    fn fair_share_follows_weights() {
+        // nice 0 weighs about three times as much as nice 5.
+        static STOP: AtomicBool = AtomicBool::new(false);
+        STOP.store(false, Ordering::SeqCst);
+        let runtimes = Arc::new([AtomicU64::new(0), AtomicU64::new(0)]);
+        let done = Arc::new(AtomicUsize::new(0));
+        for (i, nice) in [0, 5].into_iter().enumerate() {
+            let (runtimes, done) = (runtimes.clone(), done.clone());
+            Builder::new("weighted")
+                .affinity(CpuMask::single(test_cpu()))
+                .class(Class::Fair { nice })
+                .spawn(move || {
+                    while !STOP.load(Ordering::SeqCst) {}
+                    runtimes[i].store(runtime(), Ordering::SeqCst);
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+        }
+        let end = time::ticks() + 40;
+        while time::ticks() < end {
+            yield_now();
+        }
+        STOP.store(true, Ordering::SeqCst);
+        wait_for(&done, 2);
+
+        let heavy = runtimes[0].load(Ordering::SeqCst);
+        let light = runtimes[1].load(Ordering::SeqCst);
+        println!("nice 0: {} ticks, nice 5: {} ticks", heavy, light);
+        assert!(light > 0);
+        assert!(heavy >= 2 * light, "{} vs {} ticks", heavy, light);
+    }
+
+    #[test_case]
+    fn real_time_runs_before_fair() {
+        static STOP: AtomicBool = AtomicBool::new(false);
+        static PROGRESS: AtomicU64 = AtomicU64::new(0);
+        STOP.store(false, Ordering::SeqCst);
+        PROGRESS.store(0, Ordering::SeqCst);
+        let done = Arc::new(AtomicUsize::new(0));
+        {
+            let done = done.clone();
+            Builder::new("background")
+                .affinity(CpuMask::single(test_cpu()))
+                .spawn(move || {
+                    while !STOP.load(Ordering::SeqCst) {
+                        PROGRESS.fetch_add(1, Ordering::SeqCst);
+                    }
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+        }
+        while PROGRESS.load(Ordering::SeqCst) == 0 {
+            yield_now();
+        }
+
+        // The background thread must not get to run while the real-time one is busy.
+        let stalled = Arc::new(AtomicBool::new(false));
+        {
+            let (stalled, done) = (stalled.clone(), done.clone());
+            Builder::new("real-time")
+                .affinity(CpuMask::single(test_cpu()))
+                .class(Class::RealTime { priority: 10 })
+                .spawn(move || {
+                    let before = PROGRESS.load(Ordering::SeqCst);
+                    let end = time::ticks() + 3;
+                    while time::ticks() < end {}
+                    stalled.store(PROGRESS.load(Ordering::SeqCst) == before, Ordering::SeqCst);
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+        }
+        wait_for(&done, 1);
+        STOP.store(true, Ordering::SeqCst);
+        wait_for(&done, 2);
+        assert!(stalled.load(Ordering::SeqCst));
+    }
+
+    #[test_case]
+    fn threads_can_change_class() {
+        static SEEN: Mutex<Vec<Class>> = Mutex::new(Vec::new());
+        let real_time = Class::RealTime { priority: 10 };
+        let done = Arc::new(AtomicUsize::new(0));
+        {
+            let done = done.clone();
+            Builder::new("reclassed")
+                .affinity(CpuMask::single(test_cpu()))
+                .spawn(move || {
+                    let me = current_id().unwrap();
+                    for class in [real_time, Class::Fair { nice: 5 }] {
+                        set_class(class);
+                        let listed = threads().into_iter().find(|thread| thread.id == me);
+                        let seen = listed.map(|thread| thread.class);
+                        without_interrupts(|| SEEN.lock().extend(seen));
+                    }
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+        }
+        wait_for(&done, 1);
+        let seen = without_interrupts(|| core::mem::take(&mut *SEEN.lock()));
+        assert_eq!(seen, [real_time, Class::Fair { nice: 5 }]);
+    }
+
+    #[test_case]
+    fn stats_count_switches_and_runtime() {
+        let switches = cpu_stats(0).switches;
+        let done = Arc::new(AtomicUsize::new(0));
+        let runtime_seen = Arc::new(AtomicU64::new(0));
+        {
+            let (done, runtime_seen) = (done.clone(), runtime_seen.clone());
+            Builder::new("counted")
+                .affinity(CpuMask::single(0))
+                .spawn(move || {
+                    let end = time::ticks() + 3;
+                    while time::ticks() < end {}
+                    runtime_seen.store(runtime(), Ordering::SeqCst);
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+        }
+        wait_for(&done, 1);
+        assert!(cpu_stats(0).switches > switches);
+        assert!(runtime_seen.load(Ordering::SeqCst) > 0);
+
+        let me = current_id().unwrap();
+        let threads = threads();
+        assert!(threads
+            .iter()
+            .any(|thread| thread.id == me && thread.cpu == 0));
+        assert_eq!(
+            threads
+                .iter()
+                .filter(|thread| thread.name == "idle")
+                .count(),
+            smp::cpu_count()
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Scheduling policies.
+//!
+//! Each CPU's run queue is split by [`Class`], and every class has a [`Policy`] that decides
+//! which of its threads runs next, and when the running one has had enough. Classes are tried
+//! in order: a thread of a higher class always runs before, and preempts, one of a lower class.
+//!
+//! - [`RealTime`] threads run in strict priority order, taking turns in time slices with other
+//!   real-time threads of the same priority.
+//! - [`Fair`] threads share the CPU in proportion to their weight, set by their nice value.
+//!   Each one's virtual runtime advances as it runs, more slowly the heavier it is, and the one
+//!   that is furthest behind runs next, like Linux's CFS.
+//!
+//! Per-thread policy state that depends on the CPU (a fair thread's virtual runtime) is kept
+//! relative to the CPU's queue while the thread is away from it: [`Policy::detach`] makes it
+//! relative when a thread leaves a CPU, and [`Policy::attach`] makes it local to the CPU it
+//! arrives on.
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+};
+
+use super::{Thread, ThreadId, TIME_SLICE_TICKS};
+
+/// Highest real-time priority.
+pub const MAX_PRIORITY: u8 = 99;
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+
+/// How a thread competes for the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    /// Runs before any fair thread and any real-time thread of a lower priority, from 0 to
+    /// [`MAX_PRIORITY`].
+    RealTime { priority: u8 },
+    /// Shares the CPU with the other fair threads, getting more of it the lower its nice
+    /// value, from [`MIN_NICE`] to [`MAX_NICE`].
+    Fair { nice: i8 },
+}
+
+impl Class {
+    /// Classes with a lower rank run first.
+    pub(super) fn rank(self) -> usize {
+        match self {
+            Class::RealTime { .. } => 0,
+            Class::Fair { .. } => 1,
+        }
+    }
+
+    pub(super) fn is_valid(self) -> bool {
+        match self {
+            Class::RealTime { priority } => priority <= MAX_PRIORITY,
+            Class::Fair { nice } => (MIN_NICE..=MAX_NICE).contains(&nice),
+        }
+    }
+}
+
+impl Default for Class {
+    fn default() -> Self {
+        Class::Fair { nice: 0 }
+    }
+}
+
+/// The threads of one class waiting for one CPU.
+pub trait Policy: Send {
+    /// Adapts the state of a thread that is arriving on this CPU, new or from elsewhere.
+    fn attach(&mut self, thread: &mut Thread);
+
+    /// Adapts the state of a thread that is leaving this CPU, see [`Policy::attach`].
+    fn detach(&mut self, thread: &mut Thread);
+
+    /// Queues an attached thread.
+    fn push(&mut self, thread: Box<Thread>);
+
+    /// Takes the thread that should run next.
+    fn pick_next(&mut self) -> Option<Box<Thread>>;
+
+    /// Takes a waiting thread that may run on `cpu`, to move it there.
+    fn steal(&mut self, cpu: usize) -> Option<Box<Thread>>;
+
+    /// Accounts `ticks` timer ticks to `current`, which is running, and returns whether it
+    /// should make way for a waiting thread.
+    fn tick(&mut self, current: &mut Thread, ticks: u32) -> bool;
+
+    /// Ticks until [`Policy::tick`] would have `current` make way, at least 1, or `None` if no
+    /// waiting thread would take over from it.
+    fn slice_left(&self, current: &Thread) -> Option<u32>;
+
+    /// Whether `thread`, which was just queued, should preempt `current`, which is running.
+    fn preempts(&self, thread: &Thread, current: &Thread) -> bool;
+
+    /// Lets the waiting threads go before `current`, which is giving up the CPU.
+    #[cfg(test)]
+    fn yield_current(&mut self, current: &mut Thread);
+
+    fn len(&self) -> usize;
+
+    fn for_each(&self, f: &mut dyn FnMut(&Thread));
+}
+
+fn priority(thread: &Thread) -> u8 {
+    match thread.class {
+        Class::RealTime { priority } => priority,
+        Class::Fair { .. } => unreachable!("Fair thread in the real-time queue"),
+    }
+}
+
+/// Strict priorities, and round-robin between threads of the same priority.
+pub struct RealTime {
+    levels: [VecDeque<Box<Thread>>; MAX_PRIORITY as usize + 1],
+    len: usize,
+}
+
+impl RealTime {
+    pub const fn new() -> Self {
+        Self {
+            levels: [const { VecDeque::new() }; MAX_PRIORITY as usize + 1],
+            len: 0,
+        }
+    }
+
+    /// Priority of the most urgent waiting thread.
+    fn highest(&self) -> Option<u8> {
+        (0..=MAX_PRIORITY)
+            .rev()
+            .find(|&priority| !self.levels[priority as usize].is_empty())
+    }
+}
+
+impl Policy for RealTime {
+    fn attach(&mut self, thread: &mut Thread) {
+        thread.slice = TIME_SLICE_TICKS;
+    }
+
+    fn detach(&mut self, _thread: &mut Thread) {}
+
+    fn push(&mut self, thread: Box<Thread>) {
+        self.levels[priority(&thread) as usize].push_back(thread);
+        self.len += 1;
+    }
+
+    fn pick_next(&mut self) -> Option<Box<Thread>> {
+        let thread = self.levels[self.highest()? as usize].pop_front()?;
+        self.len -= 1;
+        Some(thread)
+    }
+
+    fn steal(&mut self, cpu: usize) -> Option<Box<Thread>> {
+        let thread = self.levels.iter_mut().rev().find_map(|level| {
+            let index = level
+                .iter()
+                .rposition(|thread| thread.affinity.contains(cpu))?;
+            level.remove(index)
+        })?;
+        self.len -= 1;
+        Some(thread)
+    }
+
+    fn tick(&mut self, current: &mut Thread, ticks: u32) -> bool {
+        current.slice = current.slice.saturating_sub(ticks);
+        if current.slice > 0 {
+            return false;
+        }
+        current.slice = TIME_SLICE_TICKS;
+        self.highest() >= Some(priority(current))
+    }
+
+    fn slice_left(&self, current: &Thread) -> Option<u32> {
+        (self.highest() >= Some(priority(current))).then_some(current.slice.max(1))
+    }
+
+    fn preempts(&self, thread: &Thread, current: &Thread) -> bool {
+        priority(thread) > priority(current)
+    }
+
+    #[cfg(test)]
+    fn yield_current(&mut self, current: &mut Thread) {
+        // It goes to the back of its priority's queue anyway.
+        current.slice = TIME_SLICE_TICKS;
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&Thread)) {
+        self.levels.iter().flatten().for_each(|thread| f(thread));
+    }
+}
+
+/// Weight of a nice 0 thread.
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// Weights for nice values -20 to 19, from Linux: each step is about 10% more or less CPU time.
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+/// Virtual runtime a nice 0 thread gets for a tick.
+const TICK_VRUNTIME: u64 = 1 << 20;
+
+/// How far ahead of the waiting threads the running one may get before it is preempted.
+const GRANULARITY: u64 = TICK_VRUNTIME;
+
+pub fn weight(nice: i8) -> u64 {
+    WEIGHTS[(nice.clamp(MIN_NICE, MAX_NICE) - MIN_NICE) as usize]
+}
+
+fn nice(thread: &Thread) -> i8 {
+    match thread.class {
+        Class::Fair { nice } => nice,
+        Class::RealTime { .. } => unreachable!("Real-time thread in the fair queue"),
+    }
+}
+
+/// Virtual runtime `thread` gets for a tick.
+fn tick_vruntime(thread: &Thread) -> u64 {
+    TICK_VRUNTIME * NICE_0_WEIGHT / weight(nice(thread))
+}
+
+/// Virtual runtime fair sharing.
+pub struct Fair {
+    /// Ordered by virtual runtime, with the ID to tell apart threads that are even.
+    queue: BTreeMap<(u64, ThreadId), Box<Thread>>,
+    /// Never goes back; threads arriving here start from it.
+    min_vruntime: u64,
+}
+
+impl Fair {
+    pub const fn new() -> Self {
+        Self {
+            queue: BTreeMap::new(),
+            min_vruntime: 0,
+        }
+    }
+
+    fn first_vruntime(&self) -> Option<u64> {
+        self.queue
+            .first_key_value()
+            .map(|((vruntime, _), _)| *vruntime)
+    }
+}
+
+impl Policy for Fair {
+    fn attach(&mut self, thread: &mut Thread) {
+        thread.vruntime += self.min_vruntime;
+    }
+
+    fn detach(&mut self, thread: &mut Thread) {
+        thread.vruntime = thread.vruntime.saturating_sub(self.min_vruntime);
+    }
+
+    fn push(&mut self, thread: Box<Thread>) {
+        self.queue.insert((thread.vruntime, thread.id), thread);
+    }
+
+    fn pick_next(&mut self) -> Option<Box<Thread>> {
+        let (_, thread) = self.queue.pop_first()?;
+        self.min_vruntime = self.min_vruntime.max(thread.vruntime);
+        Some(thread)
+    }
+
+    fn steal(&mut self, cpu: usize) -> Option<Box<Thread>> {
+        // The one furthest ahead, which would have to wait the longest here.
+        let key = *self
+            .queue
+            .iter()
+            .rev()
+            .find(|(_, thread)| thread.affinity.contains(cpu))?
+            .0;
+        self.queue.remove(&key)
+    }
+
+    fn tick(&mut self, current: &mut Thread, ticks: u32) -> bool {
+        current.vruntime += ticks as u64 * tick_vruntime(current);
+        self.first_vruntime()
+            .is_some_and(|first| current.vruntime >= first + GRANULARITY)
+    }
+
+    fn slice_left(&self, current: &Thread) -> Option<u32> {
+        let first = self.first_vruntime()?;
+        let ahead = (first + GRANULARITY).saturating_sub(current.vruntime);
+        let ticks = ahead.div_ceil(tick_vruntime(current)).max(1);
+        Some(ticks.min(u32::MAX as u64) as u32)
+    }
+
+    fn preempts(&self, thread: &Thread, current: &Thread) -> bool {
+        thread.vruntime + GRANULARITY <= current.vruntime
+    }
+
+    #[cfg(test)]
+    fn yield_current(&mut self, current: &mut Thread) {
+        if let Some(((last, _), _)) = self.queue.last_key_value() {
+            current.vruntime = current.vruntime.max(last + 1);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&Thread)) {
+        self.queue.values().for_each(|thread| f(thread));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn nice_values_map_to_weights() {
+        assert_eq!(weight(0), NICE_0_WEIGHT);
+        assert_eq!(weight(MIN_NICE), 88761);
+        assert_eq!(weight(MAX_NICE), 15);
+        // Out of range values are clamped.
+        assert_eq!(weight(100), weight(MAX_NICE));
+        assert!(WEIGHTS.windows(2).all(|pair| pair[0] > pair[1]));
+    }
+
+    #[test_case]
+    fn classes_are_checked() {
+        assert!(Class::RealTime {
+            priority: MAX_PRIORITY
+        }
+        .is_valid());
+        assert!(!Class::RealTime { priority: 100 }.is_valid());
+        assert!(!Class::Fair { nice: 20 }.is_valid());
+        assert!(Class::RealTime { priority: 0 }.rank() < Class::default().rank());
+    }
+}
@@ -0,0 +1,816 @@
+//! 16550 UART serial ports.
+//!
+//! PCs have up to four serial ports at fixed I/O ports, COM1 and COM3 on IRQ 4 and COM2 and
+//! COM4 on IRQ 3. [`init`] checks for each one by looping a byte back through it, sets it to
+//! 115200 8N1 with its FIFOs on, and takes its interrupts, so a port can be used as a byte
+//! stream: received bytes are moved into a ring buffer by the interrupt handler and taken out
+//! by [`SerialPort::read`], and written bytes are queued in another one and fed to the UART
+//! whenever its transmit FIFO runs empty.
+//!
+//! COM1 is the console: everything printed goes to it as well as the screen, with `\n` sent as
+//! `\r\n`, so running QEMU with `-serial stdio` shows the kernel's output in the terminal.
+//! `console=9600,7E1` on the command line runs it at another rate and framing, and
+//! [`start_console_thread`] echoes back whatever is typed into it.
+
+use core::{
+    fmt,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{
+    boot_info,
+    irq::{self, IrqError, IrqReturn},
+    println, sched,
+    sync::{IrqSpinLock, WaitQueue},
+};
+
+/// The UART's clock divided by 16, the fastest rate it can run at.
+pub const MAX_BAUD: u32 = 115_200;
+
+const PORTS: usize = 4;
+const RING_SIZE: usize = 1024;
+/// Depth of a 16550A's transmit FIFO.
+const FIFO_SIZE: usize = 16;
+/// Reads of the line status register to wait for the loopback byte.
+const LOOPBACK_SPINS: usize = 100_000;
+const LOOPBACK_BYTE: u8 = 0xae;
+
+// Register offsets from the base port.
+/// Receive and transmit holding registers; the low divisor byte with [`LCR_DLAB`] set.
+const DATA: u16 = 0;
+/// The high divisor byte with [`LCR_DLAB`] set.
+const INTERRUPT_ENABLE: u16 = 1;
+/// Interrupt identification when read, FIFO control when written.
+const INTERRUPT_ID: u16 = 2;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+
+const IER_RECEIVED: u8 = 1 << 0;
+const IER_TRANSMIT_EMPTY: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+
+/// Set when no interrupt is pending.
+const IIR_NONE_PENDING: u8 = 1 << 0;
+const IIR_ID_MASK: u8 = 0b111 << 1;
+const IIR_MODEM_STATUS: u8 = 0b000 << 1;
+const IIR_TRANSMIT_EMPTY: u8 = 0b001 << 1;
+const IIR_RECEIVED: u8 = 0b010 << 1;
+const IIR_LINE_STATUS: u8 = 0b011 << 1;
+/// Bytes have been sitting in the receive FIFO, below its trigger level.
+const IIR_RECEIVE_TIMEOUT: u8 = 0b110 << 1;
+/// Both set when the FIFOs are on and work, which they don't on the original 16550.
+const IIR_FIFOS_ENABLED: u8 = 0b11 << 6;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RECEIVE: u8 = 1 << 1;
+const FCR_CLEAR_TRANSMIT: u8 = 1 << 2;
+const FCR_TRIGGER_14: u8 = 0b11 << 6;
+
+const LCR_STOP_BITS_2: u8 = 1 << 2;
+const LCR_PARITY_SHIFT: u8 = 3;
+/// Divisor latch access: the first two registers hold the baud rate divisor while it is set.
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+/// Connects the UART's interrupt to the IRQ line on PCs.
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+/// The transmit holding register, or FIFO, is empty.
+const LSR_TRANSMIT_EMPTY: u8 = 1 << 5;
+/// The transmitter is idle as well, with the last byte sent.
+const LSR_IDLE: u8 = 1 << 6;
+
+static COM: [SerialPort; PORTS] = [
+    SerialPort::new("COM1", 0x3f8, 4),
+    SerialPort::new("COM2", 0x2f8, 3),
+    SerialPort::new("COM3", 0x3e8, 4),
+    SerialPort::new("COM4", 0x2e8, 3),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Always 1.
+    Mark,
+    /// Always 0.
+    Space,
+}
+
+/// Baud rate and character framing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    /// Up to [`MAX_BAUD`], which must divide into it within 2%.
+    pub baud: u32,
+    /// 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2, which means 1.5 with 5 data bits.
+    pub stop_bits: u8,
+}
+
+impl Default for LineConfig {
+    /// 115200 8N1.
+    fn default() -> Self {
+        Self {
+            baud: MAX_BAUD,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+        }
+    }
+}
+
+impl LineConfig {
+    /// Parses the command line form, e.g. `9600,7E1`, without checking the values.
+    fn parse(s: &str) -> Option<Self> {
+        let (baud, framing) = s.split_once(',')?;
+        let &[data_bits, parity, stop_bits] = framing.as_bytes() else {
+            return None;
+        };
+        let parity = match parity.to_ascii_uppercase() {
+            b'N' => Parity::None,
+            b'O' => Parity::Odd,
+            b'E' => Parity::Even,
+            b'M' => Parity::Mark,
+            b'S' => Parity::Space,
+            _ => return None,
+        };
+        Some(Self {
+            baud: baud.parse().ok()?,
+            data_bits: (data_bits as char).to_digit(10)? as u8,
+            parity,
+            stop_bits: (stop_bits as char).to_digit(10)? as u8,
+        })
+    }
+
+    /// The baud rate divisor.
+    fn divisor(&self) -> Result<u16, SerialError> {
+        if self.baud == 0 || self.baud > MAX_BAUD {
+            return Err(SerialError::InvalidBaud(self.baud));
+        }
+        let divisor = (MAX_BAUD + self.baud / 2) / self.baud;
+        let actual = MAX_BAUD / divisor;
+        if divisor > u16::MAX as u32 || actual.abs_diff(self.baud) * 50 > self.baud {
+            return Err(SerialError::InvalidBaud(self.baud));
+        }
+        Ok(divisor as u16)
+    }
+
+    /// The line control register value, without [`LCR_DLAB`].
+    fn line_control(&self) -> Result<u8, SerialError> {
+        if !(5..=8).contains(&self.data_bits) || !(1..=2).contains(&self.stop_bits) {
+            return Err(SerialError::InvalidFraming);
+        }
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        };
+        let stop_bits = if self.stop_bits == 2 {
+            LCR_STOP_BITS_2
+        } else {
+            0
+        };
+        Ok((self.data_bits - 5) | stop_bits | (parity << LCR_PARITY_SHIFT))
+    }
+}
+
+/// The usual shorthand, e.g. `115200 8N1`.
+impl fmt::Display for LineConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+            Parity::Mark => 'M',
+            Parity::Space => 'S',
+        };
+        write!(
+            f,
+            "{} {}{}{}",
+            self.baud, self.data_bits, parity, self.stop_bits
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// The port didn't pass the loopback test.
+    NotPresent(&'static str),
+    InvalidBaud(u32),
+    /// Data or stop bits out of range.
+    InvalidFraming,
+    Irq(IrqError),
+}
+
+impl fmt::Display for SerialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialError::NotPresent(name) => write!(f, "{} not present", name),
+            SerialError::InvalidBaud(baud) => write!(f, "no baud rate divisor for {}", baud),
+            SerialError::InvalidFraming => write!(f, "invalid character framing"),
+            SerialError::Irq(err) => write!(f, "IRQ: {:?}", err),
+        }
+    }
+}
+
+/// A fixed-size byte queue, which never allocates, so it can be filled in interrupt context.
+struct Ring {
+    bytes: [u8; RING_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; RING_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == RING_SIZE
+    }
+
+    /// Returns false, dropping `byte`, if the ring is full.
+    fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.bytes[(self.head + self.len) % RING_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % RING_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+struct Buffers {
+    received: Ring,
+    transmit: Ring,
+    /// Bytes have been put in the transmit FIFO since it was last found empty, so there is a
+    /// transmit interrupt coming to send the rest.
+    transmitting: bool,
+}
+
+pub struct SerialPort {
+    name: &'static str,
+    base: u16,
+    irq: u8,
+    present: AtomicBool,
+    /// Whether the FIFOs work, so that [`FIFO_SIZE`] bytes can be written at a time.
+    fifo: AtomicBool,
+    /// Bytes received with the receive buffer full, or lost by the UART itself.
+    dropped: AtomicU64,
+    buffers: IrqSpinLock<Buffers>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+}
+
+impl SerialPort {
+    const fn new(name: &'static str, base: u16, irq: u8) -> Self {
+        Self {
+            name,
+            base,
+            irq,
+            present: AtomicBool::new(false),
+            fifo: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            buffers: IrqSpinLock::new(Buffers {
+                received: Ring::new(),
+                transmit: Ring::new(),
+                transmitting: false,
+            }),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    /// Received bytes thrown away because the buffer was full.
+    #[cfg(test)]
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn read_reg(&self, reg: u16) -> u8 {
+        unsafe { Port::new(self.base + reg).read() }
+    }
+
+    fn write_reg(&self, reg: u16, value: u8) {
+        unsafe { Port::new(self.base + reg).write(value) }
+    }
+
+    /// Sets the baud rate and framing, once everything queued has gone out at the old ones.
+    pub fn configure(&self, config: LineConfig) -> Result<(), SerialError> {
+        let divisor = config.divisor()?;
+        let line_control = config.line_control()?;
+        let mut buffers = self.buffers.lock();
+        self.drain(&mut buffers);
+        self.write_reg(LINE_CONTROL, LCR_DLAB);
+        self.write_reg(DATA, divisor as u8);
+        self.write_reg(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write_reg(LINE_CONTROL, line_control);
+        Ok(())
+    }
+
+    /// Sends a byte to itself with the UART in loopback mode, so that nothing goes out on the
+    /// line, and checks that it comes back. Interrupts are turned off for the duration, and
+    /// anything queued is sent first.
+    pub fn loopback_test(&self) -> bool {
+        let mut buffers = self.buffers.lock();
+        self.drain(&mut buffers);
+        let interrupt_enable = self.read_reg(INTERRUPT_ENABLE);
+        let modem_control = self.read_reg(MODEM_CONTROL);
+        self.write_reg(INTERRUPT_ENABLE, 0);
+        self.write_reg(MODEM_CONTROL, MCR_LOOPBACK | MCR_RTS | MCR_OUT1 | MCR_OUT2);
+        // Throw away anything that came in before.
+        while self.read_reg(LINE_STATUS) & LSR_DATA_READY != 0 {
+            self.read_reg(DATA);
+        }
+        self.write_reg(DATA, LOOPBACK_BYTE);
+        // A missing port reads as all ones, so this gives up rather than waiting for a byte.
+        let ready = (0..LOOPBACK_SPINS).any(|_| {
+            spin_loop();
+            self.read_reg(LINE_STATUS) & LSR_DATA_READY != 0
+        });
+        let passed = ready && self.read_reg(DATA) == LOOPBACK_BYTE;
+        self.write_reg(MODEM_CONTROL, modem_control);
+        self.write_reg(INTERRUPT_ENABLE, interrupt_enable);
+        passed
+    }
+
+    /// Takes up to `buf.len()` received bytes, and returns how many, waiting for at least one
+    /// if there are none yet. Must be called from a thread with interrupts enabled.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+        self.readers
+            .wait_until(|| !self.buffers.lock().received.is_empty());
+        self.try_read(buf)
+    }
+
+    /// Takes up to `buf.len()` received bytes, and returns how many, without waiting.
+    pub fn try_read(&self, buf: &mut [u8]) -> usize {
+        let mut buffers = self.buffers.lock();
+        buf.iter_mut()
+            .map_while(|slot| buffers.received.pop().map(|byte| *slot = byte))
+            .count()
+    }
+
+    /// Queues `bytes` to be sent. When the queue is full, this waits for room, sleeping if
+    /// interrupts are enabled, or else sending bytes itself until there is some.
+    pub fn write(&self, mut bytes: &[u8]) {
+        let can_sleep = interrupts::are_enabled();
+        while !bytes.is_empty() {
+            {
+                let mut buffers = self.buffers.lock();
+                while let Some((&byte, rest)) = bytes.split_first() {
+                    if !can_sleep {
+                        self.make_room(&mut buffers);
+                    }
+                    if !buffers.transmit.push(byte) {
+                        break;
+                    }
+                    bytes = rest;
+                }
+                self.start_transmit(&mut buffers);
+            }
+            if !bytes.is_empty() {
+                self.writers
+                    .wait_until(|| !self.buffers.lock().transmit.is_full());
+            }
+        }
+    }
+
+    /// Waits until everything queued has been sent.
+    pub fn flush(&self) {
+        let mut buffers = self.buffers.lock();
+        self.drain(&mut buffers);
+    }
+
+    fn start_transmit(&self, buffers: &mut Buffers) {
+        if !buffers.transmitting {
+            self.transmit(buffers);
+        }
+    }
+
+    /// Fills the transmit FIFO from the queue if it is empty.
+    fn transmit(&self, buffers: &mut Buffers) {
+        if self.read_reg(LINE_STATUS) & LSR_TRANSMIT_EMPTY == 0 {
+            return;
+        }
+        let room = if self.fifo.load(Ordering::Relaxed) {
+            FIFO_SIZE
+        } else {
+            1
+        };
+        let sent = (0..room)
+            .map_while(|_| buffers.transmit.pop())
+            .map(|byte| self.write_reg(DATA, byte))
+            .count();
+        buffers.transmitting = sent > 0;
+    }
+
+    /// Sends bytes by hand until there is room in the queue, for when the transmit interrupt
+    /// can't be waited for.
+    fn make_room(&self, buffers: &mut Buffers) {
+        while buffers.transmit.is_full() {
+            while self.read_reg(LINE_STATUS) & LSR_TRANSMIT_EMPTY == 0 {
+                spin_loop();
+            }
+            self.transmit(buffers);
+        }
+    }
+
+    /// Sends the whole queue by hand, and waits for the last byte to go out.
+    fn drain(&self, buffers: &mut Buffers) {
+        while !buffers.transmit.is_empty() {
+            while self.read_reg(LINE_STATUS) & LSR_TRANSMIT_EMPTY == 0 {
+                spin_loop();
+            }
+            self.transmit(buffers);
+        }
+        while self.read_reg(LINE_STATUS) & LSR_IDLE == 0 {
+            spin_loop();
+        }
+    }
+
+    fn receive(&self, buffers: &mut Buffers) {
+        loop {
+            let status = self.read_reg(LINE_STATUS);
+            if status & LSR_OVERRUN != 0 {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            if status & LSR_DATA_READY == 0 {
+                break;
+            }
+            if !buffers.received.push(self.read_reg(DATA)) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Brings the port up with its interrupts on, if it passes the loopback test.
+    fn probe(&'static self, index: usize, config: LineConfig) -> Result<(), SerialError> {
+        self.write_reg(INTERRUPT_ENABLE, 0);
+        self.configure(config)?;
+        self.write_reg(
+            FIFO_CONTROL,
+            FCR_ENABLE | FCR_CLEAR_RECEIVE | FCR_CLEAR_TRANSMIT | FCR_TRIGGER_14,
+        );
+        if !self.loopback_test() {
+            return Err(SerialError::NotPresent(self.name));
+        }
+        let fifo = self.read_reg(INTERRUPT_ID) & IIR_FIFOS_ENABLED == IIR_FIFOS_ENABLED;
+        self.fifo.store(fifo, Ordering::Relaxed);
+        irq::register(self.irq, self.name, interrupt, index).map_err(SerialError::Irq)?;
+        self.write_reg(MODEM_CONTROL, MCR_DTR | MCR_RTS | MCR_OUT2);
+        self.write_reg(
+            INTERRUPT_ENABLE,
+            IER_RECEIVED | IER_TRANSMIT_EMPTY | IER_LINE_STATUS,
+        );
+        self.present.store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// Writes formatted text to the console, queueing it without ever sleeping.
+struct ConsoleWriter<'a> {
+    port: &'a SerialPort,
+    buffers: &'a mut Buffers,
+}
+
+impl ConsoleWriter<'_> {
+    fn push(&mut self, byte: u8) {
+        self.port.make_room(self.buffers);
+        self.buffers.transmit.push(byte);
+    }
+}
+
+impl fmt::Write for ConsoleWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.push(b'\r');
+            }
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+/// The port COM`n`, if it is there.
+pub fn com(n: usize) -> Option<&'static SerialPort> {
+    let port = COM.get(n.checked_sub(1)?)?;
+    port.present.load(Ordering::Acquire).then_some(port)
+}
+
+/// The port that printed output goes to.
+pub fn console() -> Option<&'static SerialPort> {
+    com(1)
+}
+
+/// Prints to the console, if there is one.
+pub fn fmt(args: fmt::Arguments) {
+    use fmt::Write;
+    let Some(port) = console() else {
+        return;
+    };
+    let mut buffers = port.buffers.lock();
+    ConsoleWriter {
+        port,
+        buffers: &mut buffers,
+    }
+    .write_fmt(args)
+    .ok();
+    port.start_transmit(&mut buffers);
+}
+
+/// Sends `report` straight out of the console, behind whatever is queued, without taking any
+/// lock, for when the kernel can't go on.
+///
+/// # Safety
+///
+/// Whoever was using the console must never touch it again, like a CPU that has stopped for
+/// good.
+pub unsafe fn write_fatal(report: &dyn fmt::Display) {
+    use fmt::Write;
+    let Some(port) = console() else {
+        return;
+    };
+    unsafe { port.buffers.force_unlock() };
+    let mut buffers = port.buffers.lock();
+    port.write_reg(INTERRUPT_ENABLE, 0);
+    port.drain(&mut buffers);
+    let mut writer = ConsoleWriter {
+        port,
+        buffers: &mut buffers,
+    };
+    write!(writer, "\n{}", report).ok();
+    port.drain(&mut buffers);
+}
+
+/// Waits until everything printed so far has gone out of the console.
+pub fn flush() {
+    if let Some(port) = console() {
+        port.flush();
+    }
+}
+
+fn interrupt(index: usize) -> IrqReturn {
+    let port = &COM[index];
+    let mut handled = false;
+    let (mut received, mut sent) = (false, false);
+    {
+        let mut buffers = port.buffers.lock();
+        loop {
+            let id = port.read_reg(INTERRUPT_ID);
+            if id & IIR_NONE_PENDING != 0 {
+                break;
+            }
+            handled = true;
+            match id & IIR_ID_MASK {
+                IIR_RECEIVED | IIR_RECEIVE_TIMEOUT | IIR_LINE_STATUS => {
+                    port.receive(&mut buffers);
+                    received = true;
+                }
+                IIR_TRANSMIT_EMPTY => {
+                    port.transmit(&mut buffers);
+                    sent = true;
+                }
+                IIR_MODEM_STATUS => {
+                    port.read_reg(MODEM_STATUS);
+                }
+                _ => {}
+            }
+        }
+    }
+    if received {
+        port.readers.wake_all();
+    }
+    if sent {
+        port.writers.wake_all();
+    }
+    // COM1 and COM3, and COM2 and COM4, share their lines.
+    if handled {
+        IrqReturn::Handled
+    } else {
+        IrqReturn::NotMine
+    }
+}
+
+/// The console's line config from the command line, or else 115200 8N1.
+fn console_config() -> LineConfig {
+    let Some(option) = boot_info::boot_info().option("console") else {
+        return LineConfig::default();
+    };
+    LineConfig::parse(option).unwrap_or_else(|| {
+        println!("Serial: ignoring console={}", option);
+        LineConfig::default()
+    })
+}
+
+/// Brings up whichever of COM1 to COM4 are there. Must run after [`irq::init`].
+pub fn init() {
+    let mut found = false;
+    for (index, port) in COM.iter().enumerate() {
+        let config = if index == 0 {
+            console_config()
+        } else {
+            LineConfig::default()
+        };
+        match port.probe(index, config) {
+            Ok(()) => {
+                found = true;
+                println!(
+                    "Serial: {} at {:#x}, IRQ {}, {}, {}",
+                    port.name(),
+                    port.base,
+                    port.irq(),
+                    config,
+                    if port.fifo.load(Ordering::Relaxed) {
+                        "16550A"
+                    } else {
+                        "no FIFO"
+                    }
+                );
+            }
+            Err(SerialError::NotPresent(_)) => {}
+            Err(err) => {
+                println!("Serial: {}", err);
+            }
+        }
+    }
+    if !found {
+        println!("Serial: no ports");
+    }
+}
+
+/// Starts a thread that echoes whatever comes in on the console back out of it, with the
+/// `\r` a terminal sends for Enter echoed as `\r\n`.
+pub fn start_console_thread() {
+    let Some(port) = console() else {
+        return;
+    };
+    sched::Builder::new("console").spawn(move || loop {
+        let mut buf = [0; FIFO_SIZE];
+        let len = port.read(&mut buf);
+        for &byte in &buf[..len] {
+            match byte {
+                b'\r' => port.write(b"\r\n"),
+                byte => port.write(&[byte]),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::{self, Duration};
+
+    #[test_case]
+    fn ring_wraps_and_fills_up() {
+        let mut ring = Ring::new();
+        for round in 0..3 {
+            for i in 0..RING_SIZE {
+                assert!(ring.push((i + round) as u8));
+            }
+            assert!(ring.is_full());
+            assert!(!ring.push(0));
+            for i in 0..RING_SIZE {
+                assert_eq!(ring.pop(), Some((i + round) as u8));
+            }
+            assert!(ring.is_empty());
+            assert_eq!(ring.pop(), None);
+            // Start the next round somewhere else.
+            ring.push(0);
+            ring.pop();
+        }
+    }
+
+    #[test_case]
+    fn line_configs_are_encoded() {
+        let config = |baud, data_bits, parity, stop_bits| LineConfig {
+            baud,
+            data_bits,
+            parity,
+            stop_bits,
+        };
+        let default = LineConfig::default();
+        assert_eq!(default.divisor(), Ok(1));
+        assert_eq!(default.line_control(), Ok(0b0000_0011));
+        assert_eq!(alloc::format!("{}", default), "115200 8N1");
+        let seven_e_two = config(9600, 7, Parity::Even, 2);
+        assert_eq!(seven_e_two.divisor(), Ok(12));
+        assert_eq!(seven_e_two.line_control(), Ok(0b0001_1110));
+        assert_eq!(config(110, 8, Parity::None, 1).divisor(), Ok(1047));
+        assert_eq!(
+            config(50, 5, Parity::Space, 1).line_control(),
+            Ok(0b0011_1000)
+        );
+
+        assert!(config(0, 8, Parity::None, 1).divisor().is_err());
+        assert!(config(230_400, 8, Parity::None, 1).divisor().is_err());
+        assert!(config(100_000, 8, Parity::None, 1).divisor().is_err());
+        assert!(config(9600, 9, Parity::None, 1).line_control().is_err());
+        assert!(config(9600, 8, Parity::None, 3).line_control().is_err());
+    }
+
+    #[test_case]
+    fn command_line_configs_are_parsed() {
+        assert_eq!(LineConfig::parse("115200,8N1"), Some(LineConfig::default()));
+        let config = LineConfig::parse("300,5m2").unwrap();
+        assert_eq!((config.baud, config.data_bits), (300, 5));
+        assert_eq!((config.parity, config.stop_bits), (Parity::Mark, 2));
+        assert_eq!(LineConfig::parse("9600,7O1").unwrap().parity, Parity::Odd);
+        assert_eq!(LineConfig::parse("9600,8S1").unwrap().parity, Parity::Space);
+        assert_eq!(LineConfig::parse("9600,8E1").unwrap().parity, Parity::Even);
+
+        assert_eq!(LineConfig::parse("9600"), None);
+        assert_eq!(LineConfig::parse("9600,8X1"), None);
+        assert_eq!(LineConfig::parse("9600,8N"), None);
+        assert_eq!(LineConfig::parse("fast,8N1"), None);
+    }
+
+    #[test_case]
+    fn console_passes_the_loopback_test() {
+        let Some(port) = console() else {
+            return;
+        };
+        assert!(port.loopback_test());
+    }
+
+    #[test_case]
+    fn bytes_go_round_through_the_rings() {
+        // Not the console, which would lose its output meanwhile. The runner gives QEMU a
+        // second port for this.
+        let Some(port) = com(2) else {
+            return;
+        };
+        // QEMU raises the port's interrupts in loopback mode, unlike a real PC, where OUT2
+        // doesn't reach the interrupt line then.
+        port.write_reg(MODEM_CONTROL, MCR_LOOPBACK | MCR_OUT2);
+        port.try_read(&mut [0; RING_SIZE]);
+
+        port.write(b"loopback");
+        let mut received = [0; 8];
+        let mut len = 0;
+        while len < received.len() {
+            len += port.read(&mut received[len..]);
+        }
+        assert_eq!(&received, b"loopback");
+
+        // Twice what the receive buffer holds, with nobody reading.
+        let dropped = port.dropped();
+        port.write(&[0x55; 2 * RING_SIZE]);
+        let deadline = time::now() + Duration::from_secs(1);
+        while port.dropped() - dropped < RING_SIZE as u64 && time::now() < deadline {
+            time::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(port.dropped() - dropped, RING_SIZE as u64);
+        let mut buf = [0; RING_SIZE];
+        assert_eq!(port.try_read(&mut buf), RING_SIZE);
+        assert!(buf.iter().all(|&byte| byte == 0x55));
+
+        port.write_reg(MODEM_CONTROL, MCR_DTR | MCR_RTS | MCR_OUT2);
+    }
+}
@@ -0,0 +1,557 @@
+//! Bringing up the application processors (APs).
+//!
+//! Only the bootstrap processor (BSP) runs after the firmware hands over; every other CPU sits
+//! waiting for an INIT IPI followed by startup IPIs (SIPIs) from it. A SIPI starts the AP in
+//! real mode at a page-aligned address below 1 MiB, so the startup code in trampoline.asm is
+//! copied to [`TRAMPOLINE_BASE`], a page the frame allocator never hands out. The trampoline
+//! page is identity mapped in the kernel page tables while APs are being started, since each
+//! AP switches to those tables before it jumps to its 64-bit entry point.
+//!
+//! APs are started one at a time, in MADT order, each on its own stack. CPU numbers are handed
+//! out in the order CPUs come online, the BSP being CPU 0.
+//!
+//! Once they are up, [`call_on`] runs a function on a set of CPUs, by sending each of them an
+//! IPI on [`CALL_VECTOR`] and waiting until all of them are done. Only one such cross call is in
+//! flight at a time. A CPU spinning on a lock with interrupts disabled can't take the IPI, so
+//! spin loops that may wait on a CPU which is making a cross call (the paging lock, for TLB
+//! shootdowns) call [`handle_calls`] while they wait.
+//!
+//! Before the machine is powered off or reset, [`park_others`] stops every other CPU for good
+//! with a cross call that never returns. A fatal error can't wait for that, so
+//! [`stop_others`] sends NMIs instead, which get through even to a CPU spinning with
+//! interrupts disabled.
+
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    hint::spin_loop,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+use x86_64::{
+    instructions::{hlt, interrupts},
+    registers::{control::Cr3, model_specific::GsBase},
+    structures::{
+        idt::InterruptStackFrame,
+        paging::{Page, PageTableFlags, PhysFrame, Size4KiB},
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    acpi,
+    apic::{self, LocalApic, ICR_DELIVERY_INIT, ICR_DELIVERY_STARTUP, ICR_LEVEL_ASSERT},
+    frame::{self, FRAME_SIZE},
+    idt, paging, percpu, println, sched,
+    sync::{IrqSpinLock, IrqSpinLockGuard},
+    time,
+};
+
+/// Most CPUs we will bring up. Must match MAX_CPUS in boot.asm.
+pub const MAX_CPUS: usize = 16;
+
+/// Where the trampoline is copied to. Must match TRAMPOLINE_BASE in trampoline.asm.
+pub const TRAMPOLINE_BASE: u64 = 0x8000;
+
+/// Stack size of each AP, the same as the BSP's kernel stack.
+const AP_STACK_FRAMES: usize = 16;
+
+/// How long an AP gets to report in before we give up on it.
+const AP_TIMEOUT_MS: u32 = 1000;
+
+/// How long [`park_others`] waits for the other CPUs to stop.
+const PARK_TIMEOUT_MS: u32 = 100;
+
+/// Vector of the IPI that makes a CPU run a cross call, see [`call_on`].
+pub const CALL_VECTOR: u8 = 0xfd;
+
+extern "C" {
+    // Defined in trampoline.asm
+    static smp_trampoline_start: u8;
+    static smp_trampoline_end: u8;
+    static smp_trampoline_args: u8;
+}
+
+/// Must match smp_trampoline_args in trampoline.asm.
+#[repr(C)]
+struct TrampolineArgs {
+    /// Physical address of the PML4, which the trampoline loads in 32-bit mode.
+    cr3: u64,
+    /// Top of the AP's stack.
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Number of CPUs that are up and running, the BSP included.
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+/// APIC ID of CPU number `cpu`, if it is online.
+pub fn apic_id(cpu: usize) -> Option<u32> {
+    percpu::get(cpu).map(|percpu| percpu.apic_id)
+}
+
+/// A set of CPUs, by CPU number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuMask(u64);
+
+impl CpuMask {
+    #[cfg(test)]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn single(cpu: usize) -> Self {
+        Self(1 << cpu)
+    }
+
+    /// Every CPU there could be, whether or not it is online.
+    pub const fn all() -> Self {
+        Self(u64::MAX)
+    }
+
+    /// Every CPU that is online.
+    pub fn online() -> Self {
+        Self((1 << cpu_count()) - 1)
+    }
+
+    /// Every CPU that is online, except the current one.
+    pub fn others() -> Self {
+        Self::online().without(current_cpu())
+    }
+
+    #[cfg(test)]
+    pub const fn with(self, cpu: usize) -> Self {
+        Self(self.0 | 1 << cpu)
+    }
+
+    pub const fn without(self, cpu: usize) -> Self {
+        Self(self.0 & !(1 << cpu))
+    }
+
+    pub const fn and(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn contains(self, cpu: usize) -> bool {
+        self.0 & 1 << cpu != 0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn count(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = usize> {
+        (0..MAX_CPUS).filter(move |&cpu| self.contains(cpu))
+    }
+}
+
+/// The CPUs in the mask, e.g. `0,2,3`, or `all` if it has every online CPU.
+impl fmt::Display for CpuMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.and(Self::online()) == Self::online() {
+            return write!(f, "all");
+        }
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        for (i, cpu) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", cpu)?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of the CPU we are running on. Until the APs are started this is always the boot CPU,
+/// which may not have its per-CPU data yet.
+pub fn current_cpu() -> usize {
+    if cpu_count() == 1 {
+        0
+    } else {
+        percpu!(cpu)
+    }
+}
+
+/// A cross call in flight.
+struct Call<'a> {
+    func: &'a (dyn Fn() + Sync),
+    /// CPUs that haven't finished running `func` yet.
+    pending: AtomicUsize,
+}
+
+/// The call each CPU has been asked to run, if any.
+static MAILBOXES: [AtomicPtr<Call<'static>>; MAX_CPUS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+static CALL_LOCK: IrqSpinLock<()> = IrqSpinLock::new(());
+
+/// CPUs stopped by [`park_others`].
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+/// Nobody waits for it to finish, so it can be static.
+static PARK_CALL: Call<'static> = Call {
+    func: &park,
+    pending: AtomicUsize::new(0),
+};
+
+/// Runs the cross call waiting for the current CPU, if there is one.
+pub fn handle_calls() {
+    // APs that are still starting up have no per-CPU data, and nobody sends them calls.
+    if cpu_count() == 1 || GsBase::read().is_null() {
+        return;
+    }
+    let call = MAILBOXES[percpu!(cpu)].swap(ptr::null_mut(), Ordering::AcqRel);
+    if let Some(call) = unsafe { call.as_ref() } {
+        (call.func)();
+        call.pending.fetch_sub(1, Ordering::Release);
+    }
+}
+
+extern "x86-interrupt" fn call_interrupt(_stack_frame: InterruptStackFrame) {
+    handle_calls();
+    apic::eoi();
+}
+
+/// Takes the right to make a cross call, answering other CPUs' calls while waiting for it.
+/// Interrupts must be disabled.
+fn lock_calls() -> IrqSpinLockGuard<'static, ()> {
+    loop {
+        if let Some(guard) = CALL_LOCK.try_lock() {
+            return guard;
+        }
+        handle_calls();
+        spin_loop();
+    }
+}
+
+/// Hands `call` to every CPU in `targets` and interrupts them to run it.
+fn post(lapic: &LocalApic, targets: CpuMask, call: *mut Call<'static>) {
+    for cpu in targets.iter() {
+        MAILBOXES[cpu].store(call, Ordering::Release);
+    }
+    // One IPI does for all of them. CPUs that never came online get it too, but they either
+    // wait for a SIPI, which ignores it, or have interrupts disabled for good.
+    if targets == CpuMask::others() {
+        lapic.broadcast_ipi(CALL_VECTOR);
+        return;
+    }
+    for cpu in targets.iter() {
+        lapic.send_ipi(
+            apic_id(cpu).expect("Online CPU without an APIC ID"),
+            CALL_VECTOR,
+        );
+    }
+}
+
+/// Runs `func` on every online CPU in `mask`, this one included if it is in there, and returns
+/// once all of them are done. `func` runs in interrupt context on the other CPUs.
+pub fn call_on(mask: CpuMask, func: &(dyn Fn() + Sync)) {
+    interrupts::without_interrupts(|| {
+        let me = current_cpu();
+        let targets = mask.and(CpuMask::online()).without(me);
+        if targets.is_empty() {
+            if mask.contains(me) {
+                func();
+            }
+            return;
+        }
+        let lapic = apic::local().expect("Other CPUs are online without a local APIC");
+        let _guard = lock_calls();
+
+        let call = Call {
+            func,
+            pending: AtomicUsize::new(targets.count()),
+        };
+        // Only borrowed until every target is done with it, which is waited for below.
+        post(lapic, targets, &call as *const Call as *mut Call<'static>);
+        if mask.contains(me) {
+            func();
+        }
+        while call.pending.load(Ordering::Acquire) != 0 {
+            spin_loop();
+        }
+    });
+}
+
+fn park() {
+    PARKED.fetch_add(1, Ordering::SeqCst);
+    loop {
+        interrupts::disable();
+        hlt();
+    }
+}
+
+/// Stops every other CPU for good, and returns once they have all stopped, or after
+/// [`PARK_TIMEOUT_MS`]. A CPU only takes the cross call with interrupts enabled, so none of
+/// them is left holding an [`IrqSpinLock`](crate::sync::IrqSpinLock).
+pub fn park_others() {
+    interrupts::without_interrupts(|| {
+        let targets = CpuMask::others();
+        if targets.is_empty() {
+            return;
+        }
+        let lapic = apic::local().expect("Other CPUs are online without a local APIC");
+        let _guard = lock_calls();
+        post(
+            lapic,
+            targets,
+            &PARK_CALL as *const Call as *mut Call<'static>,
+        );
+        for _ in 0..PARK_TIMEOUT_MS {
+            if PARKED.load(Ordering::SeqCst) >= targets.count() {
+                return;
+            }
+            time::udelay(1000);
+        }
+        println!("SMP: not every CPU stopped.");
+    });
+}
+
+/// Set once [`stop_others`] has been called.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Stops every other CPU with an NMI, without waiting for them. Takes no locks, so it can be
+/// called from [`exception::fatal_error`](crate::exception::fatal_error).
+pub fn stop_others() {
+    STOPPING.store(true, Ordering::SeqCst);
+    let Some(lapic) = apic::local() else {
+        return;
+    };
+    for cpu in CpuMask::others().iter() {
+        if let Some(id) = apic_id(cpu) {
+            lapic.send_nmi(id);
+        }
+    }
+}
+
+/// Whether some CPU has called [`stop_others`], in which case an NMI means to halt.
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// Where APs end up after the trampoline, with interrupts disabled and the kernel page tables
+/// loaded.
+extern "C" fn ap_main(cpu: usize) -> ! {
+    idt::load();
+    let lapic = apic::local().expect("AP started without a local APIC");
+    lapic.enable();
+    percpu::init(cpu);
+
+    println!("CPU {} (APIC {}) online.", cpu, percpu!(apic_id));
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+    // Only once it is counted, so that it knows which CPU it is.
+    time::start_local_tick();
+
+    sched::run_idle()
+}
+
+/// Sends the INIT-SIPI-SIPI sequence to `apic_id` and waits for it to come online as `cpu`.
+fn start_ap(lapic: &LocalApic, apic_id: u32, cpu: usize) -> bool {
+    let online = || ONLINE.load(Ordering::SeqCst) > cpu;
+
+    lapic.write_icr(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    time::udelay(10_000);
+
+    // The SIPI vector is the page number of the start address. A second SIPI is only there in
+    // case the first one got lost, and is ignored by an AP that is already running.
+    let vector = (TRAMPOLINE_BASE / FRAME_SIZE) as u32;
+    for _ in 0..2 {
+        lapic.write_icr(apic_id, ICR_DELIVERY_STARTUP | vector);
+        time::udelay(200);
+        if online() {
+            return true;
+        }
+    }
+
+    for _ in 0..AP_TIMEOUT_MS {
+        if online() {
+            return true;
+        }
+        time::udelay(1000);
+    }
+    // Put it back to sleep, so that it can't wake up later on someone else's stack.
+    lapic.write_icr(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    false
+}
+
+/// Copies the trampoline into place and returns its argument block.
+fn install_trampoline() -> *mut TrampolineArgs {
+    let start = unsafe { &smp_trampoline_start as *const u8 };
+    let end = unsafe { &smp_trampoline_end as *const u8 };
+    let args = unsafe { &smp_trampoline_args as *const u8 };
+    let len = end as usize - start as usize;
+    assert!(len as u64 <= FRAME_SIZE, "AP trampoline is too big");
+
+    let base = paging::phys_to_virt(PhysAddr::new(TRAMPOLINE_BASE));
+    unsafe {
+        core::ptr::copy_nonoverlapping(start, base.as_mut_ptr::<u8>(), len);
+    }
+    (base + (args as usize - start as usize)).as_mut_ptr()
+}
+
+/// Starts every enabled CPU listed in the MADT. Must run after [`apic::init`], [`acpi::init`]
+/// and [`percpu::init`] for the boot CPU.
+pub fn init() {
+    let Some(lapic) = apic::local() else {
+        return;
+    };
+    let bsp = lapic.id();
+
+    let Some(madt) = acpi::get().and_then(|acpi| acpi.madt.as_ref()) else {
+        println!("SMP: no MADT, running on the boot CPU only.");
+        return;
+    };
+    let aps: Vec<u32> = madt
+        .processors
+        .iter()
+        .filter(|cpu| cpu.enabled && cpu.apic_id != bsp)
+        .map(|cpu| cpu.apic_id)
+        .collect();
+    if aps.is_empty() {
+        return;
+    }
+    idt::register(CALL_VECTOR, call_interrupt).expect("Cross call vector already in use");
+
+    let args = install_trampoline();
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TRAMPOLINE_BASE));
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_BASE));
+    paging::map(page, frame, PageTableFlags::WRITABLE).expect("Failed to map the AP trampoline");
+    let cr3 = Cr3::read().0.start_address().as_u64();
+
+    for apic_id in aps {
+        let cpu = cpu_count();
+        if cpu == MAX_CPUS {
+            println!("SMP: only {} CPUs are supported.", MAX_CPUS);
+            break;
+        }
+
+        let stack = frame::alloc_contiguous(AP_STACK_FRAMES, FRAME_SIZE)
+            .expect("Out of memory for AP stacks");
+        let stack_top =
+            paging::phys_to_virt(stack.start_address()) + AP_STACK_FRAMES as u64 * FRAME_SIZE;
+        unsafe {
+            args.write_volatile(TrampolineArgs {
+                cr3,
+                stack: stack_top.as_u64(),
+                entry: ap_main as extern "C" fn(usize) -> ! as usize as u64,
+                cpu: cpu as u64,
+            });
+        }
+
+        if !start_ap(lapic, apic_id, cpu) {
+            println!("SMP: CPU with APIC ID {} did not come online.", apic_id);
+            frame::free_contiguous(stack, AP_STACK_FRAMES);
+        }
+    }
+
+    paging::unmap(page).expect("Failed to unmap the AP trampoline");
+    println!(
+        "SMP: {} of {} CPUs online.",
+        cpu_count(),
+        madt.processors.iter().filter(|cpu| cpu.enabled).count()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn every_enabled_cpu_is_online() {
+        let Some(madt) = acpi::get().and_then(|acpi| acpi.madt.as_ref()) else {
+            return;
+        };
+        if apic::local().is_none() {
+            return;
+        }
+        let enabled = madt.processors.iter().filter(|cpu| cpu.enabled).count();
+        assert_eq!(cpu_count(), enabled.min(MAX_CPUS));
+    }
+
+    #[test_case]
+    fn apic_ids_are_unique() {
+        let ids: Vec<u32> = (0..cpu_count()).filter_map(apic_id).collect();
+        assert_eq!(ids.len(), cpu_count());
+        for (i, id) in ids.iter().enumerate() {
+            assert!(!ids[i + 1..].contains(id));
+        }
+        if apic::local().is_some() {
+            assert_eq!(apic_id(0), Some(apic::id()));
+        }
+    }
+
+    #[test_case]
+    fn cpu_masks_print_as_lists() {
+        assert_eq!(alloc::format!("{}", CpuMask::single(1).with(3)), "1,3");
+        assert_eq!(alloc::format!("{}", CpuMask::empty()), "none");
+        assert_eq!(alloc::format!("{}", CpuMask::all()), "all");
+    }
+
+    #[test_case]
+    fn call_on_runs_everywhere_once() {
+        let calls = AtomicUsize::new(0);
+        call_on(CpuMask::online(), &|| {
+            calls.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(calls.load(Ordering::SeqCst), cpu_count());
+
+        calls.store(0, Ordering::SeqCst);
+        call_on(CpuMask::others(), &|| {
+            assert_ne!(current_cpu(), 0);
+            calls.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(calls.load(Ordering::SeqCst), cpu_count() - 1);
+    }
+
+    #[test_case]
+    fn unmap_shoots_down_other_cpus() {
+        let frames = [frame::alloc_frame().unwrap(), frame::alloc_frame().unwrap()];
+        for (value, frame) in frames.iter().enumerate() {
+            let ptr = paging::phys_to_virt(frame.start_address()).as_mut_ptr::<u64>();
+            unsafe { ptr.write_volatile(value as u64) };
+        }
+        let addr = paging::map_mmio(frames[0].start_address(), FRAME_SIZE);
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let ptr = addr.as_u64() as usize;
+
+        // Every CPU caches the first translation, then must see the second one.
+        let mismatches = AtomicUsize::new(0);
+        let read_on_all = |expected: u64| {
+            call_on(CpuMask::online(), &|| {
+                let value = unsafe { (ptr as *const u64).read_volatile() };
+                if value != expected {
+                    mismatches.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+        };
+        read_on_all(0);
+        assert_eq!(paging::unmap(page), Ok(frames[0]));
+        paging::map(page, frames[1], PageTableFlags::PRESENT).unwrap();
+        read_on_all(1);
+        assert_eq!(mismatches.load(Ordering::SeqCst), 0);
+
+        paging::unmap(page).unwrap();
+        frames.into_iter().for_each(frame::free_frame);
+    }
+
+    #[test_case]
+    fn cpu_masks() {
+        let mask = CpuMask::empty().with(1).with(3);
+        assert_eq!(mask.count(), 2);
+        assert!(mask.contains(3) && !mask.contains(2));
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [1, 3]);
+        assert!(mask.without(1).without(3).is_empty());
+        assert_eq!(CpuMask::single(2), CpuMask::empty().with(2));
+        assert_eq!(CpuMask::online().count(), cpu_count());
+    }
+}
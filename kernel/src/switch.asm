@@ -0,0 +1,48 @@
+	[BITS 64]
+
+	; Kernel thread context switch, see kernel/src/sched.rs.
+	;
+	; A thread that isn't running is described by nothing more than its saved stack pointer:
+	; everything else the System V ABI expects to survive a call (rbx, rbp, r12-r15) is pushed
+	; on its own stack, on top of the address to return to. Caller-saved registers have
+	; already been saved by whoever called into the scheduler, if they mattered.
+	;
+	; A new thread's stack is made to look like it was switched away from right before
+	; thread_start, with the thread's argument in r12.
+	;
+	; Stack of a thread that isn't running, from the top down:
+	;   return address
+	;   rbp, rbx, r12, r13, r14, r15  <- saved stack pointer
+
+	extern thread_entry
+
+	global context_switch
+	global thread_start
+
+	section .text
+
+	; void context_switch(u64 *old_rsp, u64 new_rsp)
+context_switch:
+	push rbp
+	push rbx
+	push r12
+	push r13
+	push r14
+	push r15
+
+	mov [rdi], rsp
+	mov rsp, rsi
+
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbx
+	pop rbp
+	ret
+
+	; First code run by a new thread, with its stack 16-byte aligned.
+thread_start:
+	mov  rdi, r12
+	call thread_entry
+	ud2
@@ -0,0 +1,761 @@
+//! Locks, and other ways for threads to wait for each other.
+//!
+//! [`IrqSpinLock`] busy-waits, and keeps interrupts disabled on the CPU holding it, so that an
+//! interrupt handler can't try to take a lock that the code it interrupted holds. It is the one
+//! to use for data that interrupt handlers touch, and for anything only held briefly.
+//!
+//! Everything else puts a thread that has to wait to sleep on a [`WaitQueue`] until it can go
+//! on, leaving the CPU to other threads: [`Mutex`], [`RwLock`], [`Semaphore`] and [`Condvar`].
+//! They can only be waited on by threads, with interrupts enabled, so not from interrupt
+//! handlers or while holding an [`IrqSpinLock`].
+//!
+//! In debug builds, taking a lock that is already held by the same CPU, for an
+//! [`IrqSpinLock`], or by the same thread, for the others, panics instead of deadlocking.
+
+use alloc::collections::VecDeque;
+#[cfg(test)]
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, AtomicUsize},
+};
+use core::{
+    mem::{self, ManuallyDrop},
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+use crate::{
+    sched::{self, Waker},
+    smp,
+};
+
+/// Who holds a lock, to catch recursive locking in debug builds.
+struct Owner(AtomicU64);
+
+impl Owner {
+    const NONE: u64 = u64::MAX;
+
+    const fn new() -> Self {
+        Self(AtomicU64::new(Self::NONE))
+    }
+
+    /// Whether `id` holds the lock, as far as debug builds keep track.
+    fn is(&self, id: u64) -> bool {
+        cfg!(debug_assertions) && id != Self::NONE && self.0.load(Ordering::Relaxed) == id
+    }
+
+    /// Panics if `id` already holds the lock.
+    fn check(&self, id: u64, lock: &str) {
+        if self.is(id) {
+            panic!(
+                "Recursive locking of {} {:#x} by {}",
+                lock, self as *const _ as usize, id
+            );
+        }
+    }
+
+    fn set(&self, id: u64) {
+        if cfg!(debug_assertions) {
+            self.0.store(id, Ordering::Relaxed);
+        }
+    }
+
+    fn clear(&self) {
+        self.set(Self::NONE);
+    }
+}
+
+/// ID of the current thread, for [`Owner`].
+#[cfg(test)]
+fn current_thread() -> u64 {
+    sched::current_id().map_or(Owner::NONE, sched::ThreadId::as_u64)
+}
+
+/// A spin lock that disables interrupts while it is held.
+pub struct IrqSpinLock<T: ?Sized> {
+    owner: Owner,
+    inner: spin::Mutex<T>,
+}
+
+pub struct IrqSpinLockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSpinLock<T>,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    /// Whether interrupts were enabled before locking.
+    enable: bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            owner: Owner::new(),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let enable = interrupts::are_enabled();
+        interrupts::disable();
+        let cpu = smp::current_cpu() as u64;
+        self.owner.check(cpu, "IrqSpinLock");
+        let guard = self.inner.lock();
+        self.owner.set(cpu);
+        IrqSpinLockGuard {
+            lock: self,
+            guard: ManuallyDrop::new(guard),
+            enable,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let enable = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                self.owner.set(smp::current_cpu() as u64);
+                Some(IrqSpinLockGuard {
+                    lock: self,
+                    guard: ManuallyDrop::new(guard),
+                    enable,
+                })
+            }
+            None => {
+                if enable {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Releases the lock, whoever holds it.
+    ///
+    /// # Safety
+    ///
+    /// Whoever holds it must never touch the data again, like a CPU that has stopped for good.
+    pub unsafe fn force_unlock(&self) {
+        self.owner.clear();
+        self.inner.force_unlock();
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.clear();
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.enable {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Threads sleeping until something happens.
+pub struct WaitQueue {
+    waiters: IrqSpinLock<VecDeque<Waker>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqSpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Sleeps until `condition` holds, checking it first and then every time the thread is
+    /// woken up. It is checked with the queue locked, so whatever makes it hold can't be missed
+    /// as long as the queue is woken up after.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        check_can_sleep();
+        loop {
+            {
+                let mut waiters = self.waiters.lock();
+                if condition() {
+                    return;
+                }
+                waiters.push_back(sched::prepare_block());
+            }
+            sched::block();
+        }
+    }
+
+    /// Sleeps until the thread is woken up, calling `then` once it is queued, so that anything
+    /// `then` lets happen can wake it up.
+    #[cfg(test)]
+    fn wait_then(&self, then: impl FnOnce()) {
+        check_can_sleep();
+        self.waiters.lock().push_back(sched::prepare_block());
+        then();
+        sched::block();
+    }
+
+    /// Wakes up the thread that has been waiting the longest, and returns whether there was one.
+    pub fn wake_one(&self) -> bool {
+        let waiter = self.waiters.lock().pop_front();
+        waiter.map(Waker::wake).is_some()
+    }
+
+    /// Wakes up every waiting thread, and returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = mem::take(&mut *self.waiters.lock());
+        let count = waiters.len();
+        waiters.into_iter().for_each(Waker::wake);
+        count
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// An interrupt handler, or the thread holding an [`IrqSpinLock`], can't go to sleep.
+fn check_can_sleep() {
+    debug_assert!(
+        interrupts::are_enabled(),
+        "Sleeping with interrupts disabled"
+    );
+}
+
+/// A lock that puts threads waiting for it to sleep.
+#[cfg(test)]
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    owner: Owner,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+#[cfg(test)]
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+#[cfg(test)]
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+#[cfg(test)]
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+#[cfg(test)]
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: Owner::new(),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+}
+
+#[cfg(test)]
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let thread = current_thread();
+        self.owner.check(thread, "Mutex");
+        if !self.acquire() {
+            self.waiters.wait_until(|| self.acquire());
+        }
+        self.owner.set(thread);
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.acquire().then(|| {
+            self.owner.set(current_thread());
+            MutexGuard { mutex: self }
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn unlock(&self) {
+        self.owner.clear();
+        self.locked.store(false, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+#[cfg(test)]
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+#[cfg(test)]
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+#[cfg(test)]
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// [`RwLock`] state when a writer holds it.
+#[cfg(test)]
+const WRITER: usize = usize::MAX;
+
+/// A lock that any number of readers can hold at once, or a single writer. Once a writer is
+/// waiting, new readers wait for it, so that readers can't keep it out forever; a thread that
+/// takes a read lock it already holds may deadlock.
+#[cfg(test)]
+pub struct RwLock<T: ?Sized> {
+    /// Number of readers, or [`WRITER`].
+    state: AtomicUsize,
+    writers_waiting: AtomicUsize,
+    writer: Owner,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+#[cfg(test)]
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+#[cfg(test)]
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+#[cfg(test)]
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+#[cfg(test)]
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+#[cfg(test)]
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            writer: Owner::new(),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+}
+
+#[cfg(test)]
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.writer.check(current_thread(), "RwLock");
+        if !self.acquire_read() {
+            self.waiters.wait_until(|| self.acquire_read());
+        }
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.acquire_read()
+            .then_some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let thread = current_thread();
+        self.writer.check(thread, "RwLock");
+        if !self.acquire_write() {
+            self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+            self.waiters.wait_until(|| self.acquire_write());
+            self.writers_waiting.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.writer.set(thread);
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.acquire_write().then(|| {
+            self.writer.set(current_thread());
+            RwLockWriteGuard { lock: self }
+        })
+    }
+
+    /// Number of readers holding the lock.
+    pub fn readers(&self) -> usize {
+        match self.state.load(Ordering::Relaxed) {
+            WRITER => 0,
+            readers => readers,
+        }
+    }
+
+    fn acquire_read(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state == WRITER || self.writers_waiting.load(Ordering::Relaxed) > 0 {
+                return false;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => state = actual,
+            }
+        }
+    }
+
+    fn acquire_write(&self) -> bool {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+#[cfg(test)]
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            // Readers and writers wait in the same queue, and only a writer can go on now, so
+            // they all get to check.
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+#[cfg(test)]
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+#[cfg(test)]
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+#[cfg(test)]
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.writer.clear();
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.waiters.wake_all();
+    }
+}
+
+/// A counting semaphore.
+#[cfg(test)]
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+#[cfg(test)]
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes one unit, sleeping until there is one.
+    pub fn acquire(&self) {
+        if !self.try_acquire() {
+            self.waiters.wait_until(|| self.try_acquire());
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Gives back one unit, waking up a thread waiting for it.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// Lets threads sleep until another one tells them that some data behind a [`Mutex`] changed.
+#[cfg(test)]
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+#[cfg(test)]
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks the mutex and sleeps until notified, then locks it again. The thread is queued
+    /// before the mutex is unlocked, so a notification sent after that can't be missed. It may
+    /// also wake up without a notification, so the condition waited for has to be checked
+    /// again, see [`Condvar::wait_while`].
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        self.waiters.wait_then(|| drop(guard));
+        mutex.lock()
+    }
+
+    /// Sleeps for as long as `condition` holds, with the mutex unlocked while it does.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec::Vec};
+
+    use super::*;
+    use crate::time;
+
+    #[test_case]
+    fn irq_spin_lock_disables_interrupts() {
+        let lock = IrqSpinLock::new(0);
+        assert!(interrupts::are_enabled());
+        {
+            let mut value = lock.lock();
+            *value += 1;
+            assert!(!interrupts::are_enabled());
+            assert!(lock.try_lock().is_none());
+            // Still held, so they stay disabled.
+            assert!(!interrupts::are_enabled());
+        }
+        assert!(interrupts::are_enabled());
+        assert_eq!(*lock.try_lock().unwrap(), 1);
+        assert!(interrupts::are_enabled());
+    }
+
+    #[test_case]
+    fn recursive_locking_is_detected() {
+        // `check` panics when `is` finds the CPU or thread holding the lock already. The panic
+        // can't be caught here, so this tests `is` on a held lock instead.
+        let held = cfg!(debug_assertions);
+        let cpu = smp::current_cpu() as u64;
+        let spin = IrqSpinLock::new(());
+        {
+            let _guard = spin.lock();
+            assert_eq!(spin.owner.is(cpu), held);
+        }
+        assert!(!spin.owner.is(cpu));
+
+        let thread = current_thread();
+        assert_ne!(thread, Owner::NONE);
+        let mutex = Mutex::new(());
+        {
+            let _guard = mutex.lock();
+            assert_eq!(mutex.owner.is(thread), held);
+            // Another thread only has to wait.
+            assert!(!mutex.owner.is(thread + 1));
+        }
+        assert!(!mutex.owner.is(thread));
+
+        let rwlock = RwLock::new(());
+        {
+            let _guard = rwlock.write();
+            assert_eq!(rwlock.writer.is(thread), held);
+        }
+        assert!(!rwlock.writer.is(thread));
+    }
+
+    #[test_case]
+    fn mutex_excludes_other_threads() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 50;
+        let counter = Arc::new(Mutex::new(0));
+        let done = Arc::new(Semaphore::new(0));
+        for _ in 0..THREADS {
+            let (counter, done) = (counter.clone(), done.clone());
+            sched::spawn("contender", move || {
+                for _ in 0..ROUNDS {
+                    let mut counter = counter.lock();
+                    let seen = *counter;
+                    // Give the others a chance to get in the way.
+                    sched::yield_now();
+                    *counter = seen + 1;
+                }
+                done.release();
+            });
+        }
+        for _ in 0..THREADS {
+            done.acquire();
+        }
+        assert_eq!(*counter.lock(), THREADS * ROUNDS);
+        assert!(!counter.is_locked());
+    }
+
+    #[test_case]
+    fn semaphore_blocks_at_zero() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        assert!(semaphore.try_acquire());
+        assert!(!semaphore.try_acquire());
+
+        let woken = Arc::new(AtomicBool::new(false));
+        {
+            let (semaphore, woken) = (semaphore.clone(), woken.clone());
+            sched::spawn("waiter", move || {
+                semaphore.acquire();
+                woken.store(true, Ordering::SeqCst);
+            });
+        }
+        while semaphore.waiters.is_empty() {
+            sched::yield_now();
+        }
+        assert!(!woken.load(Ordering::SeqCst));
+        semaphore.release();
+        while !woken.load(Ordering::SeqCst) {
+            sched::yield_now();
+        }
+        assert_eq!(semaphore.count(), 0);
+    }
+
+    #[test_case]
+    fn sleeping_threads_are_off_the_run_queues() {
+        let semaphore = Arc::new(Semaphore::new(0));
+        let id = {
+            let semaphore = semaphore.clone();
+            sched::spawn("sleeper", move || semaphore.acquire())
+        };
+        while semaphore.waiters.is_empty() {
+            sched::yield_now();
+        }
+        // It may still be on its way off its CPU.
+        let listed = || sched::threads().iter().any(|thread| thread.id == id);
+        let end = time::ticks() + 10;
+        while listed() && time::ticks() < end {
+            sched::yield_now();
+        }
+        assert!(!listed());
+        semaphore.release();
+    }
+
+    #[test_case]
+    fn condvar_ping_pong() {
+        const ROUNDS: usize = 20;
+        // Whose turn it is, and how many turns have been taken.
+        let state = Arc::new((Mutex::new((false, 0)), Condvar::new()));
+        let done = Arc::new(Semaphore::new(0));
+        for me in [false, true] {
+            let (state, done) = (state.clone(), done.clone());
+            sched::spawn("player", move || {
+                let (mutex, condvar) = &*state;
+                for _ in 0..ROUNDS {
+                    let mut turn = condvar.wait_while(mutex.lock(), |(turn, _)| *turn != me);
+                    turn.0 = !me;
+                    turn.1 += 1;
+                    condvar.notify_all();
+                }
+                done.release();
+            });
+        }
+        done.acquire();
+        done.acquire();
+        assert_eq!(state.0.lock().1, 2 * ROUNDS);
+    }
+
+    #[test_case]
+    fn rwlock_shares_reads_and_excludes_writes() {
+        let lock = Arc::new(RwLock::new(Vec::new()));
+        {
+            let first = lock.read();
+            let second = lock.try_read().unwrap();
+            assert_eq!(lock.readers(), 2);
+            assert!(lock.try_write().is_none());
+            assert_eq!(first.len(), second.len());
+        }
+        let done = Arc::new(Semaphore::new(0));
+        {
+            let reader = lock.read();
+            for i in 0..3 {
+                let (lock, done) = (lock.clone(), done.clone());
+                sched::spawn("writer", move || {
+                    lock.write().push(i);
+                    done.release();
+                });
+            }
+            while lock.waiters.len() < 3 {
+                sched::yield_now();
+            }
+            // Writers are waiting, so new readers have to as well.
+            assert!(lock.try_read().is_none());
+            assert!(reader.is_empty());
+        }
+        for _ in 0..3 {
+            done.acquire();
+        }
+        let mut values = lock.read().clone();
+        values.sort();
+        assert_eq!(values, [0, 1, 2]);
+        assert_eq!(lock.readers(), 0);
+    }
+}
@@ -0,0 +1,535 @@
+//! Time since boot, and waiting for it to pass.
+//!
+//! The PIT (or the HPET, see the hpet module) interrupts the boot CPU [`hz`] times a second,
+//! [`DEFAULT_HZ`] unless the command line says otherwise with `hz=`, and [`ticks`] counts those
+//! ticks. The monotonic clock, [`now`], reads the best counter the machine has (see
+//! [`clocksource`]), down to the nanosecond if it has a TSC, or else counts the ticks.
+//!
+//! With local APICs, every CPU also gets a tick of its own from its local APIC timer, which
+//! drives its scheduler (see [`start_local_tick`]). With `nohz=on` on the command line and a
+//! clock source that doesn't count ticks, there is no global tick at all, and each CPU's timer
+//! is only programmed for its next event (see [`reprogram`]): the end of the running thread's
+//! time slice, if another thread is waiting for it, and on the boot CPU, the earliest timer.
+//! An idle CPU, or one with a single thread, isn't interrupted at all until then.
+//!
+//! The wall clock, [`wall_clock`], is the date last read from the RTC, at boot and then at the
+//! start of every minute, moved on by the monotonic clock since.
+//!
+//! Threads wait with [`sleep`]. Code that can't sleep, because it runs before the scheduler or
+//! with interrupts disabled, busy-waits with [`udelay`] instead, which doesn't need the tick.
+//!
+//! Drivers can have a callback run once after a delay with [`after`], or periodically with
+//! [`every`]. Callbacks run from the tick interrupt on the boot CPU, with interrupts disabled,
+//! on the first tick after they are due, or in tickless mode, right when they are due.
+
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::{
+    fmt,
+    ops::{Add, AddAssign, Sub},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+pub mod clocksource;
+pub mod date;
+
+pub use core::time::Duration;
+pub use date::DateTime;
+#[cfg(test)]
+use x86_64::instructions::interrupts;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{apic, boot_info, hpet, pit, println, sched, smp, sync::IrqSpinLock};
+
+/// Tick rate, unless the command line sets another with `hz=`.
+pub const DEFAULT_HZ: u32 = 100;
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Unix time at boot, in nanoseconds, 0 until the wall clock is set.
+static BOOT_UNIX_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether every CPU has a tick of its own, see [`start_local_tick`].
+static LOCAL_TICKS: AtomicBool = AtomicBool::new(false);
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+
+/// A point in time, as nanoseconds since boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// When the clock started.
+    #[cfg(test)]
+    pub const BOOT: Self = Self(0);
+
+    pub fn now() -> Self {
+        Self(clocksource::nanos())
+    }
+
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Self(nanos)
+    }
+
+    pub const fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// Time from `earlier` to this, zero if `earlier` is later.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    #[cfg(test)]
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Self> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_add(nanos))
+            .map(Self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// Saturates at the end of time, so that "never" can be written as a long delay.
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).unwrap_or(Self(u64::MAX))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:06}",
+            self.0 / NANOS_PER_SEC,
+            self.0 % NANOS_PER_SEC / 1000
+        )
+    }
+}
+
+/// A frequency, printed in MHz.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hz(pub u64);
+
+impl fmt::Display for Hz {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:03} MHz", self.0 / 1_000_000, self.0 / 1000 % 1000)
+    }
+}
+
+/// Starts the tick, at the rate given on the command line if any.
+pub fn init() {
+    let hz = boot_info::boot_info()
+        .option("hz")
+        .and_then(|hz| hz.parse().ok())
+        .filter(|&hz| hz > 0)
+        .unwrap_or(DEFAULT_HZ);
+    pit::init(hz);
+    println!("Tick: {} Hz", self::hz());
+    clocksource::register(&clocksource::Ticks);
+}
+
+/// Time since boot.
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+/// Sets the wall clock to `now`, from which it follows the monotonic clock.
+pub fn set_wall_clock(now: DateTime) {
+    let unix_nanos = now.to_unix() * NANOS_PER_SEC;
+    BOOT_UNIX_NANOS.store(
+        unix_nanos.saturating_sub(Instant::now().as_nanos()),
+        Ordering::Relaxed,
+    );
+}
+
+/// The date and time in UTC, if the wall clock has been set (from the RTC, at boot).
+pub fn wall_clock() -> Option<DateTime> {
+    match BOOT_UNIX_NANOS.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(DateTime::from_unix(
+            (boot + Instant::now().as_nanos()) / NANOS_PER_SEC,
+        )),
+    }
+}
+
+/// Number of ticks since [`init`]. In tickless mode, the ones there would have been.
+pub fn ticks() -> u64 {
+    let ticks = TICKS.load(Ordering::Relaxed);
+    if !is_tickless() {
+        return ticks;
+    }
+    let period = tick_period().as_nanos() as u64;
+    ticks.max(now().as_nanos() / period)
+}
+
+/// Actual tick rate, which may be a little off from the one asked for.
+pub fn hz() -> u32 {
+    match pit::divisor() {
+        0 => 0,
+        divisor => (pit::FREQUENCY + divisor / 2) / divisor,
+    }
+}
+
+/// Time between two ticks.
+pub fn tick_period() -> Duration {
+    Duration::from_nanos(pit::divisor() as u64 * NANOS_PER_SEC / pit::FREQUENCY as u64)
+}
+
+/// Called from the timer interrupt on the boot CPU.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    run_timers(Instant::now());
+    if !LOCAL_TICKS.load(Ordering::Relaxed) {
+        sched::tick();
+    }
+}
+
+/// Whether the CPUs only get timer interrupts when they have something to do.
+pub fn is_tickless() -> bool {
+    TICKLESS.load(Ordering::Relaxed)
+}
+
+/// Moves the scheduler's tick onto every CPU's own local APIC timer, and goes tickless if the
+/// command line asks for it, stopping the global tick. Must run after
+/// [`apic::timer::init`], and the other CPUs call [`start_local_tick`] as they come online.
+pub fn init_local_ticks() {
+    if apic::timer::frequency() == 0 {
+        return;
+    }
+    let counts_ticks = clocksource::current().is_none_or(|source| source.name() == "pit");
+    let tickless = boot_info::boot_info().option("nohz") == Some("on") && !counts_ticks;
+    without_interrupts(|| {
+        if tickless {
+            pit::stop();
+            hpet::stop_tick();
+            TICKLESS.store(true, Ordering::Relaxed);
+        }
+        LOCAL_TICKS.store(true, Ordering::Relaxed);
+        start_local_tick();
+    });
+    println!(
+        "Tick: local APIC timers, {}",
+        if tickless { "tickless" } else { "periodic" }
+    );
+}
+
+/// Starts this CPU's local APIC timer: periodically at the tick rate, or in tickless mode,
+/// for its next event. Interrupts must be disabled.
+pub fn start_local_tick() {
+    if !LOCAL_TICKS.load(Ordering::Relaxed) {
+        return;
+    }
+    if is_tickless() {
+        sched::restart_ticks(Instant::now());
+        reprogram();
+    } else {
+        apic::timer::start_periodic(tick_period());
+    }
+}
+
+/// Called from the local APIC timer interrupt of every CPU.
+pub fn local_tick() {
+    if !is_tickless() {
+        sched::tick();
+        return;
+    }
+    let now = Instant::now();
+    if smp::current_cpu() == 0 {
+        run_timers(now);
+    }
+    sched::tick_until(now);
+    reprogram();
+}
+
+/// In tickless mode, programs this CPU's timer for its next event: the end of the running
+/// thread's time slice, or on the boot CPU, the earliest timer, whichever comes first. Anything
+/// that may change either calls this. Interrupts must be disabled.
+pub fn reprogram() {
+    if !is_tickless() {
+        return;
+    }
+    let timer = if smp::current_cpu() == 0 {
+        TIMERS
+            .lock()
+            .pending
+            .first_key_value()
+            .map(|(key, _)| key.0)
+    } else {
+        None
+    };
+    match timer.into_iter().chain(sched::slice_end()).min() {
+        Some(deadline) => apic::timer::set_deadline(deadline),
+        None => apic::timer::stop(),
+    }
+}
+
+/// Busy-waits for at least `us` microseconds. Works with interrupts disabled, and before
+/// [`init`].
+pub fn udelay(us: u64) {
+    let mut cycles = (us as u128 * pit::FREQUENCY as u128).div_ceil(1_000_000) as u64;
+    while cycles > 0 {
+        let chunk = cycles.min(u16::MAX as u64);
+        pit::wait(chunk as u16);
+        cycles -= chunk;
+    }
+}
+
+/// Busy-waits for at least `ms` milliseconds, see [`udelay`].
+pub fn mdelay(ms: u64) {
+    udelay(ms * 1000);
+}
+
+/// Puts the current thread to sleep for at least `duration`.
+#[cfg(test)]
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now() + duration);
+}
+
+/// Puts the current thread to sleep until `deadline` has passed.
+#[cfg(test)]
+pub fn sleep_until(deadline: Instant) {
+    debug_assert!(
+        interrupts::are_enabled(),
+        "Sleeping with interrupts disabled"
+    );
+    if Instant::now() >= deadline {
+        return;
+    }
+    without_interrupts(|| {
+        let mut waker = Some(sched::prepare_block());
+        add_timer(
+            deadline,
+            None,
+            Box::new(move || waker.take().into_iter().for_each(sched::Waker::wake)),
+        );
+    });
+    sched::block();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+struct Timer {
+    /// `None` for one-shot timers.
+    period: Option<Duration>,
+    callback: Box<dyn FnMut() + Send>,
+}
+
+struct Timers {
+    /// By deadline, with the ID to tell apart timers due at the same time.
+    pending: BTreeMap<(Instant, TimerId), Timer>,
+    /// The timer whose callback is running, unless it has been cancelled since.
+    running: Option<TimerId>,
+}
+
+static TIMERS: IrqSpinLock<Timers> = IrqSpinLock::new(Timers {
+    pending: BTreeMap::new(),
+    running: None,
+});
+
+#[cfg(test)]
+fn add_timer(
+    deadline: Instant,
+    period: Option<Duration>,
+    callback: Box<dyn FnMut() + Send>,
+) -> TimerId {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let first = {
+        let mut timers = TIMERS.lock();
+        timers
+            .pending
+            .insert((deadline, id), Timer { period, callback });
+        timers.pending.first_key_value().map(|(key, _)| key.1) == Some(id)
+    };
+    // The boot CPU's timer may be set for later than this one is due.
+    if first && is_tickless() {
+        without_interrupts(|| match smp::current_cpu() {
+            0 => reprogram(),
+            _ => sched::kick(0),
+        });
+    }
+    id
+}
+
+/// Runs `f` once, `delay` from now.
+#[cfg(test)]
+pub fn after<F>(delay: Duration, f: F) -> TimerId
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut f = Some(f);
+    add_timer(
+        Instant::now() + delay,
+        None,
+        Box::new(move || f.take().into_iter().for_each(|f| f())),
+    )
+}
+
+/// Runs `f` every `period`, starting `period` from now, until the timer is cancelled. Runs that
+/// would be late by a whole period or more are skipped.
+#[cfg(test)]
+pub fn every<F>(period: Duration, f: F) -> TimerId
+where
+    F: FnMut() + Send + 'static,
+{
+    assert!(!period.is_zero(), "Timer period must not be zero");
+    add_timer(Instant::now() + period, Some(period), Box::new(f))
+}
+
+/// Stops a timer, and returns whether it hadn't run its course yet. Its callback may be running
+/// right now, but won't run again.
+#[cfg(test)]
+pub fn cancel(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    if timers.running == Some(id) {
+        timers.running = None;
+        return true;
+    }
+    let key = timers
+        .pending
+        .keys()
+        .find(|(_, timer)| *timer == id)
+        .copied();
+    key.and_then(|key| timers.pending.remove(&key)).is_some()
+}
+
+/// Runs the callbacks of the timers that are due by `now`. Timers are unlocked while callbacks
+/// run, so that they can add and cancel timers.
+fn run_timers(now: Instant) {
+    loop {
+        let ((deadline, id), mut timer) = {
+            let mut timers = TIMERS.lock();
+            let Some(next) = timers.pending.first_entry() else {
+                return;
+            };
+            if next.key().0 > now {
+                return;
+            }
+            let next = next.remove_entry();
+            timers.running = Some(next.0 .1);
+            next
+        };
+        (timer.callback)();
+
+        let mut timers = TIMERS.lock();
+        if timers.running.take() != Some(id) {
+            continue;
+        }
+        if let Some(period) = timer.period {
+            let mut next = deadline + period;
+            if next <= now {
+                next = now + period;
+            }
+            timers.pending.insert((next, id), timer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, AtomicUsize};
+
+    use super::*;
+
+    #[test_case]
+    fn frequencies_print_in_mhz() {
+        assert_eq!(alloc::format!("{}", Hz(1_193_182)), "1.193 MHz");
+        assert_eq!(alloc::format!("{}", Hz(2_400_000_000)), "2400.000 MHz");
+    }
+
+    #[test_case]
+    fn instants_add_up() {
+        let start = Instant::from_nanos(1_500);
+        let later = start + Duration::from_micros(2);
+        assert_eq!(later.as_nanos(), 3_500);
+        assert_eq!(later - start, Duration::from_micros(2));
+        assert_eq!(start - later, Duration::ZERO);
+        assert_eq!(Instant::BOOT + Duration::MAX, Instant::from_nanos(u64::MAX));
+        assert_eq!(
+            alloc::format!("{}", Instant::from_nanos(2_000_345_000)),
+            "2.000345"
+        );
+    }
+
+    #[test_case]
+    fn clock_follows_ticks() {
+        let period = tick_period();
+        assert!(hz() > 0);
+        assert!(period > Duration::ZERO);
+        let (ticks_before, before) = (ticks(), Instant::now());
+        while ticks() < ticks_before + 2 {
+            sched::yield_now();
+        }
+        assert!(Instant::now() - before >= period);
+    }
+
+    #[test_case]
+    fn udelay_waits_without_interrupts() {
+        let start = Instant::now();
+        without_interrupts(|| udelay(30_000));
+        // No tick while interrupts were off; the pending one comes in now.
+        sched::yield_now();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= tick_period(), "{:?}", elapsed);
+    }
+
+    #[test_case]
+    fn sleep_lasts_at_least_the_duration() {
+        let start = Instant::now();
+        sleep(Duration::from_millis(50));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test_case]
+    fn timers_fire_and_can_be_cancelled() {
+        let fired = Arc::new(AtomicBool::new(false));
+        let count = Arc::new(AtomicUsize::new(0));
+        let one_shot = {
+            let fired = fired.clone();
+            after(Duration::from_millis(20), move || {
+                fired.store(true, Ordering::SeqCst)
+            })
+        };
+        let periodic = {
+            let count = count.clone();
+            every(tick_period(), move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        let never = after(Duration::from_secs(3600), || panic!("Cancelled timer ran"));
+
+        sleep(tick_period() * 5 + Duration::from_millis(20));
+        assert!(fired.load(Ordering::SeqCst));
+        assert!(!cancel(one_shot));
+        assert!(cancel(periodic));
+        assert!(cancel(never));
+        let runs = count.load(Ordering::SeqCst);
+        assert!(runs >= 3, "{} runs", runs);
+
+        sleep(tick_period() * 3);
+        assert_eq!(count.load(Ordering::SeqCst), runs);
+    }
+}
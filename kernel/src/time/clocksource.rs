@@ -0,0 +1,184 @@
+//! Counters that tell the time.
+//!
+//! A clock source is a free-running counter with a known frequency. Drivers [`register`] the
+//! ones the machine has, and the clock reads whichever is best: the one with the highest
+//! [`ClockSource::rating`], unless the command line picks one by name with `clocksource=`.
+//! Changing sources carries the time over, so the clock doesn't jump, and the time read is
+//! never earlier than one read before, even on another CPU.
+//!
+//! The clock is read far more often than it changes, so reading it takes no lock: the source
+//! and its base are published under a sequence count, and a reader that overlaps a change
+//! simply reads again.
+
+use alloc::vec::Vec;
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    ptr,
+    sync::atomic::{fence, AtomicU64, Ordering},
+};
+
+use super::{Hz, NANOS_PER_SEC};
+use crate::{boot_info, pit, println, sync::IrqSpinLock};
+
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Counts per second.
+    fn frequency(&self) -> u64;
+
+    fn read(&self) -> u64;
+
+    /// How good a source it is, from its resolution and how cheap and reliable it is to read:
+    /// the highest rated one is used.
+    fn rating(&self) -> u32;
+}
+
+/// The tick count, in cycles of the PIT's input clock, which is always there but only has the
+/// resolution of a tick.
+pub struct Ticks;
+
+impl ClockSource for Ticks {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn frequency(&self) -> u64 {
+        pit::FREQUENCY as u64
+    }
+
+    fn read(&self) -> u64 {
+        super::ticks() * pit::divisor() as u64
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Clock {
+    source: Option<&'static dyn ClockSource>,
+    /// Reading of the source when it was picked.
+    base_count: u64,
+    /// Time since boot when it was picked.
+    base_nanos: u64,
+}
+
+impl Clock {
+    fn nanos(&self) -> u64 {
+        let Some(source) = self.source else {
+            return self.base_nanos;
+        };
+        let counts = source.read().wrapping_sub(self.base_count) as u128;
+        self.base_nanos + (counts * NANOS_PER_SEC as u128 / source.frequency() as u128) as u64
+    }
+}
+
+/// [`Clock`] behind a sequence count, which is odd while it is being changed.
+struct SeqClock {
+    seq: AtomicU64,
+    clock: UnsafeCell<Clock>,
+}
+
+// Writers are serialized by `SELECT_LOCK`, and readers check the sequence count around their
+// copy, see `SeqClock::read`.
+unsafe impl Sync for SeqClock {}
+
+impl SeqClock {
+    fn read(&self) -> Clock {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 == 1 {
+                spin_loop();
+                continue;
+            }
+            // May be torn by a concurrent write, in which case the count has moved on and the
+            // copy is thrown away without being looked at.
+            let clock = unsafe { ptr::read_volatile(self.clock.get()) };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return clock;
+            }
+        }
+    }
+
+    /// Must only be called with `SELECT_LOCK` held.
+    fn write(&self, clock: Clock) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(self.clock.get(), clock) };
+        self.seq.fetch_add(1, Ordering::Release);
+    }
+}
+
+static CLOCK: SeqClock = SeqClock {
+    seq: AtomicU64::new(0),
+    clock: UnsafeCell::new(Clock {
+        source: None,
+        base_count: 0,
+        base_nanos: 0,
+    }),
+};
+
+/// Serializes changes to [`CLOCK`]. Holding it with interrupts disabled also keeps a reader
+/// on this CPU from spinning on a change that can't finish.
+static SELECT_LOCK: IrqSpinLock<()> = IrqSpinLock::new(());
+
+static SOURCES: IrqSpinLock<Vec<&'static dyn ClockSource>> = IrqSpinLock::new(Vec::new());
+
+/// Latest time read, see [`nanos`].
+static LATEST: AtomicU64 = AtomicU64::new(0);
+
+/// Makes `source` available, and switches to it if it is the best one.
+pub fn register(source: &'static dyn ClockSource) {
+    let best = {
+        let mut sources = SOURCES.lock();
+        sources.push(source);
+        let wanted = boot_info::boot_info().option("clocksource");
+        let best_rated = sources.iter().max_by_key(|source| source.rating());
+        sources
+            .iter()
+            .find(|source| Some(source.name()) == wanted)
+            .or(best_rated)
+            .copied()
+    };
+    if let Some(best) = best {
+        select(best);
+    }
+}
+
+fn select(source: &'static dyn ClockSource) {
+    {
+        let _guard = SELECT_LOCK.lock();
+        let clock = CLOCK.read();
+        if clock
+            .source
+            .is_some_and(|current| current.name() == source.name())
+        {
+            return;
+        }
+        CLOCK.write(Clock {
+            base_nanos: clock.nanos(),
+            base_count: source.read(),
+            source: Some(source),
+        });
+    }
+    println!(
+        "Clock source: {}, {}",
+        source.name(),
+        Hz(source.frequency())
+    );
+}
+
+/// The clock source in use.
+pub fn current() -> Option<&'static dyn ClockSource> {
+    CLOCK.read().source
+}
+
+/// Nanoseconds since boot. Sources on different CPUs, like their TSCs, may not be perfectly in
+/// step, so this never goes back from what any CPU has seen.
+pub fn nanos() -> u64 {
+    let nanos = CLOCK.read().nanos();
+    LATEST.fetch_max(nanos, Ordering::Relaxed).max(nanos)
+}
@@ -0,0 +1,154 @@
+//! Calendar dates, in the proleptic Gregorian calendar and UTC.
+
+use core::fmt;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Days from 0000-03-01 to 1970-01-01.
+const UNIX_EPOCH_DAYS: i64 = 719_468;
+/// Days in a 400-year cycle.
+const DAYS_PER_ERA: i64 = 146_097;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    /// From 1.
+    pub month: u8,
+    /// From 1.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+pub fn is_leap_year(year: u16) -> bool {
+    year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    #[cfg(test)]
+    pub const UNIX_EPOCH: Self = Self {
+        year: 1970,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && (1..=days_in_month(self.year, self.month)).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Seconds since the Unix epoch, 0 for earlier dates.
+    pub fn to_unix(self) -> u64 {
+        // Years start in March here, so that the leap day is the last day of the year.
+        let (year, month) = match self.month {
+            1 | 2 => (self.year as i64 - 1, self.month as i64 + 9),
+            _ => (self.year as i64, self.month as i64 - 3),
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * DAYS_PER_ERA + day_of_era - UNIX_EPOCH_DAYS;
+        let secs = self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64;
+        u64::try_from(days).map_or(0, |days| days * SECS_PER_DAY + secs)
+    }
+
+    /// The date `secs` seconds after the Unix epoch.
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / SECS_PER_DAY) as i64 + UNIX_EPOCH_DAYS;
+        let secs = secs % SECS_PER_DAY;
+        let era = days.div_euclid(DAYS_PER_ERA);
+        let day_of_era = days - era * DAYS_PER_ERA;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let (year, month) = match month {
+            10 | 11 => (era * 400 + year_of_era + 1, month - 9),
+            _ => (era * 400 + year_of_era, month + 3),
+        };
+        Self {
+            year: year.min(u16::MAX as i64) as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+}
+
+/// ISO 8601, e.g. `2024-02-29T13:05:09Z`.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test_case]
+    fn unix_time_round_trips() {
+        let known = [
+            (DateTime::UNIX_EPOCH, 0),
+            (date(2000, 3, 1, 0, 0, 0), 951_868_800),
+            (date(2024, 2, 29, 13, 5, 9), 1_709_211_909),
+            (date(2038, 1, 19, 3, 14, 8), 1 << 31),
+            (date(2100, 12, 31, 23, 59, 59), 4_133_980_799),
+        ];
+        for (date, unix) in known {
+            assert_eq!(date.to_unix(), unix, "{}", date);
+            assert_eq!(DateTime::from_unix(unix), date);
+        }
+        assert_eq!(date(1969, 12, 31, 23, 59, 59).to_unix(), 0);
+    }
+
+    #[test_case]
+    fn dates_are_checked_and_printed() {
+        assert!(date(2024, 2, 29, 23, 59, 59).is_valid());
+        assert!(!date(2023, 2, 29, 0, 0, 0).is_valid());
+        assert!(!date(2100, 2, 29, 0, 0, 0).is_valid());
+        assert!(date(2000, 2, 29, 0, 0, 0).is_valid());
+        assert!(!date(2024, 13, 1, 0, 0, 0).is_valid());
+        assert!(!date(2024, 4, 31, 0, 0, 0).is_valid());
+        assert!(!date(2024, 1, 1, 24, 0, 0).is_valid());
+        assert_eq!(
+            alloc::format!("{}", date(2024, 2, 9, 3, 5, 7)),
+            "2024-02-09T03:05:07Z"
+        );
+    }
+}
@@ -0,0 +1,124 @@
+	; Startup code for application processors (APs).
+	;
+	; An AP starts out in real mode at the address given in the startup IPI, which must be a
+	; page below 1 MiB. kernel/src/smp.rs copies everything between smp_trampoline_start and
+	; smp_trampoline_end to TRAMPOLINE_BASE and fills in the arguments at the end before
+	; starting each AP. The code is never run where it is linked, so every address in here is
+	; computed relative to TRAMPOLINE_BASE.
+	;
+	; From there it is the same trip the BSP takes in boot.asm: protected mode, then paging with
+	; the kernel's own page tables and long mode, and finally a call to ap_main in Rust on the
+	; stack the BSP allocated for this AP. The kernel page tables must identity map the
+	; trampoline page for the jump to long mode to land.
+
+	; Must match TRAMPOLINE_BASE in kernel/src/smp.rs.
+	TRAMPOLINE_BASE equ 0x8000
+
+	; Selectors in the trampoline's own GDT below.
+	CODE32 equ 0x08
+	DATA32 equ 0x10
+	CODE64 equ 0x18
+
+	%define ADDR(label) (TRAMPOLINE_BASE + (label - smp_trampoline_start))
+
+	global smp_trampoline_start
+	global smp_trampoline_end
+	global smp_trampoline_args
+
+	; Only ever copied, so it can live with the read-only data.
+	section .rodata
+
+	[BITS 16]
+
+smp_trampoline_start:
+	cli
+	cld
+
+	;   The SIPI vector leaves cs at TRAMPOLINE_BASE >> 4 and ip at 0; use flat addresses
+	;   from here on.
+	xor ax, ax
+	mov ds, ax
+	lgdt [ADDR(gdt.pointer)]
+
+	;   Enable protected mode
+	mov eax, cr0
+	or  eax, 1
+	mov cr0, eax
+
+	jmp dword CODE32:ADDR(protected_mode)
+
+	[BITS 32]
+
+protected_mode:
+	mov ax, DATA32
+	mov ds, ax
+	mov es, ax
+	mov ss, ax
+
+	;   Enable PAE
+	mov eax, cr4
+	or  eax, (1 << 5)
+	mov cr4, eax
+
+	;   The kernel's PML4, which lies below 4 GiB.
+	mov eax, [ADDR(smp_trampoline_args.cr3)]
+	mov cr3, eax
+
+	;   Long mode, and no-execute since the kernel page tables use it.
+	mov ecx, 0xC0000080
+	rdmsr
+	or  eax, (1 << 8) | (1 << 11)
+	wrmsr
+
+	;   Enable paging, with write protection in ring 0 like paging::init does for the BSP.
+	mov eax, cr0
+	or  eax, (1 << 31) | (1 << 16)
+	mov cr0, eax
+
+	jmp CODE64:ADDR(long_mode)
+
+	[BITS 64]
+
+long_mode:
+	xor ax, ax
+	mov ds, ax
+	mov es, ax
+	mov ss, ax
+	mov fs, ax
+	mov gs, ax
+
+	mov rsp, [ADDR(smp_trampoline_args.stack)]
+	mov rdi, [ADDR(smp_trampoline_args.cpu)]
+	mov rax, [ADDR(smp_trampoline_args.entry)]
+	call rax
+
+.halt:
+	hlt
+	jmp .halt
+
+	align 8
+
+gdt:
+	dq 0; null descriptor
+	dq 0x00cf9a000000ffff; 32-bit code, 4 GiB
+	dq 0x00cf92000000ffff; 32-bit data, 4 GiB
+	dq (1<<43) | (1<<44) | (1<<47) | (1<<53); 64-bit code, see boot.asm
+
+.pointer:
+	dw .pointer - gdt - 1
+	dd ADDR(gdt)
+
+	align 8
+
+	; Filled in by smp.rs for each AP, must match TrampolineArgs.
+smp_trampoline_args:
+.cr3:
+	dq 0
+.stack:
+	dq 0
+.entry:
+	dq 0
+.cpu:
+	dq 0
+
+smp_trampoline_end:
@@ -0,0 +1,159 @@
+//! Time stamp counter.
+//!
+//! Every CPU has a TSC that counts up from reset. On older CPUs it follows the core clock,
+//! which changes with power states, but an invariant TSC (CPUID 0x8000_0007, EDX bit 8) runs
+//! at a constant rate in every power state, which makes it the cheapest and finest clock
+//! source there is. Its rate isn't reported reliably, so it is measured against the HPET, or the
+//! PIT if there is none.
+
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    hint::spin_loop,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    hpet::{self, Hpet},
+    pit, println,
+    time::{
+        clocksource::{self, ClockSource},
+        Hz,
+    },
+};
+
+/// Length of each calibration run.
+const CALIBRATION_MS: u32 = 20;
+const CALIBRATION_RUNS: usize = 3;
+
+/// In Hz, 0 until calibrated.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+pub fn is_present() -> bool {
+    __cpuid(1).edx & (1 << 4) != 0
+}
+
+pub fn is_invariant() -> bool {
+    let max_extended = __cpuid(0x8000_0000).eax;
+    max_extended >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// Ticks per second, 0 if the TSC hasn't been calibrated.
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Measures the TSC's frequency against the HPET if there is one, or else the PIT.
+fn calibrate() -> u64 {
+    match hpet::get() {
+        Some(hpet) => calibrate_with_hpet(hpet),
+        None => calibrate_with_pit(),
+    }
+}
+
+/// Both counters are read together at each end of one run, so how long it takes doesn't matter.
+fn calibrate_with_hpet(hpet: &Hpet) -> u64 {
+    let counts = hpet.frequency() * CALIBRATION_MS as u64 / 1000;
+    let (tsc, elapsed) = without_interrupts(|| {
+        let (tsc_start, hpet_start) = (read(), hpet.counter());
+        while hpet.counts_since(hpet_start) < counts {
+            spin_loop();
+        }
+        (read() - tsc_start, hpet.counts_since(hpet_start))
+    });
+    (tsc as u128 * hpet.frequency() as u128 / elapsed as u128) as u64
+}
+
+/// Each run on PIT channel 2 can only come out too long, if the CPU is held up getting in and
+/// out of the wait, so the shortest one is used.
+fn calibrate_with_pit() -> u64 {
+    let cycles = pit::FREQUENCY * CALIBRATION_MS / 1000;
+    let shortest = (0..CALIBRATION_RUNS)
+        .map(|_| {
+            let start = read();
+            pit::wait(cycles as u16);
+            read() - start
+        })
+        .min()
+        .unwrap_or(0);
+    shortest * pit::FREQUENCY as u64 / cycles as u64
+}
+
+struct Tsc;
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn frequency(&self) -> u64 {
+        frequency()
+    }
+
+    fn read(&self) -> u64 {
+        read()
+    }
+
+    fn rating(&self) -> u32 {
+        300
+    }
+}
+
+/// Calibrates the TSC, and makes it a clock source if it is invariant.
+pub fn init() {
+    if !is_present() {
+        println!("TSC: not present");
+        return;
+    }
+    let frequency = calibrate();
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    let invariant = is_invariant();
+    println!(
+        "TSC: {}{}",
+        Hz(frequency),
+        if invariant { ", invariant" } else { "" }
+    );
+    if invariant && frequency > 0 {
+        clocksource::register(&Tsc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::{self, Duration, Instant};
+
+    #[test_case]
+    fn tsc_agrees_with_the_busy_wait() {
+        if frequency() == 0 {
+            return;
+        }
+        // Against a 10 ms busy-wait, which can only overshoot.
+        let measured = without_interrupts(|| {
+            let start = read();
+            time::udelay(10_000);
+            (read() - start) * 100
+        });
+        assert!(measured >= frequency() * 99 / 100, "{} Hz", measured);
+        assert!(measured <= frequency() * 11 / 10, "{} Hz", measured);
+    }
+
+    #[test_case]
+    fn clock_has_a_fine_resolution_with_the_tsc() {
+        if clocksource::current().map(|source| source.name()) != Some("tsc") {
+            return;
+        }
+        let elapsed = without_interrupts(|| {
+            let start = Instant::now();
+            time::udelay(100);
+            start.elapsed()
+        });
+        assert!(elapsed >= Duration::from_micros(100));
+        assert!(elapsed < time::tick_period(), "{:?}", elapsed);
+    }
+}
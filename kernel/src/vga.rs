@@ -1,14 +1,18 @@
-use spin::{lazy::Lazy, Mutex};
-use x86_64::instructions::interrupts::without_interrupts;
+use spin::lazy::Lazy;
+use x86_64::PhysAddr;
+
+use crate::{paging, serial, sync::IrqSpinLock};
 
 pub const VGA_WIDTH: usize = 80;
 pub const VGA_HEIGHT: usize = 25;
 
-pub static mut WRITER: Lazy<Mutex<Writer>> = Lazy::new(|| {
-    Mutex::new(Writer {
+pub static mut WRITER: Lazy<IrqSpinLock<Writer>> = Lazy::new(|| {
+    IrqSpinLock::new(Writer {
         column_pos: 0,
         color_code: ColorCode::new(Color::White, Color::Black),
-        buffer_ptr: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        buffer_ptr: unsafe {
+            &mut *paging::phys_to_virt(PhysAddr::new(0xb8000)).as_mut_ptr::<Buffer>()
+        },
     })
 });
 
@@ -100,6 +104,18 @@ impl Writer {
         self.buffer_ptr.chars[row] = [blank; VGA_WIDTH];
     }
 
+    pub fn set_color(&mut self, fg: Color, bg: Color) {
+        self.color_code = ColorCode::new(fg, bg);
+    }
+
+    /// Blanks the whole screen in the current colors.
+    pub fn clear_screen(&mut self) {
+        for row in 0..VGA_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_pos = 0;
+    }
+
     pub fn newline(&mut self) {
         for row in 1..VGA_HEIGHT {
             for col in 0..VGA_WIDTH {
@@ -132,9 +148,9 @@ macro_rules! print {
     };
 }
 
+/// Prints to the screen, and to the serial console if there is one.
 pub fn fmt(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    without_interrupts(|| unsafe {
-        WRITER.lock().write_fmt(args).ok();
-    });
+    unsafe { WRITER.lock().write_fmt(args).ok() };
+    serial::fmt(args);
 }
@@ -1,11 +1,20 @@
 use std::process::Command;
 
-#[test]
-fn test_main() {
+/// Runs the kernel's tests, booted from `iso`.
+fn run_tests(iso: &str) {
     let mut cmd = Command::new("qemu-system-x86_64");
     cmd.args(&[
+        "-machine",
+        "q35",
         "-cdrom",
-        "bruh_os.iso",
+        iso,
+        "-smp",
+        "4",
+        "-serial",
+        "stdio",
+        // COM2, for the serial tests to loop bytes back through.
+        "-serial",
+        "null",
         "-device",
         "isa-debug-exit,iobase=0xf4,iosize=0x04",
     ]);
@@ -15,9 +24,29 @@ fn test_main() {
     }
 }
 
+#[test]
+fn test_main() {
+    run_tests("bruh_os.iso");
+}
+
+/// The tickless paths only run with nohz=on, which bruh_os_nohz.iso boots with.
+#[test]
+fn test_nohz() {
+    run_tests("bruh_os_nohz.iso");
+}
+
 fn main() {
     let mut cmd = Command::new("qemu-system-x86_64");
-    cmd.args(&["-cdrom", "bruh_os.iso"]);
+    cmd.args(&[
+        "-machine",
+        "q35",
+        "-cdrom",
+        "bruh_os.iso",
+        "-smp",
+        "4",
+        "-serial",
+        "stdio",
+    ]);
 
     if !cmd.status().expect("failed to execute qemu").success() {
         panic!("qemu failed");